@@ -0,0 +1,15 @@
+use std::process::Command;
+
+/// Expose the version of the compiler for the user agent.
+fn main() {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|version| version.split_whitespace().nth(1).map(|v| v.to_string()))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=WECHAT_PAY_RUSTC_VERSION={}", version);
+    println!("cargo:rerun-if-env-changed=RUSTC");
+}
@@ -0,0 +1,295 @@
+//! Audit trail of outbound api calls and inbound notifications.
+//!
+//! An [AuditSink] receives an [AuditRecord] for every attempt of an api call and every
+//! notification received. Bodies are never recorded as is: by default only their sha256 is kept,
+//! a copy with sensitive fields masked can be kept with [AuditRedaction::with_masked_body].
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use security::hash::HashDigest;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    http::HttpError,
+    metrics,
+    middleware::{HttpRequest, HttpResponse, Middleware, Next},
+    prelude::*,
+    redact::{mask_json, PERSONAL_FIELDS},
+};
+
+/// What an [AuditRecord] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    /// An attempt of an outbound api call.
+    Request,
+    /// An inbound notification.
+    Notification,
+}
+
+/// A structured audit record.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    /// Milliseconds since the unix epoch.
+    pub timestamp_millis: u128,
+    pub kind: AuditKind,
+    pub merchant_id: Option<String>,
+    pub method: Option<String>,
+    /// Endpoint of the call, e.g. `/v3/pay/transactions/id/{id}`,
+    /// or event type of the notification, e.g. `TRANSACTION.SUCCESS`.
+    pub endpoint: String,
+    /// Base url of the host the request was sent to.
+    pub host: Option<String>,
+    /// `Request-ID` of the response, or id of the notification.
+    pub request_id: Option<String>,
+    pub status: Option<u16>,
+    /// WeChat Pay error code, or reason of the failure.
+    pub error: Option<String>,
+    /// Hex encoded sha256 of the request or notification body.
+    pub body_sha256: Option<String>,
+    /// Body with sensitive fields masked, only kept when configured.
+    pub body: Option<Value>,
+}
+
+/// Receives audit records, implementations should not block for long.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+/// Mask of a body which can't be masked field by field.
+const MASK: &str = "***";
+
+/// How bodies are kept in audit records.
+#[derive(Debug, Clone)]
+pub struct AuditRedaction {
+    hash_body: bool,
+    keep_body: bool,
+    masked_fields: Vec<String>,
+}
+
+impl Default for AuditRedaction {
+    /// Keep the hash of bodies only.
+    fn default() -> Self {
+        Self {
+            hash_body: true,
+            keep_body: false,
+            masked_fields: PERSONAL_FIELDS.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+}
+
+impl AuditRedaction {
+    /// Keep neither bodies nor their hash.
+    pub fn without_body() -> Self {
+        Self {
+            hash_body: false,
+            ..Self::default()
+        }
+    }
+
+    /// Keep json bodies with default sensitive fields and `fields` masked at any depth.
+    pub fn with_masked_body<I, S>(mut self, fields: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.keep_body = true;
+        self.masked_fields
+            .extend(fields.into_iter().map(|f| f.as_ref().to_string()));
+        self
+    }
+
+    fn get_body_sha256(&self, body: &[u8]) -> Option<String> {
+        if !self.hash_body || body.is_empty() {
+            return None;
+        }
+        let digest = Hash::Sha256.hash(body);
+        Some(digest.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    fn get_body(&self, body: &[u8]) -> Option<Value> {
+        if !self.keep_body || body.is_empty() {
+            return None;
+        }
+        match serde_json::from_slice::<Value>(body) {
+            Ok(mut value) => {
+                mask_json(&mut value, &self.masked_fields);
+                Some(value)
+            }
+            // a body which can't be masked is not kept.
+            Err(_) => Some(Value::String(MASK.to_string())),
+        }
+    }
+
+    /// A record of `body` with its hash and masked copy as configured.
+    pub(crate) fn new_record(&self, kind: AuditKind, endpoint: String, body: &[u8]) -> AuditRecord {
+        AuditRecord {
+            timestamp_millis: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            kind,
+            merchant_id: None,
+            method: None,
+            endpoint,
+            host: None,
+            request_id: None,
+            status: None,
+            error: None,
+            body_sha256: self.get_body_sha256(body),
+            body: self.get_body(body),
+        }
+    }
+}
+
+/// Layer recording every attempt of api calls to an [AuditSink].
+pub struct Audit {
+    sink: Arc<dyn AuditSink>,
+    redaction: AuditRedaction,
+}
+
+impl Audit {
+    pub fn new(sink: Arc<dyn AuditSink>, redaction: AuditRedaction) -> Self {
+        Self { sink, redaction }
+    }
+
+    fn record(&self, request: &HttpRequest, result: &Result<HttpResponse, HttpError>) {
+        let body = request.body.as_deref().unwrap_or_default().as_bytes();
+        let endpoint = metrics::get_endpoint(&request.path);
+        let mut record = self
+            .redaction
+            .new_record(AuditKind::Request, endpoint, body);
+        record.merchant_id = Some(request.merchant_id.clone());
+        record.method = Some(request.method.to_string());
+        record.host = request.host.clone();
+        match result {
+            Ok(response) => {
+                record.status = Some(response.status);
+                record.request_id = response.get_request_id().map(|id| id.to_string());
+                // responses are audited before error statuses are turned into errors.
+                if !(200..300).contains(&response.status) {
+                    let body = String::from_utf8_lossy(&response.body);
+                    let error = HttpError::from_response(response.status, &body);
+                    record.error = error.get_code().map(|code| code.to_string());
+                }
+            }
+            Err(e) => {
+                if let HttpError::Api { status, .. } = e {
+                    record.status = Some(*status);
+                }
+                record.request_id = e.get_request_id().map(|id| id.to_string());
+                record.error = Some(match e.get_code() {
+                    Some(code) => code.to_string(),
+                    None => e.to_string(),
+                });
+            }
+        }
+        self.sink.record(&record);
+    }
+}
+
+#[async_trait]
+impl Middleware for Audit {
+    async fn handle(
+        &self,
+        request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        let result = next.run(request.clone()).await;
+        self.record(&request, &result);
+        result
+    }
+
+    #[cfg(feature = "blocking")]
+    fn handle_blocking(
+        &self,
+        request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        let result = next.run_blocking(request.clone());
+        self.record(&request, &result);
+        result
+    }
+}
+
+/// Appends audit records to a file, one json object per line.
+pub struct JsonLinesAuditSink {
+    file: Mutex<File>,
+}
+
+impl JsonLinesAuditSink {
+    /// Open `path` for appending, creating it if missing.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditSink for JsonLinesAuditSink {
+    fn record(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize audit record for: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        // a single write per record, so that lines of concurrent writers don't interleave.
+        if let Err(e) = self.file.lock().unwrap().write_all(&line) {
+            error!("Failed to write audit record for: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redaction() {
+        let body = br#"{"amount":{"total":100},"payer":{"openid":"o4GgauInH_RCEdvrrNGrntXDuXXX"},"detail":[{"name":"Alice"}]}"#;
+        let redaction = AuditRedaction::default().with_masked_body(["total"]);
+        let record = redaction.new_record(AuditKind::Request, "/v3/pay".to_string(), body);
+        let masked = record.body.unwrap();
+        assert_eq!("o4G***XXX", masked["payer"]["openid"]);
+        assert_eq!("***", masked["amount"]["total"]);
+        assert_eq!("***", masked["detail"][0]["name"]);
+        assert_eq!(64, record.body_sha256.unwrap().len());
+
+        let record = AuditRedaction::default().new_record(AuditKind::Request, String::new(), body);
+        assert!(record.body.is_none());
+        let record =
+            AuditRedaction::without_body().new_record(AuditKind::Request, String::new(), body);
+        assert!(record.body_sha256.is_none());
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", std::process::id()));
+        let sink = JsonLinesAuditSink::open(&path).unwrap();
+        let mut record = AuditRedaction::default().new_record(
+            AuditKind::Notification,
+            "TRANSACTION.SUCCESS".to_string(),
+            b"{}",
+        );
+        record.request_id = Some("EV-2018022511223320873".to_string());
+        sink.record(&record);
+        sink.record(&record);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines = content.lines().collect::<Vec<_>>();
+        assert_eq!(2, lines.len());
+        let value: Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!("notification", value["kind"]);
+        assert_eq!("EV-2018022511223320873", value["request_id"]);
+    }
+}
@@ -3,80 +3,85 @@ use std::time::{Duration, SystemTime};
 
 pub use crate::prelude::*;
 
-use crate::{cipher::RsaSigner, cons::*, header::HttpHeaders};
+use crate::{
+    header::{HttpHeaders, WechatPayHeaders},
+    metrics::{self, VerificationFailure},
+};
 
-pub trait Credential {
+/// Authorizes requests, object safe so that it can be boxed.
+pub trait Credential: Send + Sync {
     /// Get auth type
     fn get_schema(&self) -> String;
 
     /// Get merchant id
     fn get_merchant_id(&self) -> &str;
 
-    /// Get authorization information
-    fn get_authorization(
-        &self,
-        uri: impl AsRef<str>,
-        method: impl AsRef<str>,
-        sign: impl AsRef<str>,
-    ) -> String;
+    /// Get authorization information, fails if the message can't be signed.
+    fn get_authorization(&self, uri: &str, method: &str, sign: &str) -> Result<String, String>;
 }
 
-pub trait Validator {
-    type Response;
-    type Error;
-
+/// Validates responses, object safe so that it can be boxed.
+pub trait Validator: Send + Sync {
     /// To validate whether response headers are valid or not.
-    fn validate(&self, body: impl AsRef<str>, headers: &HttpHeaders) -> Result<(), Self::Error>;
+    fn validate(&self, body: &str, headers: &HttpHeaders) -> Result<(), String>;
 }
 use std::time::UNIX_EPOCH;
 
-pub struct WxPay2Validator(CertificatesVerifier);
+/// A validator verifying response signatures with any [Verifier].
+pub struct WxPay2Validator(Box<dyn Verifier>);
 pub type MerchantId = String;
 
-/// A validator
-pub struct WxPay2Credential(MerchantId, RsaSigner);
+/// A credential signing requests with any [Signer] or [AsyncSigner].
+pub struct WxPay2Credential(MerchantId, CredentialSigner);
 
-const RESPONSE_EXPIRED_SECONDS: u64 = 5 * 60;
+enum CredentialSigner {
+    Blocking(Box<dyn Signer>),
+    Async(Box<dyn AsyncSigner>),
+}
 
-impl Validator for WxPay2Validator {
-    type Response = ();
-    type Error = String;
+/// Message to sign for a request, with the nonce and timestamp of its token.
+struct SignMessage {
+    nonce_str: String,
+    timestamp: u64,
+    canonical_url: String,
+    message: String,
+}
+
+pub(crate) const RESPONSE_EXPIRED_SECONDS: u64 = 5 * 60;
+
+impl WxPay2Validator {
+    /// Create a validator to verify responses with `verifier`, e.g. [CertificatesVerifier]
+    /// or a [CompositeVerifier].
+    pub fn new(verifier: impl Verifier + 'static) -> Self {
+        WxPay2Validator(Box::new(verifier))
+    }
+}
 
-    fn validate(&self, body: impl AsRef<str>, headers: &HttpHeaders) -> Result<(), Self::Error> {
+impl Validator for WxPay2Validator {
+    fn validate(&self, body: &str, headers: &HttpHeaders) -> Result<(), String> {
+        let headers = WechatPayHeaders::parse(headers).inspect_err(|_| {
+            metrics::recorder().increment_verification_failure(VerificationFailure::MissingHeader)
+        })?;
         // CHECK TIMESTAMP
-        let timestamp = headers.get(headers::WECHAT_PAY_TIMESTAMP).ok_or(format!(
-            "missing http header {}",
-            headers::WECHAT_PAY_TIMESTAMP
-        ))?;
         let now = SystemTime::now();
         let now = now.duration_since(UNIX_EPOCH).unwrap();
-        let timestamp = timestamp
-            .parse::<u64>()
-            .map_err(|_| "timestamp parse error".to_string())?;
-        let timestamp_d = Duration::from_secs(timestamp);
-        let elapsed = now - timestamp_d;
+        let timestamp_d = Duration::from_secs(headers.get_timestamp());
+        let elapsed = now.saturating_sub(timestamp_d);
         if elapsed.as_secs() > RESPONSE_EXPIRED_SECONDS {
+            metrics::recorder().increment_verification_failure(VerificationFailure::Expired);
             return Err("response is expired".into());
         }
-        // CHECK nonce
-        let nonce = headers.get(headers::WECHAT_PAY_NONCE).ok_or(format!(
-            "missing http header {} not exists",
-            headers::WECHAT_PAY_NONCE
-        ))?;
-
-        let message = format!("{}\n{}\n{}\n", timestamp, nonce, body.as_ref());
-        debug!("Message for verifying signatures is {}", message);
-        // CHECK serial number
-        let serial_number = headers.get(headers::WECHAT_PAY_SERIAL).ok_or(format!(
-            "missing http header {}",
-            headers::WECHAT_PAY_SERIAL
-        ))?;
-        // CHECK signature
-        let signature = headers.get(headers::WECHAT_PAY_SIGNATURE).ok_or(format!(
-            "missing http header {}",
-            headers::WECHAT_PAY_SIGNATURE
-        ))?;
-        self.0.verify(serial_number, message, signature)
+        debug!(
+            "Verifying response signature with certificate {}",
+            headers.serial_number
+        );
+        // CHECK signature, failures are recorded by the verifier.
+        let message = headers.get_message(body);
+        self.0.verify(
+            &headers.serial_number,
+            message.as_bytes(),
+            &headers.signature,
+        )
     }
 }
 
@@ -84,62 +89,113 @@ const NONCE_LENGTH: usize = 32;
 const SCHEMA_PREFIX: &str = "WECHATPAY2-";
 
 impl WxPay2Credential {
-    fn get_token(&self, uri: &str, http_method: &str, sign_body: &str) -> String {
+    /// Create a credential with merchant id and merchant private key signer, e.g. [RsaSigner].
+    pub fn new(merchant_id: impl AsRef<str>, signer: impl Signer + 'static) -> Self {
+        WxPay2Credential(
+            merchant_id.as_ref().to_string(),
+            CredentialSigner::Blocking(Box::new(signer)),
+        )
+    }
+
+    /// Create a credential signing asynchronously, e.g. with a
+    /// [RemoteSigner](crate::remote::RemoteSigner), it can't sign blocking calls.
+    pub fn from_async_signer(
+        merchant_id: impl AsRef<str>,
+        signer: impl AsyncSigner + 'static,
+    ) -> Self {
+        WxPay2Credential(
+            merchant_id.as_ref().to_string(),
+            CredentialSigner::Async(Box::new(signer)),
+        )
+    }
+
+    fn get_message(uri: &str, http_method: &str, sign_body: &str) -> SignMessage {
         let nonce_str = util::random_string(NONCE_LENGTH);
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_secs();
-        let url = uri.parse::<Url>().unwrap();
-        let mut canonical_url = url.path().to_string();
-        if let Some(query) = url.query() {
-            canonical_url.push('?');
-            canonical_url.push_str(query);
-        }
+        // `uri` is either an absolute url or a path with query.
+        let canonical_url = match uri.parse::<Url>() {
+            Ok(url) => {
+                let mut canonical_url = url.path().to_string();
+                if let Some(query) = url.query() {
+                    canonical_url.push('?');
+                    canonical_url.push_str(query);
+                }
+                canonical_url
+            }
+            Err(_) => uri.to_string(),
+        };
         let message = format!(
             "{}\n{}\n{}\n{}\n{}\n",
             http_method, canonical_url, timestamp, nonce_str, sign_body
         );
-        debug!("authorization message[{}]", message);
-        let signature_result = self.1.sign(message).unwrap();
+        SignMessage {
+            nonce_str,
+            timestamp,
+            canonical_url,
+            message,
+        }
+    }
+
+    fn get_token(&self, message: &SignMessage, signature_result: &SignatureResult) -> String {
         let token = format!(
             "mchid=\"{}\",nonce_str=\"{}\",timestamp=\"{}\",serial_no=\"{}\",signature=\"{}\"",
             self.get_merchant_id(),
-            nonce_str,
-            timestamp,
+            message.nonce_str,
+            message.timestamp,
             signature_result.get_serial_number(),
             signature_result.get_sign()
         );
-        debug!("The generated request signature information is[{}]", token);
+        debug!("Signed {}", message.canonical_url);
+        format!("{} {}", self.get_schema(), token)
+    }
 
-        token
+    /// Get authorization information, awaiting an async signer without blocking the executor.
+    pub async fn get_authorization_async(
+        &self,
+        uri: &str,
+        method: &str,
+        sign: &str,
+    ) -> Result<String, String> {
+        match &self.1 {
+            CredentialSigner::Blocking(_) => self.get_authorization(uri, method, sign),
+            CredentialSigner::Async(signer) => {
+                let message = Self::get_message(uri, method, sign);
+                let signature_result = signer.sign(&message.message).await?;
+                Ok(self.get_token(&message, &signature_result))
+            }
+        }
     }
 }
 impl Credential for WxPay2Credential {
     fn get_schema(&self) -> String {
-        format!("{}{}", SCHEMA_PREFIX, self.1.get_algorithm())
+        let algorithm = match &self.1 {
+            CredentialSigner::Blocking(signer) => signer.get_algorithm(),
+            CredentialSigner::Async(signer) => signer.get_algorithm(),
+        };
+        format!("{}{}", SCHEMA_PREFIX, algorithm)
     }
 
     fn get_merchant_id(&self) -> &str {
         self.0.as_str()
     }
 
-    fn get_authorization(
-        &self,
-        uri: impl AsRef<str>,
-        method: impl AsRef<str>,
-        sign: impl AsRef<str>,
-    ) -> String {
-        format!(
-            "{} {}",
-            self.get_schema(),
-            self.get_token(uri.as_ref(), method.as_ref(), sign.as_ref())
-        )
+    fn get_authorization(&self, uri: &str, method: &str, sign: &str) -> Result<String, String> {
+        let CredentialSigner::Blocking(signer) = &self.1 else {
+            return Err("an async signer can't sign blocking calls".to_string());
+        };
+        let message = Self::get_message(uri, method, sign);
+        let signature_result = signer.sign(&message.message)?;
+        Ok(self.get_token(&message, &signature_result))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::cipher::RsaSigner;
     use std::time::Duration;
     use std::time::SystemTime;
     use std::time::UNIX_EPOCH;
@@ -152,4 +208,18 @@ mod tests {
         let duration = now_timestamp - time;
         println!("{}", duration.as_secs());
     }
+
+    #[test]
+    fn test_token_keeps_leading_zero_of_serial_number() {
+        let signer = RsaSigner::new(
+            "0A1B2C3D4E5F60718293A4B5C6D7E8F901234567",
+            include_str!("../testdata/merchant_key.pem"),
+        )
+        .unwrap();
+        let credential = WxPay2Credential::new("1900009191", signer);
+        let authorization = credential
+            .get_authorization("/v3/certificates", "GET", "")
+            .unwrap();
+        assert!(authorization.contains(r#"serial_no="0A1B2C3D4E5F60718293A4B5C6D7E8F901234567""#));
+    }
 }
@@ -1,18 +1,19 @@
+use crate::keyring::ApiV3KeyRing;
 use crate::prelude::X509Certificate;
+use crate::serial::SerialNumber;
 
-use num_bigint_dig::BigUint;
 use std::{
     cmp::Ordering,
     collections::HashMap,
-    str::FromStr,
     sync::{Arc, RwLock},
 };
 
 use lazy_static::lazy_static;
+use serde::Deserialize;
 
 /// WxPay platform certificate provider
 pub trait CertificateProvider {
-    fn get_certificate(&self, serial_number: &str) -> Option<X509Certificate>;
+    fn get_certificate(&self, serial_number: &SerialNumber) -> Option<X509Certificate>;
 
     fn get_available_certificate(&self) -> Option<&X509Certificate>;
 }
@@ -20,7 +21,7 @@ pub trait CertificateProvider {
 lazy_static! {
 
     /// Certificates in memory
-    pub(crate) static ref IN_MEMOERY_CERTIFICATES: Arc<RwLock<HashMap<BigUint, X509Certificate<'static>>>> =
+    pub(crate) static ref IN_MEMOERY_CERTIFICATES: Arc<RwLock<HashMap<SerialNumber, X509Certificate<'static>>>> =
         Arc::new(RwLock::new(HashMap::new()));
 }
 
@@ -34,8 +35,7 @@ impl<'a> InMemoryCertificateProvider<'a> {
         // get an avaliable certificate.
         let mut longest: Option<X509Certificate> = None;
         for cert in certificates {
-            let serial_number = &cert.serial;
-            let serial_number = BigUint::from_bytes_be(serial_number.to_bytes_be().as_slice());
+            let serial_number = SerialNumber::from_certificate(&cert);
 
             if longest.is_none()
                 || longest
@@ -57,9 +57,8 @@ impl<'a> InMemoryCertificateProvider<'a> {
 }
 
 impl CertificateProvider for InMemoryCertificateProvider<'_> {
-    fn get_certificate(&self, serial_number: &str) -> Option<X509Certificate> {
-        let serial_number = BigUint::from_str(serial_number).unwrap();
-        match IN_MEMOERY_CERTIFICATES.read().unwrap().get(&serial_number) {
+    fn get_certificate(&self, serial_number: &SerialNumber) -> Option<X509Certificate> {
+        match IN_MEMOERY_CERTIFICATES.read().unwrap().get(serial_number) {
             None => None,
             Some(certificate) => Some(certificate.clone()),
         }
@@ -70,15 +69,98 @@ impl CertificateProvider for InMemoryCertificateProvider<'_> {
     }
 }
 
+/// Body of `GET /v3/certificates`.
+#[derive(Debug, Deserialize)]
+pub struct CertificatesResponse {
+    pub data: Vec<PlatformCertificate>,
+}
+
+/// A platform certificate encrypted with the APIv3 key.
+#[derive(Debug, Deserialize)]
+pub struct PlatformCertificate {
+    pub serial_no: String,
+    pub effective_time: String,
+    pub expire_time: String,
+    pub encrypt_certificate: EncryptCertificate,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EncryptCertificate {
+    pub algorithm: String,
+    pub nonce: String,
+    pub associated_data: String,
+    pub ciphertext: String,
+}
+
+/// Decrypt downloaded platform certificates with the current or a retired APIv3 key,
+/// returns the pem of every certificate by serial number.
+pub fn decrypt_certificates(
+    response: &CertificatesResponse,
+    api_v3_keys: &ApiV3KeyRing,
+) -> Result<HashMap<SerialNumber, Vec<u8>>, String> {
+    let mut certificates = HashMap::new();
+    for certificate in &response.data {
+        let serial_number = certificate.serial_no.parse::<SerialNumber>()?;
+        let encrypted = &certificate.encrypt_certificate;
+        let cipher_text = base64::decode(&encrypted.ciphertext)
+            .map_err(|_| format!("ciphertext of certificate {} is not base64", serial_number))?;
+        let decrypted = api_v3_keys
+            .decrypt(
+                encrypted.associated_data.as_bytes(),
+                encrypted.nonce.as_bytes(),
+                &cipher_text,
+            )
+            .map_err(|e| format!("failed to decrypt certificate {}: {}", serial_number, e))?;
+        certificates.insert(serial_number, decrypted.plain_text.into_bytes());
+    }
+    Ok(certificates)
+}
+
 #[cfg(test)]
 mod tests {
 
     #[test]
     fn test_serial_number() {
-        use num_bigint_dig::BigUint;
-        use std::str::FromStr;
-        let serial_number = "458609";
-        let serial_number = BigUint::from_str(serial_number).unwrap();
-        assert_eq!(BigUint::new(vec![458609]), serial_number);
+        use crate::serial::SerialNumber;
+        let serial_number = SerialNumber::from_decimal("458609").unwrap();
+        assert_eq!("06FF71", serial_number.to_string());
+    }
+
+    #[test]
+    fn test_decrypt_certificates_with_retired_key() {
+        use super::*;
+        use security::secret::SecretBytes;
+
+        const PLATFORM_CERT: &str = include_str!("../testdata/platform_cert.pem");
+        let old_key = b"0123456789abcdef0123456789abcdef";
+        let new_key = b"fedcba9876543210fedcba9876543210";
+        let cipher_text =
+            security::aes::encrypt(old_key, "certificate", "fdasflkja484", PLATFORM_CERT).unwrap();
+        let response: CertificatesResponse = serde_json::from_value(serde_json::json!({
+            "data": [{
+                "serial_no": "2F85E92795F5E7F86BA33C5C365133FDFD5E7E49",
+                "effective_time": "2023-01-01T00:00:00+08:00",
+                "expire_time": "2028-01-01T00:00:00+08:00",
+                "encrypt_certificate": {
+                    "algorithm": "AEAD_AES_256_GCM",
+                    "nonce": "fdasflkja484",
+                    "associated_data": "certificate",
+                    "ciphertext": base64::encode(cipher_text),
+                }
+            }]
+        }))
+        .unwrap();
+
+        let api_v3_keys = ApiV3KeyRing::new("2024-01", SecretBytes::from_slice(old_key));
+        api_v3_keys.rotate("2024-06", SecretBytes::from_slice(new_key));
+        let certificates = decrypt_certificates(&response, &api_v3_keys).unwrap();
+        let serial_number = SerialNumber::from_hex("2F85E92795F5E7F86BA33C5C365133FDFD5E7E49");
+        assert_eq!(
+            PLATFORM_CERT.as_bytes(),
+            certificates[&serial_number.unwrap()].as_slice()
+        );
+
+        api_v3_keys.remove("2024-01");
+        assert!(decrypt_certificates(&response, &api_v3_keys).is_err());
     }
 }
@@ -1,12 +1,21 @@
-#[derive(Debug, Clone)]
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use security::rsa::{RsaAlgorithm, RsaSigningKey};
+use security::secret::SecretString;
+
+use crate::redact::Secret;
+use crate::serial::SerialNumber;
+
+#[derive(Clone)]
 pub struct SignatureResult {
     pub signature: String,
-    pub certificate_serial_number: String,
+    pub certificate_serial_number: SerialNumber,
 }
 
 impl SignatureResult {
     #[must_use]
-    pub fn new(signature: String, certificate_serial_number: String) -> Self {
+    pub fn new(signature: String, certificate_serial_number: SerialNumber) -> Self {
         Self {
             signature,
             certificate_serial_number,
@@ -17,8 +26,8 @@ impl SignatureResult {
         self.signature.as_str()
     }
 
-    pub fn get_serial_number(&self) -> &str {
-        self.certificate_serial_number.as_str()
+    pub fn get_serial_number(&self) -> &SerialNumber {
+        &self.certificate_serial_number
     }
 }
 
@@ -27,30 +36,111 @@ impl std::fmt::Display for SignatureResult {
         write!(
             f,
             "{{signature: {}, serial_number: {}}}",
-            self.get_sign(),
+            Secret(self.get_sign()),
             self.get_serial_number()
         )
     }
 }
-pub trait Signer {
+impl std::fmt::Debug for SignatureResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SignatureResult")
+            .field("signature", &Secret(self.get_sign()))
+            .field("certificate_serial_number", &self.certificate_serial_number)
+            .finish()
+    }
+}
+
+/// Signs messages with the merchant key, object safe so that it can be boxed.
+pub trait Signer: Send + Sync {
+    /// Generate sign result str
+    fn sign(&self, message: &str) -> Result<SignatureResult, String>;
+
+    /// Get signature algorithm
+    fn get_algorithm(&self) -> &str;
+}
+
+impl<S: Signer + ?Sized> Signer for Box<S> {
+    fn sign(&self, message: &str) -> Result<SignatureResult, String> {
+        (**self).sign(message)
+    }
+
+    fn get_algorithm(&self) -> &str {
+        (**self).get_algorithm()
+    }
+}
+
+impl<S: Signer + ?Sized> Signer for Arc<S> {
+    fn sign(&self, message: &str) -> Result<SignatureResult, String> {
+        (**self).sign(message)
+    }
+
+    fn get_algorithm(&self) -> &str {
+        (**self).get_algorithm()
+    }
+}
+
+/// Signs messages without blocking the executor, e.g. through a remote signing service,
+/// see [RemoteSigner](crate::remote::RemoteSigner).
+#[async_trait]
+pub trait AsyncSigner: Send + Sync {
     /// Generate sign result str
-    fn sign(&self, message: impl AsRef<str>) -> Result<SignatureResult, String>;
+    async fn sign(&self, message: &str) -> Result<SignatureResult, String>;
 
     /// Get signature algorithm
     fn get_algorithm(&self) -> &str;
 }
 
-/// `#PKCS8` private key
-pub struct RsaSigner(Vec<u8>);
+/// Merchant signing key, parsed once and wiped from memory on drop.
+pub struct RsaSigner {
+    serial_number: SerialNumber,
+    private_key: RsaSigningKey,
+}
+
+/// Algorithm of `SHA256withRSA` signatures in the authorization schema.
+pub(crate) const SHA256_RSA2048: &str = "SHA256-RSA2048";
+
 impl RsaSigner {
-    const ALGORITHM: &str = "SHA256-RSA2048";
+    /// Create a signer with merchant certificate serial number and `#PKCS8` private key in `PEM`.
+    ///
+    /// The key is copied, prefer [RsaSigner::from_secret] to avoid leaving a copy behind.
+    pub fn new(
+        serial_number: impl AsRef<str>,
+        private_key: impl AsRef<[u8]>,
+    ) -> Result<Self, String> {
+        let private_key = SecretString::from_utf8(private_key.as_ref())
+            .map_err(|_| "private key is not in pem format".to_string())?;
+        Self::from_secret(serial_number, private_key)
+    }
+
+    /// Create a signer parsing the `#PKCS8` private key in `PEM`, which is wiped afterwards.
+    pub fn from_secret(
+        serial_number: impl AsRef<str>,
+        private_key: SecretString,
+    ) -> Result<Self, String> {
+        let private_key =
+            RsaSigningKey::from_pkcs8_pem(private_key.expose()).map_err(|e| e.to_string())?;
+        Ok(Self {
+            serial_number: SerialNumber::from_hex(serial_number)?,
+            private_key,
+        })
+    }
+
+    /// Serial number of the merchant certificate.
+    pub fn get_serial_number(&self) -> &SerialNumber {
+        &self.serial_number
+    }
 }
+
 impl Signer for RsaSigner {
     fn get_algorithm(&self) -> &str {
-        Self::ALGORITHM
+        SHA256_RSA2048
     }
 
-    fn sign(&self, message: impl AsRef<str>) -> Result<SignatureResult, String> {
-        todo!()
+    fn sign(&self, message: &str) -> Result<SignatureResult, String> {
+        let signature = self
+            .private_key
+            .sign(&RsaAlgorithm::Sha256withRsa, message)
+            .map_err(|e| e.to_string())?;
+        Ok(SignatureResult::new(signature, self.serial_number.clone()))
     }
 }
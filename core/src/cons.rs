@@ -20,12 +20,12 @@ pub fn get_user_agent() -> String {
     let os_type = os_info.os_type();
     let os_version = os_info.version();
     format!(
-        "WechatPay-Rust/{} ({}/{}.{:?}) Rust/{} Credential/{:?} Validator/{:?} with reqwest",
+        "WechatPay-Rust/{} ({}/{}.{:?}) Rust/{} Credential/{:?} Validator/{:?}",
         version,
         os_type,
         os_version,
         os_info.bitness(),
-        "1.66",
+        env!("WECHAT_PAY_RUSTC_VERSION"),
         type_name::<WxPay2Credential>().split("::").last().unwrap(),
         type_name::<WxPay2Validator>().split("::").last().unwrap()
     )
@@ -42,5 +42,7 @@ mod tests {
         println!("{}", super::get_user_agent());
         println!("{}", env!("CARGO_PKG_VERSION"));
         println!("{}", env!("CARGO_PKG_NAME"));
+        let rustc = format!("Rust/{} ", env!("WECHAT_PAY_RUSTC_VERSION"));
+        assert!(super::get_user_agent().contains(&rustc));
     }
 }
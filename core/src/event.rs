@@ -0,0 +1,157 @@
+//! Typed notification events.
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::redact::REDACTED;
+
+/// Type of a notification, for dispatching.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum EventType {
+    /// `TRANSACTION.SUCCESS`
+    TransactionSuccess,
+    /// `REFUND.SUCCESS`
+    RefundSuccess,
+    /// `REFUND.ABNORMAL`
+    RefundAbnormal,
+    /// `REFUND.CLOSED`
+    RefundClosed,
+    /// `PROFITSHARING.SUCCESS`
+    ProfitSharingSuccess,
+    /// `PROFITSHARING.RETURN`
+    ProfitSharingReturn,
+    /// `MCHTRANSFER.BILL.FINISHED`
+    TransferBillFinished,
+    /// `COUPON.USE`
+    CouponUse,
+    /// `PAYSCORE.USER_CONFIRM`
+    PayScoreUserConfirm,
+    /// `PAYSCORE.USER_PAID`
+    PayScoreUserPaid,
+    /// Any other type, as sent.
+    Unknown(String),
+}
+
+impl EventType {
+    const KNOWN: &'static [(&'static str, EventType)] = &[
+        ("TRANSACTION.SUCCESS", EventType::TransactionSuccess),
+        ("REFUND.SUCCESS", EventType::RefundSuccess),
+        ("REFUND.ABNORMAL", EventType::RefundAbnormal),
+        ("REFUND.CLOSED", EventType::RefundClosed),
+        ("PROFITSHARING.SUCCESS", EventType::ProfitSharingSuccess),
+        ("PROFITSHARING.RETURN", EventType::ProfitSharingReturn),
+        ("MCHTRANSFER.BILL.FINISHED", EventType::TransferBillFinished),
+        ("COUPON.USE", EventType::CouponUse),
+        ("PAYSCORE.USER_CONFIRM", EventType::PayScoreUserConfirm),
+        ("PAYSCORE.USER_PAID", EventType::PayScoreUserPaid),
+    ];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            EventType::Unknown(event_type) => event_type,
+            known => Self::KNOWN
+                .iter()
+                .find(|(_, event_type)| event_type == known)
+                .map(|(name, _)| *name)
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        matches!(self, EventType::Unknown(_))
+    }
+}
+
+impl FromStr for EventType {
+    type Err = std::convert::Infallible;
+
+    /// Never fails, unknown types are kept as [EventType::Unknown].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let known = Self::KNOWN.iter().find(|(name, _)| *name == s);
+        Ok(match known {
+            Some((_, event_type)) => event_type.clone(),
+            None => EventType::Unknown(s.to_string()),
+        })
+    }
+}
+
+impl Display for EventType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for EventType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for EventType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let event_type = String::deserialize(deserializer)?;
+        Ok(event_type.parse().unwrap_or_else(|e| match e {}))
+    }
+}
+
+/// A verified notification with its resource decrypted as `T`.
+pub struct Event<T> {
+    pub id: String,
+    pub create_time: OffsetDateTime,
+    pub event_type: EventType,
+    /// e.g. `encrypt-resource`.
+    pub resource_type: String,
+    pub summary: String,
+    /// Original type of the resource, e.g. `transaction`.
+    pub original_type: String,
+    pub resource: T,
+    /// Id of the APIv3 key which decrypted the resource.
+    pub api_v3_key_id: Option<String>,
+}
+
+impl<T> Debug for Event<T> {
+    /// The decrypted resource is redacted.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Event")
+            .field("id", &self.id)
+            .field("create_time", &self.create_time)
+            .field("event_type", &self.event_type)
+            .field("resource_type", &self.resource_type)
+            .field("summary", &self.summary)
+            .field("original_type", &self.original_type)
+            .field("resource", &format_args!("{}", REDACTED))
+            .field("api_v3_key_id", &self.api_v3_key_id)
+            .finish()
+    }
+}
+
+/// Parse a timestamp of WeChat Pay, e.g. `2018-06-08T10:34:56+08:00`.
+pub fn parse_time(time: &str) -> Result<OffsetDateTime, String> {
+    OffsetDateTime::parse(time, &Rfc3339).map_err(|_| format!("invalid time {}", time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_type() {
+        let event_type = serde_json::from_str::<EventType>(r#""REFUND.ABNORMAL""#).unwrap();
+        assert_eq!(EventType::RefundAbnormal, event_type);
+        assert_eq!(
+            r#""REFUND.ABNORMAL""#,
+            serde_json::to_string(&event_type).unwrap()
+        );
+
+        let event_type = "VEHICLE.ENTRANCE".parse::<EventType>().unwrap();
+        assert!(event_type.is_unknown());
+        assert_eq!("VEHICLE.ENTRANCE", event_type.to_string());
+
+        let time = parse_time("2015-05-20T13:29:35+08:00").unwrap();
+        assert_eq!(1432099775, time.unix_timestamp());
+        assert!(parse_time("2015-05-20 13:29:35").is_err());
+    }
+}
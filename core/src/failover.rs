@@ -0,0 +1,234 @@
+//! Host selection across WeChat Pay domains with a circuit breaker per host.
+//!
+//! WeChat Pay recommends falling back to `api2.mch.weixin.qq.com` when the primary domain is
+//! unreachable. A host is skipped while its circuit is open, and tried again after a cool down.
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::header::HostName;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Requests are allowed, with the count of consecutive failures.
+    Closed(u32),
+    /// Requests are rejected until the instant.
+    Open(Instant),
+    /// A single probe is allowed after the cool down, started at the instant.
+    HalfOpen(Instant),
+}
+
+/// A circuit breaker opening after consecutive failures.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_duration: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    /// Open the circuit after `failure_threshold` consecutive failures for `open_duration`.
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            open_duration,
+            state: Mutex::new(State::Closed(0)),
+        }
+    }
+
+    /// Whether a request may be sent now, an expired open circuit turns half-open.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            State::Closed(_) => true,
+            // a probe never reported back is given up after another cool down.
+            State::Open(until) | State::HalfOpen(until) if now >= until => {
+                *state = State::HalfOpen(now + self.open_duration);
+                true
+            }
+            State::Open(_) | State::HalfOpen(_) => false,
+        }
+    }
+
+    /// Whether the circuit is open.
+    pub fn is_open(&self) -> bool {
+        matches!(*self.state.lock().unwrap(), State::Open(_))
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().unwrap() = State::Closed(0);
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        *state = match *state {
+            State::Closed(failures) if failures + 1 < self.failure_threshold => {
+                State::Closed(failures + 1)
+            }
+            _ => State::Open(Instant::now() + self.open_duration),
+        };
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new(3, Duration::from_secs(30))
+    }
+}
+
+/// Ordered WeChat Pay hosts, the first one is the primary.
+#[derive(Debug)]
+pub struct HostSelector {
+    hosts: Vec<(String, CircuitBreaker)>,
+}
+
+impl HostSelector {
+    /// Create a selector with base urls such as `https://api.mch.weixin.qq.com`, in priority order,
+    /// fails without any url.
+    pub fn new<I, S>(base_urls: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Self::with_circuit_breaker(base_urls, CircuitBreaker::default)
+    }
+
+    /// Create a selector with a circuit breaker created by `breaker` for every host, fails
+    /// without any url.
+    pub fn with_circuit_breaker<I, S, F>(base_urls: I, breaker: F) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+        F: Fn() -> CircuitBreaker,
+    {
+        let hosts = base_urls
+            .into_iter()
+            .map(|url| (url.as_ref().trim_end_matches('/').to_string(), breaker()))
+            .collect::<Vec<_>>();
+        if hosts.is_empty() {
+            return Err("at least one host is required".to_string());
+        }
+        Ok(Self { hosts })
+    }
+
+    /// A single host without failover.
+    pub fn single(base_url: impl AsRef<str>) -> Self {
+        let host = base_url.as_ref().trim_end_matches('/').to_string();
+        Self {
+            hosts: vec![(host, CircuitBreaker::default())],
+        }
+    }
+
+    /// Hosts to try in order: every host whose circuit allows a request, or the primary if none
+    /// does. Circuits are checked lazily, so a probe is only spent on a host actually tried.
+    pub fn candidates(&self) -> Candidates<'_> {
+        Candidates {
+            selector: self,
+            index: 0,
+            yielded: false,
+        }
+    }
+
+    /// The primary host.
+    pub fn primary(&self) -> &str {
+        self.hosts[0].0.as_str()
+    }
+
+    pub fn record_success(&self, host: &str) {
+        if let Some(breaker) = self.get_breaker(host) {
+            breaker.record_success();
+        }
+    }
+
+    pub fn record_failure(&self, host: &str) {
+        if let Some(breaker) = self.get_breaker(host) {
+            breaker.record_failure();
+        }
+    }
+
+    fn get_breaker(&self, host: &str) -> Option<&CircuitBreaker> {
+        self.hosts
+            .iter()
+            .find(|(h, _)| h == host)
+            .map(|(_, breaker)| breaker)
+    }
+}
+
+/// Iterator over hosts to try, see [HostSelector::candidates].
+pub struct Candidates<'a> {
+    selector: &'a HostSelector,
+    index: usize,
+    yielded: bool,
+}
+
+impl<'a> Iterator for Candidates<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let hosts = &self.selector.hosts;
+        while self.index < hosts.len() {
+            let (host, breaker) = &hosts[self.index];
+            self.index += 1;
+            if breaker.allow() {
+                self.yielded = true;
+                return Some(host.as_str());
+            }
+        }
+        if !self.yielded {
+            self.yielded = true;
+            return Some(self.selector.primary());
+        }
+        None
+    }
+}
+
+impl Default for HostSelector {
+    /// `api.mch.weixin.qq.com` with fallback to `api2.mch.weixin.qq.com`.
+    fn default() -> Self {
+        HostSelector::new([
+            format!("https://{}", HostName::API.get_value()),
+            format!("https://{}", HostName::API2.get_value()),
+        ])
+        .expect("two hosts")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(20));
+        breaker.record_failure();
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert!(breaker.is_open());
+        assert!(!breaker.allow());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.allow());
+        // only one probe when half-open
+        assert!(!breaker.allow());
+        breaker.record_success();
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn test_candidates() {
+        let selector = HostSelector::with_circuit_breaker(["https://a/", "https://b"], || {
+            CircuitBreaker::new(1, Duration::from_secs(60))
+        })
+        .unwrap();
+        fn candidates(selector: &HostSelector) -> Vec<&str> {
+            selector.candidates().collect()
+        }
+        assert_eq!(vec!["https://a", "https://b"], candidates(&selector));
+        selector.record_failure("https://a");
+        assert_eq!(vec!["https://b"], candidates(&selector));
+        selector.record_failure("https://b");
+        assert_eq!(vec!["https://a"], candidates(&selector));
+
+        assert!(HostSelector::new(Vec::<String>::new()).is_err());
+    }
+}
@@ -1,53 +1,131 @@
+use crate::cons::headers;
 use crate::prelude::*;
+use crate::redact::{is_secret_header, Secret};
+use crate::serial::{SerialNumber, WechatPaySerial};
+use http::{HeaderName, HeaderValue};
 use std::collections::HashMap;
 
-/// A struct to represent Http-Headers
-#[derive(Debug, Default, Clone)]
-pub struct HttpHeaders(HashMap<String, String>);
+/// Http headers looked up case-insensitively, names keep their case and may repeat.
+#[derive(Default, Clone)]
+pub struct HttpHeaders(Vec<(String, String)>);
 
 impl HttpHeaders {
     /// Create a HttpHeaders instant with headers.
     pub fn new(headers: HashMap<String, String>) -> Self {
-        HttpHeaders(headers)
+        HttpHeaders(headers.into_iter().collect())
     }
 
-    /// Add a http header
+    /// Set a http header, replacing its values.
     pub fn insert(&mut self, name: impl AsRef<str>, value: impl AsRef<str>) {
-        self.0.insert(name.as_ref().into(), value.as_ref().into());
+        self.remove(name.as_ref());
+        self.append(name, value);
     }
 
-    /// Try to get an exist http header or return None if not exist
+    /// Add a value to a http header.
+    pub fn append(&mut self, name: impl AsRef<str>, value: impl AsRef<str>) {
+        self.0
+            .push((name.as_ref().to_string(), value.as_ref().to_string()));
+    }
+
+    /// Remove every value of a http header.
+    pub fn remove(&mut self, name: impl AsRef<str>) {
+        self.0
+            .retain(|(n, _)| !n.eq_ignore_ascii_case(name.as_ref()));
+    }
+
+    /// Try to get the first value of a http header or return None if not exist
     pub fn get(&self, name: impl AsRef<str>) -> Option<&String> {
-        self.0.get(name.as_ref())
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name.as_ref()))
+            .map(|(_, v)| v)
+    }
+
+    /// Get every value of a http header.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: impl AsRef<str>) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Iterate over names and values, in insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
     }
 
-    /// Get all http headers
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Get all http headers, values of a repeated header are joined with `, `.
     pub fn get_headers(&self) -> HashMap<String, String> {
-        self.0.clone()
+        let mut headers = HashMap::<String, String>::new();
+        for (name, value) in self.iter() {
+            let existing = headers
+                .iter_mut()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name));
+            match existing {
+                Some((_, values)) => {
+                    values.push_str(", ");
+                    values.push_str(value);
+                }
+                None => {
+                    headers.insert(name.to_string(), value.to_string());
+                }
+            }
+        }
+        headers
     }
 
-    /// Get all http headers
-    pub fn get_headers_ref(&self) -> &HashMap<String, String> {
-        &self.0
+    /// Convert to a [HeaderMap], fails on invalid names or values.
+    pub fn to_header_map(&self) -> Result<HeaderMap, String> {
+        let mut map = HeaderMap::with_capacity(self.0.len());
+        for (name, value) in self.iter() {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid http header name {}", name))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| format!("invalid value of http header {}", name))?;
+            map.append(name, value);
+        }
+        Ok(map)
     }
 }
 
 impl std::fmt::Display for HttpHeaders {
+    /// Values of secret headers such as `Authorization` are redacted.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.0.is_empty() {
-            return Ok(());
-        }
         let kvs = self
-            .0
             .iter()
-            .map(|(k, v)| format!("{}={}", k, v))
+            .map(|(k, v)| {
+                if is_secret_header(k) {
+                    format!("{}={}", k, Secret(v))
+                } else {
+                    format!("{}={}", k, v)
+                }
+            })
             .collect::<Vec<String>>();
         let display = kvs.join(",");
         write!(f, "{}", display)
     }
 }
 
+impl std::fmt::Debug for HttpHeaders {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HttpHeaders({})", self)
+    }
+}
+
 impl From<&HeaderMap> for HttpHeaders {
+    /// Values which are not visible ascii are skipped.
     fn from(hm: &HeaderMap) -> Self {
         let hh = hm
             .iter()
@@ -58,7 +136,7 @@ impl From<&HeaderMap> for HttpHeaders {
                     None
                 }
             })
-            .collect::<HashMap<String, String>>();
+            .collect();
         HttpHeaders(hh)
     }
 }
@@ -69,11 +147,116 @@ impl From<HeaderMap> for HttpHeaders {
     }
 }
 
+impl TryFrom<&HttpHeaders> for HeaderMap {
+    type Error = String;
+
+    fn try_from(headers: &HttpHeaders) -> Result<Self, Self::Error> {
+        headers.to_header_map()
+    }
+}
+
+impl<N: AsRef<str>, V: AsRef<str>> FromIterator<(N, V)> for HttpHeaders {
+    fn from_iter<I: IntoIterator<Item = (N, V)>>(iter: I) -> Self {
+        let mut headers = HttpHeaders::default();
+        for (name, value) in iter {
+            headers.append(name, value);
+        }
+        headers
+    }
+}
+
+/// The `Wechatpay-*` headers of a signed response or notification, parsed once.
+#[derive(Clone)]
+pub struct WechatPayHeaders {
+    /// Serial number of the platform certificate, or id of the public key.
+    pub serial_number: WechatPaySerial,
+    pub signature: String,
+    /// Unix timestamp in seconds.
+    pub timestamp: u64,
+    pub nonce: String,
+    /// `Request-ID` of a response, notifications have none.
+    pub request_id: Option<String>,
+}
+
+impl WechatPayHeaders {
+    /// Parse the signature headers, fails with the name of the first missing or invalid one.
+    ///
+    /// The timestamp must be in canonical decimal, so that the signed message can be rebuilt
+    /// from the parsed value.
+    pub fn parse(headers: &HttpHeaders) -> Result<Self, String> {
+        let required = |name: &str| match headers.get(name) {
+            Some(value) if !value.is_empty() => Ok(value.clone()),
+            _ => Err(format!("missing http header {}", name)),
+        };
+        let sent = required(headers::WECHAT_PAY_TIMESTAMP)?;
+        let timestamp = match sent.parse::<u64>() {
+            Ok(timestamp) if timestamp.to_string() == sent => timestamp,
+            _ => {
+                return Err(format!(
+                    "invalid http header {}",
+                    headers::WECHAT_PAY_TIMESTAMP
+                ))
+            }
+        };
+        Ok(Self {
+            serial_number: WechatPaySerial::new(required(headers::WECHAT_PAY_SERIAL)?),
+            signature: required(headers::WECHAT_PAY_SIGNATURE)?,
+            timestamp,
+            nonce: required(headers::WECHAT_PAY_NONCE)?,
+            request_id: headers.get(headers::REQUEST_ID).cloned(),
+        })
+    }
+
+    /// Serial number of the platform certificate, `None` for a public key id.
+    pub fn get_certificate_serial_number(&self) -> Option<&SerialNumber> {
+        self.serial_number.get_certificate_serial_number()
+    }
+
+    pub fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// The signed message of `body`.
+    pub fn get_message(&self, body: &str) -> String {
+        format!("{}\n{}\n{}\n", self.timestamp, self.nonce, body)
+    }
+}
+
+impl TryFrom<&HttpHeaders> for WechatPayHeaders {
+    type Error = String;
+
+    fn try_from(headers: &HttpHeaders) -> Result<Self, Self::Error> {
+        Self::parse(headers)
+    }
+}
+
+impl TryFrom<&HeaderMap> for WechatPayHeaders {
+    type Error = String;
+
+    fn try_from(headers: &HeaderMap) -> Result<Self, Self::Error> {
+        Self::parse(&HttpHeaders::from(headers))
+    }
+}
+
+impl std::fmt::Debug for WechatPayHeaders {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WechatPayHeaders")
+            .field("serial_number", &self.serial_number)
+            .field("signature", &Secret(&self.signature))
+            .field("timestamp", &self.timestamp)
+            .field("nonce", &self.nonce)
+            .field("request_id", &self.request_id)
+            .finish()
+    }
+}
+
 /// wx-pay domains
 #[derive(Debug)]
 pub struct HostName(&'static str);
 impl HostName {
     pub const API: HostName = HostName("api.mch.weixin.qq.com");
+    /// Backup domain recommended when `api.mch.weixin.qq.com` is unreachable.
+    pub const API2: HostName = HostName("api2.mch.weixin.qq.com");
     pub const API_HK: HostName = HostName("apihk.mch.weixin.qq.com");
 
     pub fn get_value(&self) -> &str {
@@ -90,3 +273,62 @@ impl std::fmt::Display for HostName {
         write!(f, "HostName({})", self.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_case_insensitive() {
+        let mut map = HeaderMap::new();
+        map.insert("wechatpay-serial", HeaderValue::from_static("5157F09E"));
+        map.insert("wechatpay-signature", HeaderValue::from_static("c2lnbg=="));
+        map.insert(
+            "wechatpay-timestamp",
+            HeaderValue::from_static("1554208460"),
+        );
+        map.insert("wechatpay-nonce", HeaderValue::from_static("593BEC0C"));
+        map.append("set-cookie", HeaderValue::from_static("a=1"));
+        map.append("set-cookie", HeaderValue::from_static("b=2"));
+
+        let mut headers = HttpHeaders::from(&map);
+        assert_eq!(
+            Some(&"5157F09E".to_string()),
+            headers.get("Wechatpay-Serial")
+        );
+        assert_eq!(
+            vec!["a=1", "b=2"],
+            headers.get_all("Set-Cookie").collect::<Vec<_>>()
+        );
+        headers.insert("Set-Cookie", "c=3");
+        assert_eq!(
+            vec!["c=3"],
+            headers.get_all("set-cookie").collect::<Vec<_>>()
+        );
+        assert_eq!(5, headers.to_header_map().unwrap().len());
+
+        let wechat_pay = WechatPayHeaders::try_from(&map).unwrap();
+        assert_eq!("5157F09E", wechat_pay.serial_number.to_string());
+        assert_eq!(
+            Some(&"5157f09e".parse().unwrap()),
+            wechat_pay.get_certificate_serial_number()
+        );
+        assert_eq!(1554208460, wechat_pay.get_timestamp());
+        assert_eq!("1554208460\n593BEC0C\n{}\n", wechat_pay.get_message("{}"));
+        assert_eq!(None, wechat_pay.request_id);
+
+        for invalid in ["yesterday", "+1554208460", "01554208460"] {
+            headers.insert("Wechatpay-Timestamp", invalid);
+            assert_eq!(
+                Err("invalid http header Wechatpay-Timestamp".to_string()),
+                WechatPayHeaders::parse(&headers).map(|_| ())
+            );
+        }
+        headers.remove("WECHATPAY-NONCE");
+        headers.insert("Wechatpay-Timestamp", "1554208460");
+        assert_eq!(
+            Err("missing http header Wechatpay-Nonce".to_string()),
+            WechatPayHeaders::parse(&headers).map(|_| ())
+        );
+    }
+}
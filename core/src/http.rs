@@ -1,184 +1,1121 @@
-use std::collections::HashMap;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
-use reqwest::{header::HeaderMap, Certificate, ClientBuilder};
-use serde::{Deserialize, Serialize};
+use http::Method;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::Instrument;
 
+#[cfg(feature = "blocking")]
+use crate::transport::BlockingHttpTransport;
+#[cfg(feature = "reqwest")]
+use crate::transport::ReqwestTransport;
 use crate::{
-    auth::{WxPay2Credential, WxPay2Validator},
-    cons::get_user_agent,
+    audit::{Audit, AuditRedaction, AuditSink},
+    auth::{Credential, WxPay2Credential, WxPay2Validator},
+    failover::HostSelector,
+    metrics,
+    middleware::{Endpoint, HttpRequest, HttpResponse, Middleware, Next, Timeout},
+    ratelimit::RateLimiter,
+    retry::RetryPolicy,
+    transport::{HttpTransport, TransportError, TransportErrorKind},
 };
 
-#[derive(Debug, Default, Clone)]
-pub struct HttpHeaders(HashMap<String, String>);
+pub use crate::header::{HostName, HttpHeaders, WechatPayHeaders};
+
+/// Build a https only http client with timeouts in milliseconds,
+/// see [ReqwestTransport::builder] for other options.
+#[cfg(feature = "reqwest")]
+pub fn build_async_http_client(
+    connect_timeout: u64,
+    timeout: u64,
+) -> Result<reqwest::Client, String> {
+    ReqwestTransport::builder()
+        .with_connect_timeout(Duration::from_millis(connect_timeout))
+        .with_timeout(Duration::from_millis(timeout))
+        .with_https_only(true)
+        .build_client()
+}
+
+#[cfg(all(feature = "reqwest", feature = "blocking"))]
+pub fn build_blocking_http_client(
+    connect_timeout: u64,
+    timeout: u64,
+) -> Result<reqwest::blocking::Client, String> {
+    ReqwestTransport::builder()
+        .with_connect_timeout(Duration::from_millis(connect_timeout))
+        .with_timeout(Duration::from_millis(timeout))
+        .with_https_only(true)
+        .build_blocking_client()
+}
+
+/// Errors of an api call.
+#[derive(Debug)]
+pub enum HttpError {
+    /// Failed to send the request or to read the response.
+    Transport(TransportError),
+    /// WeChat Pay responded with a non-success status.
+    Api {
+        status: u16,
+        code: String,
+        message: String,
+        /// `Request-ID` of the response, to be given to WeChat Pay support.
+        request_id: Option<String>,
+    },
+    /// Response signature is invalid.
+    Validation {
+        reason: String,
+        request_id: Option<String>,
+    },
+    /// Failed to sign the request.
+    Sign(String),
+    /// Failed to serialize the request.
+    Serialize(serde_json::Error),
+    /// Failed to deserialize the response.
+    Deserialize {
+        source: serde_json::Error,
+        request_id: Option<String>,
+    },
+    /// The rate limiter can't let the request through before its deadline.
+    RateLimited,
+    /// Failure raised by a user [Middleware].
+    Custom(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Error body of WeChat Pay api.
+#[derive(Debug, Default, Deserialize)]
+struct ErrorBody {
+    #[serde(default)]
+    code: String,
+    #[serde(default)]
+    message: String,
+}
+
+/// WeChat Pay error code of a temporary system failure.
+const SYSTEM_ERROR: &str = "SYSTEM_ERROR";
+/// WeChat Pay error code of exceeding the frequency limit.
+const FREQUENCY_LIMITED: &str = "FREQUENCY_LIMITED";
 
-impl HttpHeaders {
-    /// Create a HttpHeaders instant with headers.
-    pub fn new(headers: HashMap<String, String>) -> Self {
-        HttpHeaders(headers)
+impl HttpError {
+    pub(crate) fn from_response(status: u16, body: &str) -> Self {
+        let ErrorBody { code, message } = serde_json::from_str(body).unwrap_or_default();
+        HttpError::Api {
+            status,
+            code,
+            message,
+            request_id: None,
+        }
     }
 
-    /// Add a http header
-    pub fn insert(&mut self, name: impl AsRef<str>, value: impl AsRef<str>) {
-        self.0.insert(name.as_ref().into(), value.as_ref().into());
+    /// Attach the `Request-ID` of the response to errors raised after receiving it.
+    pub(crate) fn with_request_id(mut self, id: Option<&str>) -> Self {
+        if let HttpError::Api { request_id, .. }
+        | HttpError::Validation { request_id, .. }
+        | HttpError::Deserialize { request_id, .. } = &mut self
+        {
+            if request_id.is_none() {
+                *request_id = id.map(|id| id.to_string());
+            }
+        }
+        self
     }
 
-    /// Try to get an exist http header or return None if not exist
-    pub fn get(&self, name: impl AsRef<str>) -> Option<&String> {
-        self.0.get(name.as_ref())
+    /// `Request-ID` of the response, `None` if no response was received.
+    pub fn get_request_id(&self) -> Option<&str> {
+        match self {
+            HttpError::Api { request_id, .. }
+            | HttpError::Validation { request_id, .. }
+            | HttpError::Deserialize { request_id, .. } => request_id.as_deref(),
+            _ => None,
+        }
     }
 
-    /// Get all http headers
-    pub fn get_headers(&self) -> HashMap<String, String> {
-        self.0.clone()
+    /// WeChat Pay error code, e.g. `PARAM_ERROR`.
+    pub fn get_code(&self) -> Option<&str> {
+        match self {
+            HttpError::Api { code, .. } => Some(code.as_str()),
+            _ => None,
+        }
     }
 
-    /// Get all http headers
-    pub fn get_headers_ref(&self) -> &HashMap<String, String> {
-        &self.0
+    /// Whether the connection was never established, so the request was not processed.
+    pub fn is_connect_failure(&self) -> bool {
+        matches!(self, HttpError::Transport(e) if e.get_kind() == TransportErrorKind::Connect)
     }
-}
 
-impl std::fmt::Display for HttpHeaders {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.0.is_empty() {
-            return Ok(());
-        }
-        let kvs = self
-            .0
-            .iter()
-            .map(|(k, v)| format!("{}={}", k, v))
-            .collect::<Vec<String>>();
-        let display = kvs.join(",");
-        write!(f, "{}", display)
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, HttpError::Transport(e) if e.get_kind() == TransportErrorKind::Timeout)
     }
-}
 
-impl From<&HeaderMap> for HttpHeaders {
-    fn from(hm: &HeaderMap) -> Self {
-        let hh = hm
-            .iter()
-            .flat_map(|(k, v)| {
-                if let Ok(v) = v.to_str() {
-                    Some((k.as_str().to_string(), v.to_string()))
-                } else {
-                    None
-                }
-            })
-            .collect::<HashMap<String, String>>();
-        HttpHeaders(hh)
+    /// Whether WeChat Pay rejected the request for exceeding the frequency limit.
+    pub fn is_frequency_limited(&self) -> bool {
+        matches!(self, HttpError::Api { status, code, .. } if *status == 429 || code == FREQUENCY_LIMITED)
+    }
+
+    /// Whether the failure is transient, thus worth retrying.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            HttpError::Transport(e) => e.get_kind() != TransportErrorKind::Other,
+            HttpError::Api { status, code, .. } => {
+                *status >= 500 || code == SYSTEM_ERROR || self.is_frequency_limited()
+            }
+            HttpError::Validation { .. }
+            | HttpError::Sign(_)
+            | HttpError::Serialize(_)
+            | HttpError::Deserialize { .. }
+            | HttpError::RateLimited
+            | HttpError::Custom(_) => false,
+        }
     }
 }
 
-impl From<HeaderMap> for HttpHeaders {
-    fn from(map: HeaderMap) -> Self {
-        HttpHeaders::from(&map)
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpError::Transport(e) => write!(f, "transport error: {}", e),
+            HttpError::Api {
+                status,
+                code,
+                message,
+                request_id,
+            } => write!(
+                f,
+                "api error: status={}, code={}, message={}, request_id={}",
+                status,
+                code,
+                message,
+                request_id.as_deref().unwrap_or_default()
+            ),
+            HttpError::Validation { reason, request_id } => write!(
+                f,
+                "validation error: {}, request_id={}",
+                reason,
+                request_id.as_deref().unwrap_or_default()
+            ),
+            HttpError::Sign(e) => write!(f, "sign error: {}", e),
+            HttpError::Serialize(e) => write!(f, "serialize error: {}", e),
+            HttpError::Deserialize { source, request_id } => write!(
+                f,
+                "deserialize error: {}, request_id={}",
+                source,
+                request_id.as_deref().unwrap_or_default()
+            ),
+            HttpError::RateLimited => write!(f, "rate limited"),
+            HttpError::Custom(e) => write!(f, "{}", e),
+        }
     }
 }
 
-/// wx-pay domains
+impl std::error::Error for HttpError {}
+
+/// A successful api response.
 #[derive(Debug)]
-pub struct HostName(&'static str);
-impl HostName {
-    pub const API: HostName = HostName("api.mch.weixin.qq.com");
-    pub const API_HK: HostName = HostName("apihk.mch.weixin.qq.com");
+pub struct ApiResponse<R> {
+    /// Base url of the host which served the call.
+    pub host: String,
+    pub status: u16,
+    /// `Request-ID` of the response, to be given to WeChat Pay support.
+    pub request_id: Option<String>,
+    pub body: R,
+}
 
-    pub fn get_value(&self) -> &str {
-        self.0
+impl<R> ApiResponse<R> {
+    pub fn get_host(&self) -> &str {
+        self.host.as_str()
     }
 
-    pub fn equals(&self, string: impl AsRef<str>) -> bool {
-        string.as_ref().starts_with(self.0)
+    pub fn get_request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    pub fn into_body(self) -> R {
+        self.body
     }
 }
 
-impl std::fmt::Display for HostName {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "HostName({})", self.0)
+/// Endpoint sending requests with the transports of a client.
+struct Transports {
+    transport: Arc<dyn HttpTransport>,
+    #[cfg(feature = "blocking")]
+    blocking_transport: Option<Arc<dyn BlockingHttpTransport>>,
+}
+
+impl Transports {
+    fn send_span(request: &HttpRequest) -> tracing::Span {
+        tracing::debug_span!(
+            "wechat_pay.send",
+            host = request.host.as_deref().unwrap_or_default()
+        )
     }
 }
 
-/// Build a http client to execute http request.
-pub fn build_async_http_client(connect_timeout: u64, timeout: u64) -> reqwest::Client {
-    let root_certificate = Certificate::from_pem(&[]).unwrap();
-    ClientBuilder::default()
-        .connect_timeout(Duration::from_millis(connect_timeout))
-        .timeout(Duration::from_millis(timeout))
-        .https_only(true)
-        .user_agent(get_user_agent())
-        .add_root_certificate(root_certificate)
-        .http09_responses()
-        .build()
-        .unwrap()
+#[async_trait]
+impl Endpoint for Transports {
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse, HttpError> {
+        let span = Self::send_span(&request);
+        self.transport
+            .send(request)
+            .instrument(span)
+            .await
+            .map_err(HttpError::Transport)
+    }
+
+    #[cfg(feature = "blocking")]
+    fn send_blocking(&self, request: HttpRequest) -> Result<HttpResponse, HttpError> {
+        let _span = Self::send_span(&request).entered();
+        match &self.blocking_transport {
+            Some(transport) => transport.send(request).map_err(HttpError::Transport),
+            None => Err(HttpError::Transport(TransportError::new(
+                TransportErrorKind::Other,
+                "no blocking transport",
+            ))),
+        }
+    }
 }
 
-#[cfg(feature = "blocking")]
-pub fn build_blocking_http_client(connect_timeout: u64, timeout: u64) -> reqwest::blocking::Client {
-    reqwest::blocking::ClientBuilder::default()
-        .connect_timeout(Duration::from_millis(connect_timeout))
-        .timeout(Duration::from_millis(timeout))
-        .https_only(true)
-        .user_agent(get_user_agent())
-        .build()
-        .unwrap()
+/// Http client signing requests with [WxPay2Credential] and validating responses
+/// with [WxPay2Validator], transient failures are retried according to [RetryPolicy],
+/// unreachable hosts are failed over according to [HostSelector].
+///
+/// See [middleware](crate::middleware) for the stack every call runs through.
+pub struct DefaultHttpClient {
+    transports: Transports,
+    credential: WxPay2Credential,
+    validator: WxPay2Validator,
+    retry_policy: RetryPolicy,
+    hosts: HostSelector,
+    rate_limiter: Option<RateLimiter>,
+    timeout: Option<Timeout>,
+    layers: Vec<Box<dyn Middleware>>,
+    inner_layers: Vec<Box<dyn Middleware>>,
+    audit: Option<Audit>,
 }
 
-pub struct DefaultHttpClient(WxPay2Credential, WxPay2Validator);
+impl DefaultHttpClient {
+    /// Create a client sending requests with the default [ReqwestTransport],
+    /// fails if the `reqwest` client can't be built.
+    #[cfg(feature = "reqwest")]
+    pub fn new(credential: WxPay2Credential, validator: WxPay2Validator) -> Result<Self, String> {
+        let transport = Arc::new(ReqwestTransport::try_default()?);
+        let client = Self::from_transport(transport.clone(), credential, validator);
+        #[cfg(feature = "blocking")]
+        let client = client.with_blocking_transport(transport);
+        Ok(client)
+    }
+
+    /// Create a client sending requests with `transport`.
+    pub fn from_transport(
+        transport: Arc<dyn HttpTransport>,
+        credential: WxPay2Credential,
+        validator: WxPay2Validator,
+    ) -> Self {
+        Self {
+            transports: Transports {
+                transport,
+                #[cfg(feature = "blocking")]
+                blocking_transport: None,
+            },
+            credential,
+            validator,
+            retry_policy: RetryPolicy::default(),
+            hosts: HostSelector::default(),
+            rate_limiter: None,
+            timeout: None,
+            layers: Vec::new(),
+            inner_layers: Vec::new(),
+            audit: None,
+        }
+    }
 
+    /// Replace the transport of async calls.
+    pub fn with_transport(mut self, transport: Arc<dyn HttpTransport>) -> Self {
+        self.transports.transport = transport;
+        self
+    }
+
+    /// Replace the transport of blocking calls.
+    #[cfg(feature = "blocking")]
+    pub fn with_blocking_transport(mut self, transport: Arc<dyn BlockingHttpTransport>) -> Self {
+        self.transports.blocking_transport = Some(transport);
+        self
+    }
+
+    /// Replace the retry policy.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Replace hosts to send requests to.
+    pub fn with_hosts(mut self, hosts: HostSelector) -> Self {
+        self.hosts = hosts;
+        self
+    }
+
+    /// Limit requests sent per merchant and path.
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Timeout of every attempt.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        let paths = self.timeout.take().unwrap_or_default();
+        self.timeout = Some(Timeout::new(timeout).with_paths_of(paths));
+        self
+    }
+
+    /// Timeout of every attempt on paths starting with `path_prefix`,
+    /// e.g. longer for `/v3/billdownload`.
+    pub fn with_path_timeout(mut self, path_prefix: impl AsRef<str>, timeout: Duration) -> Self {
+        let base = self.timeout.take().unwrap_or_default();
+        self.timeout = Some(base.with_path_timeout(path_prefix, timeout));
+        self
+    }
+
+    /// Add a layer around the whole call, retries included.
+    /// Layers added first are the outermost.
+    pub fn with_layer(mut self, layer: impl Middleware + 'static) -> Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    /// Add a layer around every attempt on every host, after signing and validation.
+    /// Layers added first are the outermost.
+    pub fn with_inner_layer(mut self, layer: impl Middleware + 'static) -> Self {
+        self.inner_layers.push(Box::new(layer));
+        self
+    }
+
+    /// Record every attempt to `sink`, bodies are redacted according to `redaction`.
+    pub fn with_audit_sink(mut self, sink: Arc<dyn AuditSink>, redaction: AuditRedaction) -> Self {
+        self.audit = Some(Audit::new(sink, redaction));
+        self
+    }
+
+    fn get_layers(&self) -> Vec<&dyn Middleware> {
+        let mut layers = self
+            .layers
+            .iter()
+            .map(|layer| layer.as_ref())
+            .collect::<Vec<_>>();
+        layers.push(&self.retry_policy);
+        if let Some(rate_limiter) = &self.rate_limiter {
+            layers.push(rate_limiter);
+        }
+        layers.push(&self.credential);
+        layers.push(&self.validator);
+        if let Some(timeout) = &self.timeout {
+            layers.push(timeout);
+        }
+        layers.extend(self.inner_layers.iter().map(|layer| layer.as_ref()));
+        layers.push(&self.hosts);
+        if let Some(audit) = &self.audit {
+            layers.push(audit);
+        }
+        layers
+    }
+
+    fn into_api_response<R>(response: HttpResponse) -> Result<ApiResponse<R>, HttpError>
+    where
+        R: DeserializeOwned,
+    {
+        let body = if response.body.is_empty() {
+            b"null"
+        } else {
+            response.body.as_slice()
+        };
+        let request_id = response.get_request_id().map(|id| id.to_string());
+        let body = serde_json::from_slice(body).map_err(|source| HttpError::Deserialize {
+            source,
+            request_id: request_id.clone(),
+        })?;
+        Ok(ApiResponse {
+            host: response.host,
+            status: response.status,
+            request_id,
+            body,
+        })
+    }
+
+    /// Span of a whole call, retries included.
+    fn call_span(request: &HttpRequest) -> tracing::Span {
+        tracing::info_span!(
+            "wechat_pay.call",
+            merchant_id = %request.merchant_id,
+            method = %request.method,
+            path = %request.path,
+            status = tracing::field::Empty,
+            code = tracing::field::Empty,
+            request_id = tracing::field::Empty,
+        )
+    }
+
+    fn record_result<R>(
+        request: &(Method, String),
+        started: Instant,
+        span: &tracing::Span,
+        result: &Result<ApiResponse<R>, HttpError>,
+    ) {
+        let (method, path) = request;
+        let endpoint = metrics::get_endpoint(path);
+        let status = match result {
+            Ok(response) => Some(response.status),
+            Err(HttpError::Api { status, .. }) => Some(*status),
+            Err(_) => None,
+        };
+        let recorder = metrics::recorder();
+        recorder.record_request(method.as_str(), &endpoint, status, started.elapsed());
+        if let Some(code) = result.as_ref().err().and_then(|e| e.get_code()) {
+            recorder.increment_error_code(&endpoint, code);
+        }
+        match result {
+            Ok(response) => {
+                span.record("status", response.status);
+                if let Some(request_id) = response.get_request_id() {
+                    span.record("request_id", request_id);
+                }
+            }
+            Err(e) => {
+                if let HttpError::Api { status, .. } = e {
+                    span.record("status", status);
+                }
+                if let Some(code) = e.get_code() {
+                    span.record("code", code);
+                }
+                if let Some(request_id) = e.get_request_id() {
+                    span.record("request_id", request_id);
+                }
+                tracing::warn!(parent: span, error = %e, "WeChat Pay api call failed");
+            }
+        }
+    }
+
+    async fn execute<R>(&self, mut request: HttpRequest) -> Result<ApiResponse<R>, HttpError>
+    where
+        R: DeserializeOwned,
+    {
+        request.merchant_id = self.credential.get_merchant_id().to_string();
+        let span = Self::call_span(&request);
+        let started = Instant::now();
+        let call = (request.method.clone(), request.path.clone());
+        let layers = self.get_layers();
+        let result = Next::new(&layers, &self.transports)
+            .run(request)
+            .instrument(span.clone())
+            .await
+            .and_then(Self::into_api_response);
+        Self::record_result(&call, started, &span, &result);
+        result
+    }
+
+    #[cfg(feature = "blocking")]
+    fn execute_blocking<R>(&self, mut request: HttpRequest) -> Result<ApiResponse<R>, HttpError>
+    where
+        R: DeserializeOwned,
+    {
+        request.merchant_id = self.credential.get_merchant_id().to_string();
+        let span = Self::call_span(&request);
+        let started = Instant::now();
+        let call = (request.method.clone(), request.path.clone());
+        let layers = self.get_layers();
+        let result = span
+            .in_scope(|| Next::new(&layers, &self.transports).run_blocking(request))
+            .and_then(Self::into_api_response);
+        Self::record_result(&call, started, &span, &result);
+        result
+    }
+}
+
+/// Http client to call WeChat Pay api, `path` is relative to the selected host,
+/// e.g. `/v3/pay/transactions/native`.
 #[async_trait]
 pub trait HttpClient {
     type Error;
-    async fn post<T, R>(url: &str, body: &T) -> Result<R, Self::Error>
+    async fn post<T, R>(&self, path: &str, body: &T) -> Result<ApiResponse<R>, Self::Error>
     where
         T: Serialize + Send + Sync,
-        R: Deserialize<'static>;
-    async fn get<R>(url: &str) -> Result<R, Self::Error>
+        R: DeserializeOwned + Send;
+    async fn get<R>(&self, path: &str) -> Result<ApiResponse<R>, Self::Error>
     where
-        R: Deserialize<'static>;
+        R: DeserializeOwned + Send;
 
     #[cfg(feature = "blocking")]
-    fn post_blocking<T, R>(url: &str, body: &T) -> Result<R, Self::Error>
+    fn post_blocking<T, R>(&self, path: &str, body: &T) -> Result<ApiResponse<R>, Self::Error>
     where
         T: Serialize,
-        R: Deserialize<'static>;
+        R: DeserializeOwned;
     #[cfg(feature = "blocking")]
-    fn get_blocking<R>(url: &str) -> Result<R, Self::Error>
+    fn get_blocking<R>(&self, path: &str) -> Result<ApiResponse<R>, Self::Error>
     where
-        R: Deserialize<'static>;
+        R: DeserializeOwned;
 }
 
 #[async_trait]
 impl HttpClient for DefaultHttpClient {
-    type Error = Box<dyn std::error::Error>;
+    type Error = HttpError;
 
-    async fn post<T, R>(url: &str, body: &T) -> Result<R, Self::Error>
+    async fn post<T, R>(&self, path: &str, body: &T) -> Result<ApiResponse<R>, Self::Error>
     where
         T: Serialize + Send + Sync,
-        R: Deserialize<'static>,
+        R: DeserializeOwned + Send,
     {
-        todo!()
+        let body = serde_json::to_string(body).map_err(HttpError::Serialize)?;
+        self.execute(HttpRequest::new(Method::POST, path, Some(body)))
+            .await
     }
-    async fn get<R>(url: &str) -> Result<R, Self::Error>
+    async fn get<R>(&self, path: &str) -> Result<ApiResponse<R>, Self::Error>
     where
-        R: Deserialize<'static>,
+        R: DeserializeOwned + Send,
     {
-        todo!()
+        self.execute(HttpRequest::new(Method::GET, path, None))
+            .await
     }
 
     #[cfg(feature = "blocking")]
-    fn post_blocking<T, R>(url: &str, body: &T) -> Result<R, Self::Error>
+    fn post_blocking<T, R>(&self, path: &str, body: &T) -> Result<ApiResponse<R>, Self::Error>
     where
         T: Serialize,
-        R: Deserialize<'static>,
+        R: DeserializeOwned,
     {
-        todo!()
+        let body = serde_json::to_string(body).map_err(HttpError::Serialize)?;
+        self.execute_blocking(HttpRequest::new(Method::POST, path, Some(body)))
     }
     #[cfg(feature = "blocking")]
-    fn get_blocking<R>(url: &str) -> Result<R, Self::Error>
+    fn get_blocking<R>(&self, path: &str) -> Result<ApiResponse<R>, Self::Error>
     where
-        R: Deserialize<'static>,
+        R: DeserializeOwned,
     {
-        todo!()
+        self.execute_blocking(HttpRequest::new(Method::GET, path, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::RsaSigner;
+    use crate::ratelimit::Quota;
+    use crate::transport::InMemoryTransport;
+    use crate::verify::CertificatesVerifier;
+    use std::time::Duration;
+
+    const MERCHANT_KEY: &str = include_str!("../testdata/merchant_key.pem");
+    const HOST: &str = "https://api.mch.weixin.qq.com";
+
+    /// A client of merchant `1900000001` sending to [HOST] through `transport`, validating
+    /// responses signed with [signed_response].
+    fn new_client(transport: Arc<InMemoryTransport>) -> DefaultHttpClient {
+        let signer =
+            RsaSigner::new("6048A6A668D316A4EBA392BD0CA4FEAABDCB611E", MERCHANT_KEY).unwrap();
+        DefaultHttpClient::from_transport(
+            transport,
+            WxPay2Credential::new("1900000001", signer),
+            platform_validator(),
+        )
+        .with_retry_policy(
+            RetryPolicy::default().with_backoff(Duration::from_millis(1), Duration::from_millis(5)),
+        )
+        .with_hosts(HostSelector::single(HOST))
+    }
+
+    fn get_authorizations(transport: &InMemoryTransport) -> Vec<String> {
+        transport
+            .get_requests()
+            .iter()
+            .map(|request| request.headers.get("Authorization").unwrap().clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_retry_with_fresh_signature() {
+        let transport = Arc::new(InMemoryTransport::with_responses(vec![
+            (500, r#"{"code":"SYSTEM_ERROR","message":"busy"}"#),
+            (500, r#"{"code":"SYSTEM_ERROR","message":"busy"}"#),
+            (400, r#"{"code":"PARAM_ERROR","message":"invalid"}"#),
+        ]));
+        let path = "/v3/pay/transactions/out-trade-no/1217752501201407033233368018";
+        let result = new_client(transport.clone())
+            .get::<serde_json::Value>(path)
+            .await;
+        match result {
+            Err(HttpError::Api { status, code, .. }) => {
+                assert_eq!(400, status);
+                assert_eq!("PARAM_ERROR", code);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let authorizations = get_authorizations(&transport);
+        assert_eq!(3, authorizations.len());
+        assert!(authorizations[0].starts_with("WECHATPAY2-SHA256-RSA2048 mchid=\"1900000001\""));
+        assert_ne!(authorizations[0], authorizations[1]);
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_without_idempotency_key() {
+        let transport = Arc::new(InMemoryTransport::with_responses(vec![(
+            500,
+            r#"{"code":"SYSTEM_ERROR","message":"busy"}"#,
+        )]));
+        let path = "/v3/marketing/favor/users/openid/coupons";
+        let body = serde_json::json!({"stock_id": "9856000"});
+        let result = new_client(transport.clone())
+            .post::<_, serde_json::Value>(path, &body)
+            .await;
+        assert!(matches!(result, Err(HttpError::Api { status: 500, .. })));
+        assert_eq!(1, transport.get_requests().len());
+    }
+
+    #[tokio::test]
+    async fn test_fail_over_on_connect_error() {
+        let transport = Arc::new(InMemoryTransport::new(|request| {
+            if request.host.as_deref() == Some(HOST) {
+                return Err(TransportError::new(
+                    TransportErrorKind::Connect,
+                    "connection refused",
+                ));
+            }
+            Ok(HttpResponse {
+                host: request.host.clone().unwrap(),
+                status: 404,
+                headers: Default::default(),
+                body: br#"{"code":"ORDER_NOT_EXIST","message":"not exist"}"#.to_vec(),
+            })
+        }));
+        let backup = "https://api2.mch.weixin.qq.com";
+        let client =
+            new_client(transport.clone()).with_hosts(HostSelector::new([HOST, backup]).unwrap());
+
+        let result = client
+            .get::<serde_json::Value>("/v3/pay/transactions/id/4200000001")
+            .await;
+        assert!(matches!(result, Err(HttpError::Api { status: 404, .. })));
+        let hosts = transport
+            .get_requests()
+            .into_iter()
+            .map(|request| request.host.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![HOST, backup], hosts);
+    }
+
+    #[tokio::test]
+    async fn test_no_fail_over_on_timeout() {
+        let transport = Arc::new(InMemoryTransport::new(|_| {
+            Err(TransportError::new(
+                TransportErrorKind::Timeout,
+                "timed out",
+            ))
+        }));
+        let client = new_client(transport.clone())
+            .with_hosts(HostSelector::new([HOST, "https://api2.mch.weixin.qq.com"]).unwrap())
+            .with_retry_policy(RetryPolicy::disabled());
+
+        let result = client
+            .get::<serde_json::Value>("/v3/pay/transactions/id/4200000001")
+            .await;
+        assert!(matches!(result, Err(ref e) if e.is_timeout()));
+        assert_eq!(1, transport.get_requests().len());
+    }
+
+    #[tokio::test]
+    async fn test_slow_down_on_frequency_limited() {
+        let transport = Arc::new(InMemoryTransport::with_responses(vec![(
+            429,
+            r#"{"code":"FREQUENCY_LIMITED","message":"slow down"}"#,
+        )]));
+        // without slowing down, the next request would wait 100ms.
+        let limiter = RateLimiter::new(Quota::new(10.0, 1))
+            .with_min_rate(1.0)
+            .with_max_wait(Duration::from_millis(150));
+        let client = new_client(transport)
+            .with_rate_limiter(limiter)
+            .with_retry_policy(RetryPolicy::disabled());
+
+        let path = "/v3/bill/tradebill?bill_date=2019-06-11";
+        let result = client.get::<serde_json::Value>(path).await;
+        assert!(matches!(result, Err(ref e) if e.is_frequency_limited()));
+        // the rate is lowered, so the next request can't be sent within the deadline.
+        let result = client.get::<serde_json::Value>(path).await;
+        assert!(matches!(result, Err(HttpError::RateLimited)));
+    }
+
+    /// Adds a header to every request.
+    struct AddHeader;
+
+    #[async_trait]
+    impl Middleware for AddHeader {
+        async fn handle(
+            &self,
+            mut request: HttpRequest,
+            next: Next<'_>,
+        ) -> Result<HttpResponse, HttpError> {
+            request.headers.insert("X-Trace", "42");
+            next.run(request).await
+        }
+    }
+
+    /// Fails the first attempt.
+    struct FailOnce(std::sync::atomic::AtomicBool);
+
+    #[async_trait]
+    impl Middleware for FailOnce {
+        async fn handle(
+            &self,
+            request: HttpRequest,
+            next: Next<'_>,
+        ) -> Result<HttpResponse, HttpError> {
+            if !self.0.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                return Err(HttpError::from_response(
+                    500,
+                    r#"{"code":"SYSTEM_ERROR","message":"injected"}"#,
+                ));
+            }
+            next.run(request).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_layers() {
+        let transport = Arc::new(InMemoryTransport::with_responses(vec![(
+            404,
+            r#"{"code":"ORDER_NOT_EXIST","message":"not exist"}"#,
+        )]));
+        let client = new_client(transport.clone())
+            .with_layer(AddHeader)
+            .with_inner_layer(FailOnce(Default::default()));
+
+        let result = client
+            .get::<serde_json::Value>("/v3/pay/transactions/id/4200000001")
+            .await;
+        assert!(matches!(result, Err(HttpError::Api { status: 404, .. })));
+        // the injected failure is retried, and the request reaches the transport once.
+        let requests = transport.get_requests();
+        assert_eq!(1, requests.len());
+        assert_eq!(Some(&"42".to_string()), requests[0].headers.get("X-Trace"));
+    }
+
+    #[tokio::test]
+    async fn test_path_timeout() {
+        let transport = Arc::new(InMemoryTransport::with_responses(vec![(204, "")]));
+        let client = new_client(transport.clone())
+            .with_timeout(Duration::from_secs(5))
+            .with_path_timeout("/v3/billdownload", Duration::from_secs(60));
+
+        client
+            .get::<()>("/v3/pay/transactions/id/4200000001")
+            .await
+            .ok();
+        client
+            .get::<()>("/v3/billdownload/file?token=xx")
+            .await
+            .ok();
+        let timeouts = transport
+            .get_requests()
+            .into_iter()
+            .map(|request| request.timeout.unwrap().as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![5, 60], timeouts);
+    }
+
+    const PLATFORM_KEY: &str = include_str!("../testdata/platform_key.pem");
+    const PLATFORM_CERT: &str = include_str!("../testdata/platform_cert.pem");
+    const PLATFORM_SERIAL: &str = "2F85E92795F5E7F86BA33C5C365133FDFD5E7E49";
+
+    /// A response signed with the platform key.
+    fn signed_response(status: u16, body: &str, request_id: &str) -> HttpResponse {
+        use crate::cipher::Signer;
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_string();
+        let nonce = "5K8264ILTKCH16CQ2502SI8ZNMTM67VS";
+        let message = format!("{}\n{}\n{}\n", timestamp, nonce, body);
+        let signature = RsaSigner::new(PLATFORM_SERIAL, PLATFORM_KEY)
+            .unwrap()
+            .sign(&message)
+            .unwrap();
+        let mut headers = crate::header::HttpHeaders::default();
+        headers.insert("Wechatpay-Timestamp", timestamp);
+        headers.insert("Wechatpay-Nonce", nonce);
+        headers.insert("Wechatpay-Serial", PLATFORM_SERIAL);
+        headers.insert("Wechatpay-Signature", signature.get_sign());
+        headers.insert("request-id", request_id);
+        HttpResponse {
+            host: HOST.to_string(),
+            status,
+            headers,
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn platform_validator() -> WxPay2Validator {
+        let serial = PLATFORM_SERIAL.parse().unwrap();
+        let mut verifier = CertificatesVerifier::new();
+        verifier.update_certificates(std::collections::HashMap::from([(
+            serial,
+            PLATFORM_CERT.as_bytes().to_vec(),
+        )]));
+        WxPay2Validator::new(verifier)
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let transport = Arc::new(InMemoryTransport::new(|request| {
+            if request.path.ends_with("/4200000001") {
+                Ok(signed_response(
+                    200,
+                    r#"{"trade_state":"SUCCESS"}"#,
+                    "08F78BB5-1",
+                ))
+            } else {
+                Ok(signed_response(
+                    404,
+                    r#"{"code":"ORDER_NOT_EXIST","message":"not exist"}"#,
+                    "08F78BB5-2",
+                ))
+            }
+        }));
+        let client = new_client(transport);
+
+        let response = client
+            .get::<serde_json::Value>("/v3/pay/transactions/id/4200000001")
+            .await
+            .unwrap();
+        assert_eq!(Some("08F78BB5-1"), response.get_request_id());
+        assert_eq!("SUCCESS", response.body["trade_state"]);
+
+        let error = client
+            .get::<serde_json::Value>("/v3/pay/transactions/id/4200000002")
+            .await
+            .unwrap_err();
+        assert_eq!(Some("08F78BB5-2"), error.get_request_id());
+        assert_eq!(Some("ORDER_NOT_EXIST"), error.get_code());
+        assert!(error.to_string().contains("request_id=08F78BB5-2"));
+
+        // a valid response which can't be deserialized.
+        let error = client
+            .get::<Vec<String>>("/v3/pay/transactions/id/4200000001")
+            .await
+            .unwrap_err();
+        assert!(matches!(error, HttpError::Deserialize { .. }));
+        assert_eq!(Some("08F78BB5-1"), error.get_request_id());
+    }
+
+    /// Records events as strings.
+    #[derive(Default)]
+    struct TestRecorder(std::sync::Mutex<Vec<String>>);
+
+    impl metrics::MetricsRecorder for TestRecorder {
+        fn record_request(&self, method: &str, endpoint: &str, status: Option<u16>, _: Duration) {
+            let event = format!("request {} {} {:?}", method, endpoint, status);
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn increment_error_code(&self, endpoint: &str, code: &str) {
+            let event = format!("error {} {}", endpoint, code);
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn increment_verification_failure(&self, reason: metrics::VerificationFailure) {
+            let event = format!("verification {}", reason.as_str());
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn record_certificate(&self, serial_number: &str, _: Duration, expires_in: Duration) {
+            let event = format!(
+                "certificate {} {}",
+                serial_number,
+                expires_in > Duration::ZERO
+            );
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let recorder = Arc::new(TestRecorder::default());
+        let _guard = metrics::set_local_recorder(recorder.clone());
+
+        let transport = Arc::new(InMemoryTransport::new(|request| {
+            let mut response = signed_response(200, "{}", "08F78BB5-3");
+            if request.path.ends_with("/1217752501201407033233368019") {
+                response.status = 400;
+                response.body = br#"{"code":"PARAM_ERROR","message":"invalid"}"#.to_vec();
+            } else {
+                // tampered after signing.
+                response.body = br#"{"status":"SUCCESS"}"#.to_vec();
+            }
+            Ok(response)
+        }));
+        let client = new_client(transport).with_retry_policy(RetryPolicy::disabled());
+
+        let path = "/v3/refund/domestic/refunds/1217752501201407033233368018";
+        assert!(client.get::<serde_json::Value>(path).await.is_err());
+        let path = "/v3/refund/domestic/refunds/1217752501201407033233368019";
+        assert!(client.get::<serde_json::Value>(path).await.is_err());
+
+        let events = recorder.0.lock().unwrap().clone();
+        let contains = |event: &str| events.iter().any(|e| e == event);
+        let certificate = format!("certificate {} true", PLATFORM_SERIAL);
+        assert!(contains(&certificate), "{:?}", events);
+        assert!(contains("verification bad_signature"));
+        assert!(contains(
+            "request GET /v3/refund/domestic/refunds/{id} None"
+        ));
+        assert!(contains(
+            "request GET /v3/refund/domestic/refunds/{id} Some(400)"
+        ));
+        assert!(contains(
+            "error /v3/refund/domestic/refunds/{id} PARAM_ERROR"
+        ));
+    }
+
+    #[cfg(feature = "blocking")]
+    fn blocking_client(transport: Arc<InMemoryTransport>) -> DefaultHttpClient {
+        new_client(transport.clone()).with_blocking_transport(transport)
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_blocking_retry_with_fresh_signature() {
+        let transport = Arc::new(InMemoryTransport::with_responses(vec![
+            (500, r#"{"code":"SYSTEM_ERROR","message":"busy"}"#),
+            (429, r#"{"code":"FREQUENCY_LIMITED","message":"slow down"}"#),
+            (404, r#"{"code":"ORDER_NOT_EXIST","message":"not exist"}"#),
+        ]));
+        let client = blocking_client(transport.clone())
+            .with_rate_limiter(RateLimiter::new(Quota::new(100.0, 10)));
+
+        let path = "/v3/pay/transactions/out-trade-no/1217752501201407033233368018";
+        let result = client.get_blocking::<serde_json::Value>(path);
+        assert!(matches!(result, Err(HttpError::Api { status: 404, .. })));
+        let authorizations = get_authorizations(&transport);
+        assert_eq!(3, authorizations.len());
+        assert_ne!(authorizations[0], authorizations[1]);
+
+        // not idempotent without an out_refund_no or similar key.
+        let path = "/v3/marketing/favor/users/openid/coupons";
+        let body = serde_json::json!({"stock_id": "9856000"});
+        let transport = Arc::new(InMemoryTransport::with_responses(vec![(
+            500,
+            r#"{"code":"SYSTEM_ERROR","message":"busy"}"#,
+        )]));
+        let result =
+            blocking_client(transport.clone()).post_blocking::<_, serde_json::Value>(path, &body);
+        assert!(matches!(result, Err(HttpError::Api { status: 500, .. })));
+        assert_eq!(1, transport.get_requests().len());
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_blocking_fail_over_on_connect_error() {
+        let transport = Arc::new(InMemoryTransport::new(|request| {
+            if request.host.as_deref() == Some(HOST) {
+                return Err(TransportError::new(
+                    TransportErrorKind::Connect,
+                    "connection refused",
+                ));
+            }
+            Ok(signed_response(204, "", "08F78BB5-5"))
+        }));
+        let backup = "https://api2.mch.weixin.qq.com";
+        let client = blocking_client(transport.clone())
+            .with_hosts(HostSelector::new([HOST, backup]).unwrap());
+
+        let path = "/v3/pay/transactions/out-trade-no/1217752501201407033233368018/close";
+        let body = serde_json::json!({"mchid": "1900000001"});
+        let response = client.post_blocking::<_, ()>(path, &body).unwrap();
+        assert_eq!(204, response.status);
+        assert_eq!(Some("08F78BB5-5"), response.get_request_id());
+        let hosts = transport
+            .get_requests()
+            .into_iter()
+            .map(|request| request.host.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![HOST, backup], hosts);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_blocking_validation() {
+        let transport = Arc::new(InMemoryTransport::new(|request| {
+            let mut response = signed_response(200, r#"{"trade_state":"SUCCESS"}"#, "08F78BB5-6");
+            if request.path.ends_with("/4200000002") {
+                // tampered after signing.
+                response.body = br#"{"trade_state":"REFUND"}"#.to_vec();
+            }
+            Ok(response)
+        }));
+        let sink = Arc::new(MemorySink::default());
+        let client = blocking_client(transport.clone())
+            .with_audit_sink(sink.clone(), AuditRedaction::default());
+
+        let response = client
+            .get_blocking::<serde_json::Value>("/v3/pay/transactions/id/4200000001")
+            .unwrap();
+        assert_eq!("SUCCESS", response.body["trade_state"]);
+
+        let error = client
+            .get_blocking::<serde_json::Value>("/v3/pay/transactions/id/4200000002")
+            .unwrap_err();
+        assert!(matches!(error, HttpError::Validation { .. }));
+        assert_eq!(Some("08F78BB5-6"), error.get_request_id());
+        // validation failures are not retried.
+        assert_eq!(2, transport.get_requests().len());
+        assert_eq!(2, sink.0.lock().unwrap().len());
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_blocking_without_transport() {
+        let transport = Arc::new(InMemoryTransport::with_responses(vec![(204, "")]));
+        let result = new_client(transport).get_blocking::<()>("/v3/certificates");
+        assert!(matches!(result, Err(HttpError::Transport(_))));
+    }
+
+    #[derive(Default)]
+    struct MemorySink(std::sync::Mutex<Vec<crate::audit::AuditRecord>>);
+
+    impl AuditSink for MemorySink {
+        fn record(&self, record: &crate::audit::AuditRecord) {
+            self.0.lock().unwrap().push(record.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit() {
+        let transport = Arc::new(InMemoryTransport::new(|request| {
+            let mut response = signed_response(
+                400,
+                r#"{"code":"PARAM_ERROR","message":"invalid"}"#,
+                "08F78BB5-4",
+            );
+            response.host = request.host.clone().unwrap();
+            Ok(response)
+        }));
+        let sink = Arc::new(MemorySink::default());
+        let client = new_client(transport)
+            .with_retry_policy(RetryPolicy::disabled())
+            .with_audit_sink(sink.clone(), AuditRedaction::default());
+
+        let path = "/v3/pay/transactions/jsapi";
+        let body = serde_json::json!({"payer": {"openid": "o4GgauInH_RCEdvrrNGrntXDuXXX"}});
+        assert!(client
+            .post::<_, serde_json::Value>(path, &body)
+            .await
+            .is_err());
+
+        let records = sink.0.lock().unwrap();
+        assert_eq!(1, records.len());
+        let record = &records[0];
+        assert_eq!(Some("1900000001"), record.merchant_id.as_deref());
+        assert_eq!(Some("POST"), record.method.as_deref());
+        assert_eq!(path, record.endpoint);
+        assert_eq!(Some(HOST), record.host.as_deref());
+        assert_eq!(Some("08F78BB5-4"), record.request_id.as_deref());
+        assert_eq!(Some(400), record.status);
+        assert_eq!(Some("PARAM_ERROR"), record.error.as_deref());
+        assert!(record.body_sha256.is_some());
+        assert!(record.body.is_none());
     }
 }
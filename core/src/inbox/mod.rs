@@ -0,0 +1,449 @@
+//! A durable inbox of verified notifications.
+//!
+//! WeChat Pay redelivers a notification until it is acknowledged, the inbox persists it before
+//! it is handled, skips ids already processed and moves events failing too often to a
+//! dead-letter list to be replayed, see [NotificationRouter::with_inbox]. A delivery claims
+//! its entry while handling it, a redelivery arriving meanwhile is failed to be retried later.
+//!
+//! Stores hold decrypted resources, which may contain personal data.
+//!
+//! [NotificationRouter::with_inbox]: crate::router::NotificationRouter::with_inbox
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::notification::Notification;
+use crate::prelude::*;
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// State of a notification in the inbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InboxStatus {
+    /// Received, not handled successfully yet.
+    Pending,
+    /// Being handled by a delivery since [InboxEntry::claimed_at].
+    Processing,
+    Processed,
+    /// Failed too often or acknowledged on failure, waiting for a replay.
+    DeadLetter,
+}
+
+impl InboxStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InboxStatus::Pending => "pending",
+            InboxStatus::Processing => "processing",
+            InboxStatus::Processed => "processed",
+            InboxStatus::DeadLetter => "dead_letter",
+        }
+    }
+}
+
+impl std::str::FromStr for InboxStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(InboxStatus::Pending),
+            "processing" => Ok(InboxStatus::Processing),
+            "processed" => Ok(InboxStatus::Processed),
+            "dead_letter" => Ok(InboxStatus::DeadLetter),
+            _ => Err(format!("invalid inbox status {}", s)),
+        }
+    }
+}
+
+/// A notification in the inbox.
+#[derive(Debug, Clone)]
+pub struct InboxEntry {
+    pub notification: Notification,
+    pub status: InboxStatus,
+    /// Number of failed attempts to handle it.
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// Unix timestamp in seconds of the first delivery.
+    pub received_at: u64,
+    /// Unix timestamp in seconds of the claim of a [InboxStatus::Processing] entry.
+    pub claimed_at: Option<u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+impl InboxEntry {
+    pub fn new(notification: Notification) -> Self {
+        Self {
+            notification,
+            status: InboxStatus::Pending,
+            attempts: 0,
+            last_error: None,
+            received_at: now(),
+            claimed_at: None,
+        }
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.notification.id
+    }
+}
+
+/// Persists inbox entries, object safe so that it can be shared.
+pub trait InboxStore: Send + Sync {
+    /// Insert `entry` unless its id is known, returns the entry already stored if any.
+    fn insert(&self, entry: &InboxEntry) -> Result<Option<InboxEntry>, String>;
+
+    fn get(&self, id: &str) -> Result<Option<InboxEntry>, String>;
+
+    /// Atomically mark an entry in `status`, other than processing, or a processing one
+    /// claimed before `expired_before`, processing since `claimed_at`, returns the claimed
+    /// entry or `None`.
+    fn claim(
+        &self,
+        id: &str,
+        status: InboxStatus,
+        claimed_at: u64,
+        expired_before: u64,
+    ) -> Result<Option<InboxEntry>, String>;
+
+    /// Replace the status, attempts, last error and claim of a stored entry.
+    fn update(&self, entry: &InboxEntry) -> Result<(), String>;
+
+    /// Entries with `status`, the oldest first.
+    fn list(&self, status: InboxStatus, limit: usize) -> Result<Vec<InboxEntry>, String>;
+}
+
+impl<S: InboxStore + ?Sized> InboxStore for Arc<S> {
+    fn insert(&self, entry: &InboxEntry) -> Result<Option<InboxEntry>, String> {
+        (**self).insert(entry)
+    }
+
+    fn get(&self, id: &str) -> Result<Option<InboxEntry>, String> {
+        (**self).get(id)
+    }
+
+    fn claim(
+        &self,
+        id: &str,
+        status: InboxStatus,
+        claimed_at: u64,
+        expired_before: u64,
+    ) -> Result<Option<InboxEntry>, String> {
+        (**self).claim(id, status, claimed_at, expired_before)
+    }
+
+    fn update(&self, entry: &InboxEntry) -> Result<(), String> {
+        (**self).update(entry)
+    }
+
+    fn list(&self, status: InboxStatus, limit: usize) -> Result<Vec<InboxEntry>, String> {
+        (**self).list(status, limit)
+    }
+}
+
+/// An inbox store in memory, for tests and single instances which can afford losing entries
+/// on restart.
+#[derive(Default)]
+pub struct InMemoryInboxStore(Mutex<HashMap<String, InboxEntry>>);
+
+impl InMemoryInboxStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl InboxStore for InMemoryInboxStore {
+    fn insert(&self, entry: &InboxEntry) -> Result<Option<InboxEntry>, String> {
+        let mut entries = self.0.lock().unwrap();
+        if let Some(existing) = entries.get(entry.get_id()) {
+            return Ok(Some(existing.clone()));
+        }
+        entries.insert(entry.get_id().to_string(), entry.clone());
+        Ok(None)
+    }
+
+    fn get(&self, id: &str) -> Result<Option<InboxEntry>, String> {
+        Ok(self.0.lock().unwrap().get(id).cloned())
+    }
+
+    fn claim(
+        &self,
+        id: &str,
+        status: InboxStatus,
+        claimed_at: u64,
+        expired_before: u64,
+    ) -> Result<Option<InboxEntry>, String> {
+        let mut entries = self.0.lock().unwrap();
+        let Some(stored) = entries.get_mut(id) else {
+            return Ok(None);
+        };
+        let claimable = match stored.status {
+            InboxStatus::Processing => stored.claimed_at.unwrap_or_default() <= expired_before,
+            stored_status => stored_status == status,
+        };
+        if !claimable {
+            return Ok(None);
+        }
+        stored.status = InboxStatus::Processing;
+        stored.claimed_at = Some(claimed_at);
+        Ok(Some(stored.clone()))
+    }
+
+    fn update(&self, entry: &InboxEntry) -> Result<(), String> {
+        let mut entries = self.0.lock().unwrap();
+        let Some(stored) = entries.get_mut(entry.get_id()) else {
+            return Err(format!("notification {} not found", entry.get_id()));
+        };
+        stored.status = entry.status;
+        stored.attempts = entry.attempts;
+        stored.last_error = entry.last_error.clone();
+        stored.claimed_at = entry.claimed_at;
+        Ok(())
+    }
+
+    fn list(&self, status: InboxStatus, limit: usize) -> Result<Vec<InboxEntry>, String> {
+        let entries = self.0.lock().unwrap();
+        let mut listed = entries
+            .values()
+            .filter(|entry| entry.status == status)
+            .cloned()
+            .collect::<Vec<_>>();
+        listed.sort_by(|a, b| (a.received_at, a.get_id()).cmp(&(b.received_at, b.get_id())));
+        listed.truncate(limit);
+        Ok(listed)
+    }
+}
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_LEASE: Duration = Duration::from_secs(5 * 60);
+
+/// Outcome of [NotificationInbox::receive].
+#[derive(Debug)]
+pub enum Delivery {
+    /// Claimed by this delivery, to be handled.
+    Claimed(Box<InboxEntry>),
+    /// Processed or dead-lettered already, to be acknowledged.
+    Duplicate(InboxStatus),
+    /// Being handled by another delivery, to be failed so that WeChat Pay retries it later.
+    InFlight,
+}
+
+/// De-duplicates and persists verified notifications in an [InboxStore].
+#[derive(Clone)]
+pub struct NotificationInbox {
+    store: Arc<dyn InboxStore>,
+    max_attempts: u32,
+    lease: Duration,
+}
+
+impl NotificationInbox {
+    pub fn new(store: impl InboxStore + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            lease: DEFAULT_LEASE,
+        }
+    }
+
+    /// Move an event to the dead-letter list after `max_attempts` failures, 5 by default.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Let a redelivery take over an entry claimed longer than `lease` ago, e.g. by an
+    /// instance which crashed, 5 minutes by default.
+    pub fn with_lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    pub fn get_store(&self) -> &dyn InboxStore {
+        self.store.as_ref()
+    }
+
+    /// Persist a verified notification and claim it for this delivery.
+    pub fn receive(&self, notification: Notification) -> Result<Delivery, String> {
+        let mut entry = InboxEntry::new(notification);
+        entry.status = InboxStatus::Processing;
+        entry.claimed_at = Some(entry.received_at);
+        let Some(existing) = self.store.insert(&entry)? else {
+            return Ok(Delivery::Claimed(Box::new(entry)));
+        };
+        if matches!(
+            existing.status,
+            InboxStatus::Processed | InboxStatus::DeadLetter
+        ) {
+            debug!(
+                "Skipped duplicated notification {}, {}",
+                existing.get_id(),
+                existing.status.as_str()
+            );
+            return Ok(Delivery::Duplicate(existing.status));
+        }
+        match self.claim(existing.get_id(), InboxStatus::Pending)? {
+            Some(entry) => {
+                debug!("Redelivered pending notification {}", entry.get_id());
+                Ok(Delivery::Claimed(Box::new(entry)))
+            }
+            None => {
+                debug!("Notification {} is in flight", existing.get_id());
+                Ok(Delivery::InFlight)
+            }
+        }
+    }
+
+    /// Claim a stored entry in `status`, or one whose claim expired, e.g. a dead letter to
+    /// replay, returns `None` if it is in flight or no longer in `status`.
+    pub fn claim(&self, id: &str, status: InboxStatus) -> Result<Option<InboxEntry>, String> {
+        let now = now();
+        let expired_before = now.saturating_sub(self.lease.as_secs());
+        self.store.claim(id, status, now, expired_before)
+    }
+
+    /// Mark an entry handled successfully.
+    pub fn complete(&self, entry: &mut InboxEntry) -> Result<(), String> {
+        entry.status = InboxStatus::Processed;
+        entry.last_error = None;
+        entry.claimed_at = None;
+        self.store.update(entry)
+    }
+
+    /// Record a failure, returns whether the entry is dead-lettered, after too many attempts
+    /// or right away with `dead_letter`.
+    pub fn fail(
+        &self,
+        entry: &mut InboxEntry,
+        error: &str,
+        dead_letter: bool,
+    ) -> Result<bool, String> {
+        entry.attempts += 1;
+        entry.last_error = Some(error.to_string());
+        entry.status = InboxStatus::Pending;
+        entry.claimed_at = None;
+        if dead_letter || entry.attempts >= self.max_attempts {
+            warn!(
+                "Dead-lettered notification {} after {} attempts: {}",
+                entry.get_id(),
+                entry.attempts,
+                error
+            );
+            entry.status = InboxStatus::DeadLetter;
+        }
+        self.store.update(entry)?;
+        Ok(entry.status == InboxStatus::DeadLetter)
+    }
+
+    /// Dead-lettered entries, the oldest first.
+    pub fn dead_letters(&self, limit: usize) -> Result<Vec<InboxEntry>, String> {
+        self.store.list(InboxStatus::DeadLetter, limit)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn new_notification(id: &str) -> Notification {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "create_time": "2015-05-20T13:29:35+08:00",
+            "event_type": "REFUND.SUCCESS",
+            "resource_type": "encrypt-resource",
+            "summary": "退款成功",
+            "resource": {
+                "algorithm": "AEAD_AES_256_GCM",
+                "ciphertext": "",
+                "nonce": "fdasflkja484",
+                "original_type": "refund"
+            },
+            "decrypt_data": r#"{"out_refund_no":"R1"}"#
+        }))
+        .unwrap()
+    }
+
+    /// Exercise the contract of an [InboxStore].
+    pub(crate) fn check_store(store: impl InboxStore + 'static) {
+        let store = Arc::new(store);
+        let inbox = NotificationInbox::new(store.clone()).with_max_attempts(2);
+        let claimed = |delivery| match delivery {
+            Delivery::Claimed(entry) => *entry,
+            delivery => panic!("unexpected {:?}", delivery),
+        };
+        let mut entry = claimed(inbox.receive(new_notification("EV-1")).unwrap());
+        assert_eq!(InboxStatus::Processing, entry.status);
+        assert_eq!(Some(entry.received_at), entry.claimed_at);
+
+        // redelivered while it is handled.
+        assert!(matches!(
+            inbox.receive(new_notification("EV-1")).unwrap(),
+            Delivery::InFlight
+        ));
+        assert!(!inbox.fail(&mut entry, "timeout", false).unwrap());
+        let stored = inbox.get_store().get("EV-1").unwrap().unwrap();
+        assert_eq!(InboxStatus::Pending, stored.status);
+        assert_eq!(None, stored.claimed_at);
+
+        // redelivered after a failure.
+        let mut entry = claimed(inbox.receive(new_notification("EV-1")).unwrap());
+        assert_eq!(stored.received_at, entry.received_at);
+        assert_eq!(InboxStatus::Processing, entry.status);
+        assert!(inbox.fail(&mut entry, "timeout", false).unwrap());
+        assert!(matches!(
+            inbox.receive(new_notification("EV-1")).unwrap(),
+            Delivery::Duplicate(InboxStatus::DeadLetter)
+        ));
+
+        let dead_letters = inbox.dead_letters(10).unwrap();
+        assert_eq!(1, dead_letters.len());
+        assert_eq!(2, dead_letters[0].attempts);
+        assert!(inbox.claim("EV-1", InboxStatus::Pending).unwrap().is_none());
+        assert_eq!(Some("timeout"), dead_letters[0].last_error.as_deref());
+        assert_eq!(
+            Some(r#"{"out_refund_no":"R1"}"#),
+            dead_letters[0].notification.decrypt_data.as_deref()
+        );
+
+        // claimed once to be replayed.
+        let mut entry = inbox
+            .claim("EV-1", InboxStatus::DeadLetter)
+            .unwrap()
+            .unwrap();
+        assert_eq!(InboxStatus::Processing, entry.status);
+        assert!(inbox
+            .claim("EV-1", InboxStatus::DeadLetter)
+            .unwrap()
+            .is_none());
+        assert!(inbox
+            .claim("EV-1", InboxStatus::Processing)
+            .unwrap()
+            .is_none());
+        assert!(inbox.fail(&mut entry, "timeout", true).unwrap());
+
+        let mut entry = claimed(inbox.receive(new_notification("EV-2")).unwrap());
+        inbox.complete(&mut entry).unwrap();
+        assert!(matches!(
+            inbox.receive(new_notification("EV-2")).unwrap(),
+            Delivery::Duplicate(InboxStatus::Processed)
+        ));
+        let stored = inbox.get_store().get("EV-2").unwrap().unwrap();
+        assert_eq!(InboxStatus::Processed, stored.status);
+        assert!(inbox.get_store().get("EV-3").unwrap().is_none());
+
+        // the claim of a crashed delivery expires.
+        let inbox = NotificationInbox::new(store).with_lease(Duration::ZERO);
+        claimed(inbox.receive(new_notification("EV-3")).unwrap());
+        let entry = claimed(inbox.receive(new_notification("EV-3")).unwrap());
+        assert_eq!(InboxStatus::Processing, entry.status);
+    }
+
+    #[test]
+    fn test_in_memory_store() {
+        check_store(InMemoryInboxStore::new());
+    }
+}
@@ -0,0 +1,185 @@
+//! An inbox store in SQLite.
+use std::path::Path;
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use super::{InboxEntry, InboxStatus, InboxStore};
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS wechat_pay_inbox (
+    id TEXT PRIMARY KEY,
+    event_type TEXT NOT NULL,
+    notification TEXT NOT NULL,
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    last_error TEXT,
+    received_at INTEGER NOT NULL,
+    claimed_at INTEGER
+)";
+
+const SELECT: &str = "SELECT notification, status, attempts, last_error, received_at, \
+                      claimed_at FROM wechat_pay_inbox";
+
+/// notification, status, attempts, last_error, received_at, claimed_at
+type Columns = (String, String, u32, Option<String>, i64, Option<i64>);
+
+/// Stores inbox entries in the `wechat_pay_inbox` table, created if missing.
+pub struct SqliteInboxStore(Mutex<Connection>);
+
+impl SqliteInboxStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, String> {
+        let connection = Connection::open(path).map_err(to_string)?;
+        Self::from_connection(connection)
+    }
+
+    pub fn open_in_memory() -> Result<Self, String> {
+        let connection = Connection::open_in_memory().map_err(to_string)?;
+        Self::from_connection(connection)
+    }
+
+    pub fn from_connection(connection: Connection) -> Result<Self, String> {
+        connection.execute(CREATE_TABLE, []).map_err(to_string)?;
+        Ok(Self(Mutex::new(connection)))
+    }
+
+    fn read_row(row: &Row<'_>) -> rusqlite::Result<Columns> {
+        Ok((
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+            row.get(5)?,
+        ))
+    }
+
+    fn to_entry(columns: Columns) -> Result<InboxEntry, String> {
+        let (notification, status, attempts, last_error, received_at, claimed_at) = columns;
+        Ok(InboxEntry {
+            notification: serde_json::from_str(&notification).map_err(to_string)?,
+            status: status.parse()?,
+            attempts,
+            last_error,
+            received_at: received_at as u64,
+            claimed_at: claimed_at.map(|claimed_at| claimed_at as u64),
+        })
+    }
+}
+
+fn to_string(e: impl std::fmt::Display) -> String {
+    e.to_string()
+}
+
+impl InboxStore for SqliteInboxStore {
+    fn insert(&self, entry: &InboxEntry) -> Result<Option<InboxEntry>, String> {
+        let notification = serde_json::to_string(&entry.notification).map_err(to_string)?;
+        let connection = self.0.lock().unwrap();
+        let inserted = connection
+            .execute(
+                "INSERT INTO wechat_pay_inbox (id, event_type, notification, status, attempts, \
+                 last_error, received_at, claimed_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8) \
+                 ON CONFLICT (id) DO NOTHING",
+                params![
+                    entry.get_id(),
+                    entry.notification.event_type,
+                    notification,
+                    entry.status.as_str(),
+                    entry.attempts,
+                    entry.last_error,
+                    entry.received_at as i64,
+                    entry.claimed_at.map(|claimed_at| claimed_at as i64),
+                ],
+            )
+            .map_err(to_string)?;
+        if inserted > 0 {
+            return Ok(None);
+        }
+        drop(connection);
+        self.get(entry.get_id())
+    }
+
+    fn get(&self, id: &str) -> Result<Option<InboxEntry>, String> {
+        let connection = self.0.lock().unwrap();
+        connection
+            .query_row(&format!("{} WHERE id = ?1", SELECT), [id], Self::read_row)
+            .optional()
+            .map_err(to_string)?
+            .map(Self::to_entry)
+            .transpose()
+    }
+
+    fn claim(
+        &self,
+        id: &str,
+        status: InboxStatus,
+        claimed_at: u64,
+        expired_before: u64,
+    ) -> Result<Option<InboxEntry>, String> {
+        let connection = self.0.lock().unwrap();
+        let claimed = connection
+            .execute(
+                "UPDATE wechat_pay_inbox SET status = ?2, claimed_at = ?3 WHERE id = ?1 \
+                 AND ((status = ?4 AND status != ?2) \
+                 OR (status = ?2 AND IFNULL(claimed_at, 0) <= ?5))",
+                params![
+                    id,
+                    InboxStatus::Processing.as_str(),
+                    claimed_at as i64,
+                    status.as_str(),
+                    expired_before as i64,
+                ],
+            )
+            .map_err(to_string)?;
+        if claimed == 0 {
+            return Ok(None);
+        }
+        drop(connection);
+        self.get(id)
+    }
+
+    fn update(&self, entry: &InboxEntry) -> Result<(), String> {
+        let connection = self.0.lock().unwrap();
+        let updated = connection
+            .execute(
+                "UPDATE wechat_pay_inbox SET status = ?2, attempts = ?3, last_error = ?4, \
+                 claimed_at = ?5 WHERE id = ?1",
+                params![
+                    entry.get_id(),
+                    entry.status.as_str(),
+                    entry.attempts,
+                    entry.last_error,
+                    entry.claimed_at.map(|claimed_at| claimed_at as i64),
+                ],
+            )
+            .map_err(to_string)?;
+        if updated == 0 {
+            return Err(format!("notification {} not found", entry.get_id()));
+        }
+        Ok(())
+    }
+
+    fn list(&self, status: InboxStatus, limit: usize) -> Result<Vec<InboxEntry>, String> {
+        let connection = self.0.lock().unwrap();
+        let mut statement = connection
+            .prepare(&format!(
+                "{} WHERE status = ?1 ORDER BY received_at, id LIMIT ?2",
+                SELECT
+            ))
+            .map_err(to_string)?;
+        let rows = statement
+            .query_map(params![status.as_str(), limit as i64], Self::read_row)
+            .map_err(to_string)?;
+        rows.map(|row| Self::to_entry(row.map_err(to_string)?))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sqlite_store() {
+        super::super::tests::check_store(SqliteInboxStore::open_in_memory().unwrap());
+    }
+}
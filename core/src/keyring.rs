@@ -0,0 +1,371 @@
+//! Rotation of merchant keys and APIv3 keys.
+//!
+//! While a merchant certificate is renewed both the old and the new serial numbers are valid,
+//! a [MerchantKeyRing] holds both and signs with the preferred one. It can be shared with the
+//! client through an `Arc` and replaced at runtime without rebuilding the client.
+//!
+//! Once the APIv3 key is rotated, resources encrypted with the previous key are still in flight
+//! for a while, an [ApiV3KeyRing] keeps retired keys for a grace period.
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use security::aes::decrypt;
+use security::secret::SecretBytes;
+
+use crate::cipher::{RsaSigner, SignatureResult, Signer, SHA256_RSA2048};
+use crate::metrics;
+use crate::prelude::*;
+use crate::serial::SerialNumber;
+
+/// A merchant signing key, preferred from its activation time.
+pub struct MerchantKey {
+    signer: RsaSigner,
+    active_from: SystemTime,
+}
+
+impl MerchantKey {
+    /// A key active right away.
+    pub fn new(signer: RsaSigner) -> Self {
+        Self {
+            signer,
+            active_from: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    /// Prefer the key from `active_from`, e.g. once the new certificate is effective.
+    pub fn with_active_from(mut self, active_from: SystemTime) -> Self {
+        self.active_from = active_from;
+        self
+    }
+
+    pub fn get_serial_number(&self) -> &SerialNumber {
+        self.signer.get_serial_number()
+    }
+
+    pub fn get_active_from(&self) -> SystemTime {
+        self.active_from
+    }
+}
+
+type Keys = Arc<Vec<Arc<MerchantKey>>>;
+
+/// Signs with the most recently activated of several merchant keys, the last added on ties,
+/// or with the first to be activated if none is active yet.
+pub struct MerchantKeyRing {
+    keys: RwLock<Keys>,
+}
+
+impl MerchantKeyRing {
+    /// Create a key ring, fails without any key.
+    pub fn new(keys: Vec<MerchantKey>) -> Result<Self, String> {
+        Ok(Self {
+            keys: RwLock::new(Self::to_keys(keys)?),
+        })
+    }
+
+    fn to_keys(keys: Vec<MerchantKey>) -> Result<Keys, String> {
+        if keys.is_empty() {
+            return Err("no merchant key".to_string());
+        }
+        Ok(Arc::new(keys.into_iter().map(Arc::new).collect()))
+    }
+
+    fn get_keys(&self) -> Keys {
+        self.keys.read().unwrap().clone()
+    }
+
+    /// Replace every key at once, signatures in progress complete with the previous keys.
+    pub fn replace(&self, keys: Vec<MerchantKey>) -> Result<(), String> {
+        let keys = Self::to_keys(keys)?;
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    /// Add a key, replacing the key with the same serial number.
+    pub fn add(&self, key: MerchantKey) {
+        let mut keys = self.keys.write().unwrap();
+        let mut updated = keys
+            .iter()
+            .filter(|k| k.get_serial_number() != key.get_serial_number())
+            .cloned()
+            .collect::<Vec<_>>();
+        updated.push(Arc::new(key));
+        *keys = Arc::new(updated);
+    }
+
+    /// Remove the key of a retired certificate, the last key can't be removed.
+    pub fn remove(&self, serial_number: &str) -> Result<(), String> {
+        let mut keys = self.keys.write().unwrap();
+        let updated = keys
+            .iter()
+            .filter(|k| k.get_serial_number() != serial_number)
+            .cloned()
+            .collect::<Vec<_>>();
+        if updated.is_empty() {
+            return Err("can't remove the last merchant key".to_string());
+        }
+        *keys = Arc::new(updated);
+        Ok(())
+    }
+
+    /// Serial numbers of all keys, active or not.
+    pub fn get_serial_numbers(&self) -> Vec<String> {
+        self.get_keys()
+            .iter()
+            .map(|k| k.get_serial_number().to_string())
+            .collect()
+    }
+
+    /// The key signing at `now`.
+    pub fn get_preferred(&self, now: SystemTime) -> Arc<MerchantKey> {
+        let keys = self.get_keys();
+        let active = keys
+            .iter()
+            .filter(|k| k.active_from <= now)
+            .max_by_key(|k| k.active_from);
+        match active {
+            Some(key) => key.clone(),
+            // keys are never empty.
+            None => keys.iter().min_by_key(|k| k.active_from).unwrap().clone(),
+        }
+    }
+}
+
+impl Signer for MerchantKeyRing {
+    fn sign(&self, message: &str) -> Result<SignatureResult, String> {
+        self.get_preferred(SystemTime::now()).signer.sign(message)
+    }
+
+    fn get_algorithm(&self) -> &str {
+        SHA256_RSA2048
+    }
+}
+
+/// An APIv3 key, identified by a label of its own, e.g. the date it was set.
+struct ApiV3Key {
+    id: String,
+    key: SecretBytes,
+    retired_at: Option<SystemTime>,
+}
+
+/// Plain text of a resource, with the id of the APIv3 key which decrypted it.
+pub struct Decrypted {
+    pub key_id: String,
+    pub plain_text: String,
+}
+
+impl std::fmt::Debug for Decrypted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Decrypted")
+            .field("key_id", &self.key_id)
+            .field("plain_text", &crate::redact::Secret(&self.plain_text))
+            .finish()
+    }
+}
+
+/// Default time retired APIv3 keys are still tried.
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Decrypts with the current APIv3 key, then with keys retired less than the grace period ago.
+pub struct ApiV3KeyRing {
+    keys: RwLock<Arc<Vec<Arc<ApiV3Key>>>>,
+    grace_period: Duration,
+}
+
+impl ApiV3KeyRing {
+    /// Create a key ring with the current key, which is wiped on drop.
+    pub fn new(id: impl AsRef<str>, key: SecretBytes) -> Self {
+        let key = ApiV3Key {
+            id: id.as_ref().to_string(),
+            key,
+            retired_at: None,
+        };
+        Self {
+            keys: RwLock::new(Arc::new(vec![Arc::new(key)])),
+            grace_period: DEFAULT_GRACE_PERIOD,
+        }
+    }
+
+    /// Try retired keys for `grace_period` after their rotation, a day by default.
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Make `key` the current key, retiring the current one and dropping expired ones.
+    pub fn rotate(&self, id: impl AsRef<str>, key: SecretBytes) {
+        let now = SystemTime::now();
+        let mut keys = self.keys.write().unwrap();
+        let mut rotated = vec![Arc::new(ApiV3Key {
+            id: id.as_ref().to_string(),
+            key,
+            retired_at: None,
+        })];
+        for key in keys.iter() {
+            let key = match key.retired_at {
+                Some(_) => key.clone(),
+                None => Arc::new(ApiV3Key {
+                    id: key.id.clone(),
+                    key: SecretBytes::from_slice(key.key.expose()),
+                    retired_at: Some(now),
+                }),
+            };
+            if self.is_usable(&key, now) {
+                rotated.push(key);
+            }
+        }
+        *keys = Arc::new(rotated);
+    }
+
+    /// Remove a retired key before the end of its grace period, the current key is kept.
+    pub fn remove(&self, id: &str) {
+        let mut keys = self.keys.write().unwrap();
+        let remaining = keys
+            .iter()
+            .filter(|k| k.retired_at.is_none() || k.id != id)
+            .cloned()
+            .collect();
+        *keys = Arc::new(remaining);
+    }
+
+    /// Ids of the keys being tried, the current one first.
+    pub fn get_key_ids(&self) -> Vec<String> {
+        let now = SystemTime::now();
+        self.get_keys()
+            .iter()
+            .filter(|k| self.is_usable(k, now))
+            .map(|k| k.id.clone())
+            .collect()
+    }
+
+    fn get_keys(&self) -> Arc<Vec<Arc<ApiV3Key>>> {
+        self.keys.read().unwrap().clone()
+    }
+
+    fn is_usable(&self, key: &ApiV3Key, now: SystemTime) -> bool {
+        match key.retired_at {
+            None => true,
+            Some(retired_at) => now < retired_at + self.grace_period,
+        }
+    }
+
+    /// Decrypt an aes-256 gcm `cipher_text` with the tag appended, trying keys in order.
+    pub fn decrypt(
+        &self,
+        associated_data: &[u8],
+        nonce: &[u8],
+        cipher_text: &[u8],
+    ) -> Result<Decrypted, String> {
+        let now = SystemTime::now();
+        let mut error = "no usable api v3 key".to_string();
+        for key in self.get_keys().iter().filter(|k| self.is_usable(k, now)) {
+            match decrypt(key.key.expose(), associated_data, nonce, cipher_text) {
+                Ok(plain_text) => {
+                    let retired = key.retired_at.is_some();
+                    if retired {
+                        warn!("Decrypted with retired api v3 key {}", key.id);
+                    }
+                    metrics::recorder().increment_api_v3_key_use(&key.id, retired);
+                    return Ok(Decrypted {
+                        key_id: key.id.clone(),
+                        plain_text,
+                    });
+                }
+                Err(e) => error = e,
+            }
+        }
+        metrics::recorder().increment_decrypt_failure();
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Credential, WxPay2Credential};
+    use std::time::Duration;
+
+    const MERCHANT_SERIAL: &str = "6048A6A668D316A4EBA392BD0CA4FEAABDCB611E";
+    const MERCHANT_KEY: &str = include_str!("../testdata/merchant_key.pem");
+    const RENEWED_SERIAL: &str = "2F85E92795F5E7F86BA33C5C365133FDFD5E7E49";
+    const RENEWED_KEY: &str = include_str!("../testdata/platform_key.pem");
+
+    fn key(serial_number: &str, pem: &str) -> MerchantKey {
+        MerchantKey::new(RsaSigner::new(serial_number, pem).unwrap())
+    }
+
+    #[test]
+    fn test_preferred_key() {
+        let now = SystemTime::now();
+        let ring = MerchantKeyRing::new(vec![
+            key(MERCHANT_SERIAL, MERCHANT_KEY),
+            key(RENEWED_SERIAL, RENEWED_KEY).with_active_from(now + Duration::from_secs(3600)),
+        ])
+        .unwrap();
+        assert_eq!(MERCHANT_SERIAL, ring.get_preferred(now).get_serial_number());
+        let later = now + Duration::from_secs(7200);
+        assert_eq!(
+            RENEWED_SERIAL,
+            ring.get_preferred(later).get_serial_number()
+        );
+
+        // none active yet, the first to activate is preferred.
+        let ring = MerchantKeyRing::new(vec![
+            key(RENEWED_SERIAL, RENEWED_KEY).with_active_from(now + Duration::from_secs(60))
+        ])
+        .unwrap();
+        assert_eq!(RENEWED_SERIAL, ring.get_preferred(now).get_serial_number());
+        assert!(ring.remove(RENEWED_SERIAL).is_err());
+        assert!(MerchantKeyRing::new(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_hot_swap() {
+        let ring =
+            Arc::new(MerchantKeyRing::new(vec![key(MERCHANT_SERIAL, MERCHANT_KEY)]).unwrap());
+        let credential = WxPay2Credential::new("1900000001", ring.clone());
+        let authorization = credential
+            .get_authorization("/v3/certificates", "GET", "")
+            .unwrap();
+        assert!(authorization.contains(MERCHANT_SERIAL));
+
+        ring.add(key(RENEWED_SERIAL, RENEWED_KEY));
+        assert_eq!(2, ring.get_serial_numbers().len());
+        let authorization = credential
+            .get_authorization("/v3/certificates", "GET", "")
+            .unwrap();
+        assert!(authorization.contains(RENEWED_SERIAL));
+
+        ring.remove(RENEWED_SERIAL).unwrap();
+        ring.replace(vec![key(RENEWED_SERIAL, RENEWED_KEY)])
+            .unwrap();
+        assert_eq!(vec![RENEWED_SERIAL.to_string()], ring.get_serial_numbers());
+    }
+
+    #[test]
+    fn test_api_v3_key_rotation() {
+        const NONCE: &[u8] = b"fdasflkja484";
+        let old_key = b"0123456789abcdef0123456789abcdef";
+        let new_key = b"fedcba9876543210fedcba9876543210";
+        let cipher_text =
+            security::aes::encrypt(old_key, b"certificate", NONCE, b"{\"id\":1}").unwrap();
+
+        let ring = ApiV3KeyRing::new("2024-01", SecretBytes::from_slice(old_key));
+        ring.rotate("2024-06", SecretBytes::from_slice(new_key));
+        assert_eq!(vec!["2024-06", "2024-01"], ring.get_key_ids());
+        let decrypted = ring.decrypt(b"certificate", NONCE, &cipher_text).unwrap();
+        assert_eq!("2024-01", decrypted.key_id);
+        assert_eq!("{\"id\":1}", decrypted.plain_text);
+        assert!(ring.decrypt(b"tampered", NONCE, &cipher_text).is_err());
+
+        ring.remove("2024-01");
+        assert!(ring.decrypt(b"certificate", NONCE, &cipher_text).is_err());
+
+        // retired keys are no longer tried after the grace period.
+        let ring = ApiV3KeyRing::new("2024-01", SecretBytes::from_slice(old_key))
+            .with_grace_period(Duration::ZERO);
+        ring.rotate("2024-06", SecretBytes::from_slice(new_key));
+        assert_eq!(vec!["2024-06"], ring.get_key_ids());
+        assert!(ring.decrypt(b"certificate", NONCE, &cipher_text).is_err());
+    }
+}
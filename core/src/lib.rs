@@ -1,16 +1,35 @@
+pub mod audit;
 pub mod auth;
 pub mod certs;
 pub mod cipher;
 pub(crate) mod cons;
+pub mod event;
+pub mod failover;
 pub mod header;
 pub mod http;
+pub mod inbox;
+pub mod keyring;
+pub mod metrics;
+pub mod middleware;
 pub mod notification;
+pub mod ratelimit;
+pub mod redact;
+pub mod remote;
+pub mod retry;
+pub mod router;
+pub mod serial;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod transport;
 pub mod verify;
+#[cfg(any(feature = "axum", feature = "actix-web"))]
+pub mod web;
 
 pub mod prelude {
     pub(crate) use crate::cipher::*;
     pub(crate) use crate::verify::*;
-    pub(crate) use reqwest::{header::HeaderMap, Url};
+    pub(crate) use http::HeaderMap;
     pub use security::prelude::*;
     pub(crate) use security::*;
+    pub(crate) use url::Url;
 }
@@ -0,0 +1,221 @@
+//! Metrics facade of the client and notification handling.
+//!
+//! Nothing is recorded until a [MetricsRecorder] is installed with [set_recorder], so any
+//! backend, e.g. a Prometheus registry, can be plugged in by implementing the trait.
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::marker::PhantomData;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
+
+/// Reason of a failed signature verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VerificationFailure {
+    /// A `Wechatpay-*` header is missing or malformed.
+    MissingHeader,
+    /// The timestamp of the response is too old.
+    Expired,
+    /// No platform certificate with the serial number.
+    CertificateNotFound,
+    /// The signature doesn't match.
+    BadSignature,
+}
+
+impl VerificationFailure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VerificationFailure::MissingHeader => "missing_header",
+            VerificationFailure::Expired => "expired",
+            VerificationFailure::CertificateNotFound => "certificate_not_found",
+            VerificationFailure::BadSignature => "bad_signature",
+        }
+    }
+}
+
+/// Receives measurements, every method does nothing by default.
+pub trait MetricsRecorder: Send + Sync {
+    /// A call to `endpoint` finished after `latency`, retries included,
+    /// `status` is `None` if no response was received.
+    fn record_request(&self, method: &str, endpoint: &str, status: Option<u16>, latency: Duration) {
+        let _ = (method, endpoint, status, latency);
+    }
+
+    /// WeChat Pay replied the error `code`, e.g. `SYSTEM_ERROR`.
+    fn increment_error_code(&self, endpoint: &str, code: &str) {
+        let _ = (endpoint, code);
+    }
+
+    /// A response or notification signature was rejected.
+    fn increment_verification_failure(&self, reason: VerificationFailure) {
+        let _ = reason;
+    }
+
+    /// The resource of a notification could not be decrypted.
+    fn increment_decrypt_failure(&self) {}
+
+    /// A resource was decrypted with the APIv3 key `key_id`, once a retired key is no longer
+    /// used it can be deleted.
+    fn increment_api_v3_key_use(&self, key_id: &str, retired: bool) {
+        let _ = (key_id, retired);
+    }
+
+    /// A platform certificate was loaded, issued `age` ago and expiring in `expires_in`,
+    /// which is zero once expired.
+    fn record_certificate(&self, serial_number: &str, age: Duration, expires_in: Duration) {
+        let _ = (serial_number, age, expires_in);
+    }
+}
+
+struct NoopRecorder;
+
+impl MetricsRecorder for NoopRecorder {}
+
+static RECORDER: OnceLock<Arc<dyn MetricsRecorder>> = OnceLock::new();
+static NOOP_RECORDER: OnceLock<Arc<dyn MetricsRecorder>> = OnceLock::new();
+
+#[cfg(test)]
+thread_local! {
+    static LOCAL_RECORDER: RefCell<Option<Arc<dyn MetricsRecorder>>> = const { RefCell::new(None) };
+}
+
+/// Install the global recorder, fails if one is already installed.
+pub fn set_recorder(recorder: impl MetricsRecorder + 'static) -> Result<(), String> {
+    RECORDER
+        .set(Arc::new(recorder))
+        .map_err(|_| "metrics recorder is already set".to_string())
+}
+
+/// Restores the previous recorder of the thread on drop.
+#[cfg(test)]
+#[must_use = "the local recorder is removed when the guard is dropped"]
+pub(crate) struct LocalRecorderGuard {
+    previous: Option<Arc<dyn MetricsRecorder>>,
+    /// Bound to the thread of the recorder.
+    _thread: PhantomData<*const ()>,
+}
+
+#[cfg(test)]
+impl Drop for LocalRecorderGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        LOCAL_RECORDER.with(|local| *local.borrow_mut() = previous);
+    }
+}
+
+/// Record the measurements of the current thread with `recorder` instead of the global one
+/// until the guard is dropped, so that tests running in parallel don't share a recorder.
+/// Async tests must run on a current thread runtime.
+#[cfg(test)]
+pub(crate) fn set_local_recorder(recorder: Arc<dyn MetricsRecorder>) -> LocalRecorderGuard {
+    let previous = LOCAL_RECORDER.with(|local| local.borrow_mut().replace(recorder));
+    LocalRecorderGuard {
+        previous,
+        _thread: PhantomData,
+    }
+}
+
+/// The installed recorder, or one discarding everything.
+pub fn recorder() -> Arc<dyn MetricsRecorder> {
+    #[cfg(test)]
+    if let Some(recorder) = LOCAL_RECORDER.with(|local| local.borrow().clone()) {
+        return recorder;
+    }
+    match RECORDER.get() {
+        Some(recorder) => recorder.clone(),
+        None => NOOP_RECORDER.get_or_init(|| Arc::new(NoopRecorder)).clone(),
+    }
+}
+
+/// Endpoint of a path for labels, e.g. `/v3/pay/transactions/id/{id}` for
+/// `/v3/pay/transactions/id/4200000001?mchid=1900000001`.
+pub fn get_endpoint(path: &str) -> String {
+    let path = path.split('?').next().unwrap_or_default();
+    path.split('/')
+        .map(|segment| {
+            let is_name = segment
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c == '-' || c == '_');
+            let is_version = segment.starts_with('v')
+                && segment.len() > 1
+                && segment[1..].chars().all(|c| c.is_ascii_digit());
+            if is_name || is_version {
+                segment
+            } else {
+                "{id}"
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Record the validity of a pem encoded platform certificate.
+pub(crate) fn record_certificate(serial_number: &str, pem: &[u8]) {
+    let Ok((_, pem)) = x509_parser::pem::parse_x509_pem(pem) else {
+        return;
+    };
+    let Ok(certificate) = pem.parse_x509() else {
+        return;
+    };
+    let validity = certificate.validity();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let age = (now - validity.not_before.timestamp()).max(0) as u64;
+    let expires_in = (validity.not_after.timestamp() - now).max(0) as u64;
+    recorder().record_certificate(
+        serial_number,
+        Duration::from_secs(age),
+        Duration::from_secs(expires_in),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct CountingRecorder(Mutex<u32>);
+
+    impl MetricsRecorder for CountingRecorder {
+        fn increment_decrypt_failure(&self) {
+            *self.0.lock().unwrap() += 1;
+        }
+    }
+
+    #[test]
+    fn test_local_recorder() {
+        let outer = Arc::new(CountingRecorder::default());
+        let inner = Arc::new(CountingRecorder::default());
+        let guard = set_local_recorder(outer.clone());
+        recorder().increment_decrypt_failure();
+        {
+            let _guard = set_local_recorder(inner.clone());
+            recorder().increment_decrypt_failure();
+            // other threads are not affected.
+            std::thread::spawn(|| recorder().increment_decrypt_failure())
+                .join()
+                .unwrap();
+        }
+        recorder().increment_decrypt_failure();
+        drop(guard);
+        recorder().increment_decrypt_failure();
+        assert_eq!(2, *outer.0.lock().unwrap());
+        assert_eq!(1, *inner.0.lock().unwrap());
+    }
+
+    #[test]
+    fn test_endpoint() {
+        assert_eq!(
+            "/v3/pay/transactions/id/{id}",
+            get_endpoint("/v3/pay/transactions/id/4200000001?mchid=1900000001")
+        );
+        assert_eq!(
+            "/v3/marketing/favor/users/{id}/coupons",
+            get_endpoint("/v3/marketing/favor/users/oUpF8uMuAJO_M2pxb1Q9zNjWeS6o/coupons")
+        );
+        assert_eq!("/v3/certificates", get_endpoint("/v3/certificates"));
+    }
+}
@@ -0,0 +1,536 @@
+//! Middleware chain of the outbound http client.
+//!
+//! A call runs through a stack of [Middleware] before reaching the endpoint sending it.
+//! The stack of [DefaultHttpClient](crate::http::DefaultHttpClient) is, from the outermost:
+//!
+//! * user layers added with `with_layer`
+//! * [RetryPolicy]
+//! * [RateLimiter]
+//! * signing with [WxPay2Credential]
+//! * validation with [WxPay2Validator]
+//! * [Timeout]
+//! * user layers added with `with_inner_layer`, running once per attempt and host
+//! * [HostSelector]
+//! * [Audit](crate::audit::Audit) if configured, recording every attempt on every host
+//!
+//! Every middleware implements both the async and the blocking flavor, the default
+//! implementations pass the request through untouched.
+use std::time::Duration;
+
+use async_trait::async_trait;
+use http::Method;
+use tracing::Instrument;
+
+use crate::{
+    auth::{Credential, Validator, WxPay2Credential, WxPay2Validator},
+    cons::headers,
+    failover::HostSelector,
+    header::HttpHeaders,
+    http::HttpError,
+    prelude::*,
+    ratelimit::RateLimiter,
+    redact::Body,
+    retry::RetryPolicy,
+    transport::{TransportError, TransportErrorKind},
+};
+
+/// An outbound api request.
+#[derive(Clone)]
+pub struct HttpRequest {
+    pub method: Method,
+    /// Path with query relative to the host, e.g. `/v3/pay/transactions/native`.
+    pub path: String,
+    pub headers: HttpHeaders,
+    /// Json body.
+    pub body: Option<String>,
+    /// Merchant id of the credential signing the request.
+    pub merchant_id: String,
+    /// Base url of the host to send to, selected by [HostSelector].
+    pub host: Option<String>,
+    /// Timeout of a single attempt.
+    pub timeout: Option<Duration>,
+}
+
+impl HttpRequest {
+    pub fn new(method: Method, path: impl AsRef<str>, body: Option<String>) -> Self {
+        Self {
+            method,
+            path: path.as_ref().to_string(),
+            headers: HttpHeaders::default(),
+            body,
+            merchant_id: String::new(),
+            host: None,
+            timeout: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for HttpRequest {
+    /// Secret headers are redacted and personal data of the body masked.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpRequest")
+            .field("method", &self.method)
+            .field("path", &self.path)
+            .field("headers", &self.headers)
+            .field("body", &self.body.as_deref().map(|b| Body(b.as_bytes())))
+            .field("merchant_id", &self.merchant_id)
+            .field("host", &self.host)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+/// A raw api response.
+#[derive(Clone)]
+pub struct HttpResponse {
+    /// Base url of the host which served the request.
+    pub host: String,
+    pub status: u16,
+    pub headers: HttpHeaders,
+    /// Raw body as received.
+    pub body: Vec<u8>,
+}
+
+impl std::fmt::Debug for HttpResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpResponse")
+            .field("host", &self.host)
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("body", &Body(&self.body))
+            .finish()
+    }
+}
+
+impl HttpResponse {
+    /// `Request-ID` assigned by WeChat Pay.
+    pub fn get_request_id(&self) -> Option<&str> {
+        self.headers.get(headers::REQUEST_ID).map(|id| id.as_str())
+    }
+}
+
+/// Sends a request built by the middleware stack.
+#[async_trait]
+pub(crate) trait Endpoint: Send + Sync {
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse, HttpError>;
+
+    #[cfg(feature = "blocking")]
+    fn send_blocking(&self, request: HttpRequest) -> Result<HttpResponse, HttpError>;
+}
+
+/// A layer around every api call.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    async fn handle(
+        &self,
+        request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        next.run(request).await
+    }
+
+    #[cfg(feature = "blocking")]
+    fn handle_blocking(
+        &self,
+        request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        next.run_blocking(request)
+    }
+}
+
+/// The rest of the middleware stack, may be run several times.
+#[derive(Clone, Copy)]
+pub struct Next<'a> {
+    layers: &'a [&'a dyn Middleware],
+    endpoint: &'a dyn Endpoint,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(layers: &'a [&'a dyn Middleware], endpoint: &'a dyn Endpoint) -> Self {
+        Self { layers, endpoint }
+    }
+
+    pub async fn run(self, request: HttpRequest) -> Result<HttpResponse, HttpError> {
+        match self.layers.split_first() {
+            Some((layer, layers)) => {
+                let next = Next {
+                    layers,
+                    endpoint: self.endpoint,
+                };
+                layer.handle(request, next).await
+            }
+            None => self.endpoint.send(request).await,
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn run_blocking(self, request: HttpRequest) -> Result<HttpResponse, HttpError> {
+        match self.layers.split_first() {
+            Some((layer, layers)) => {
+                let next = Next {
+                    layers,
+                    endpoint: self.endpoint,
+                };
+                layer.handle_blocking(request, next)
+            }
+            None => self.endpoint.send_blocking(request),
+        }
+    }
+}
+
+/// Timeout of a single attempt, by api path prefix.
+///
+/// Without a timeout for the path, the default timeout of the transport applies.
+#[derive(Debug, Clone, Default)]
+pub struct Timeout {
+    default: Option<Duration>,
+    paths: Vec<(String, Duration)>,
+}
+
+impl Timeout {
+    pub fn new(default: Duration) -> Self {
+        Self {
+            default: Some(default),
+            paths: Vec::new(),
+        }
+    }
+
+    /// Timeout of paths starting with `path_prefix`, the longest prefix wins.
+    pub fn with_path_timeout(mut self, path_prefix: impl AsRef<str>, timeout: Duration) -> Self {
+        self.paths.push((path_prefix.as_ref().to_string(), timeout));
+        self
+    }
+
+    /// Keep path timeouts of `other`.
+    pub(crate) fn with_paths_of(mut self, other: Timeout) -> Self {
+        self.paths = other.paths;
+        self
+    }
+
+    pub fn get_timeout(&self, path: &str) -> Option<Duration> {
+        self.paths
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, timeout)| *timeout)
+            .or(self.default)
+    }
+
+    fn apply(&self, request: &mut HttpRequest) {
+        if request.timeout.is_none() {
+            request.timeout = self.get_timeout(&request.path);
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for Timeout {
+    async fn handle(
+        &self,
+        mut request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        self.apply(&mut request);
+        next.run(request).await
+    }
+
+    #[cfg(feature = "blocking")]
+    fn handle_blocking(
+        &self,
+        mut request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        self.apply(&mut request);
+        next.run_blocking(request)
+    }
+}
+
+#[async_trait]
+impl Middleware for RetryPolicy {
+    async fn handle(
+        &self,
+        request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        let retryable = self.is_idempotent(request.method.as_str(), request.body.as_deref());
+        self.get_budget().deposit();
+        let mut attempt = 1;
+        loop {
+            let result = next.run(request.clone()).await;
+            match result {
+                Err(ref e) => match self.next_backoff(retryable, attempt, e) {
+                    Some(backoff) => {
+                        tokio::time::sleep(backoff).await;
+                        attempt += 1;
+                    }
+                    None => return result,
+                },
+                Ok(_) => return result,
+            }
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    fn handle_blocking(
+        &self,
+        request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        let retryable = self.is_idempotent(request.method.as_str(), request.body.as_deref());
+        self.get_budget().deposit();
+        let mut attempt = 1;
+        loop {
+            let result = next.run_blocking(request.clone());
+            match result {
+                Err(ref e) => match self.next_backoff(retryable, attempt, e) {
+                    Some(backoff) => {
+                        std::thread::sleep(backoff);
+                        attempt += 1;
+                    }
+                    None => return result,
+                },
+                Ok(_) => return result,
+            }
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Returns the time to wait before sending the request.
+    fn permit(&self, request: &HttpRequest) -> Result<Duration, HttpError> {
+        self.acquire(&request.merchant_id, &request.path, self.get_max_wait())
+            .ok_or(HttpError::RateLimited)
+    }
+
+    /// Adapt to the result of the request.
+    fn adapt(&self, request: &HttpRequest, result: &Result<HttpResponse, HttpError>) {
+        match result {
+            Err(e) if e.is_frequency_limited() => {
+                warn!(
+                    "Frequency limited by WeChat Pay, slowing down requests to {}",
+                    request.path
+                );
+                self.on_frequency_limited(&request.merchant_id, &request.path);
+            }
+            Ok(_) => self.on_success(&request.merchant_id, &request.path),
+            Err(_) => {}
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for RateLimiter {
+    async fn handle(
+        &self,
+        request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        let wait = self.permit(&request)?;
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        let result = next.run(request.clone()).await;
+        self.adapt(&request, &result);
+        result
+    }
+
+    #[cfg(feature = "blocking")]
+    fn handle_blocking(
+        &self,
+        request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        let wait = self.permit(&request)?;
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+        let result = next.run_blocking(request.clone());
+        self.adapt(&request, &result);
+        result
+    }
+}
+
+impl WxPay2Credential {
+    fn sign_span() -> tracing::Span {
+        tracing::debug_span!("wechat_pay.sign")
+    }
+
+    /// Set the authorization header, signed with a fresh nonce and timestamp for every attempt.
+    fn sign_request(
+        &self,
+        request: &mut HttpRequest,
+        authorization: Result<String, String>,
+    ) -> Result<(), HttpError> {
+        let authorization = authorization.map_err(HttpError::Sign)?;
+        request.merchant_id = self.get_merchant_id().to_string();
+        request
+            .headers
+            .insert(headers::AUTHORIZATION, authorization);
+        request.headers.insert(headers::ACCEPT, "application/json");
+        if request.body.is_some() {
+            request
+                .headers
+                .insert(headers::CONTENT_TYPE, "application/json");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Middleware for WxPay2Credential {
+    async fn handle(
+        &self,
+        mut request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        let authorization = self
+            .get_authorization_async(
+                &request.path,
+                request.method.as_str(),
+                request.body.as_deref().unwrap_or_default(),
+            )
+            .instrument(Self::sign_span())
+            .await;
+        self.sign_request(&mut request, authorization)?;
+        next.run(request).await
+    }
+
+    #[cfg(feature = "blocking")]
+    fn handle_blocking(
+        &self,
+        mut request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        let authorization = Self::sign_span().in_scope(|| {
+            self.get_authorization(
+                &request.path,
+                request.method.as_str(),
+                request.body.as_deref().unwrap_or_default(),
+            )
+        });
+        self.sign_request(&mut request, authorization)?;
+        next.run_blocking(request)
+    }
+}
+
+impl WxPay2Validator {
+    /// Turn error statuses into [HttpError::Api] and verify signatures of success responses.
+    fn check_response(&self, response: HttpResponse) -> Result<HttpResponse, HttpError> {
+        let request_id = response.get_request_id();
+        let _span = tracing::debug_span!(
+            "wechat_pay.validate",
+            status = response.status,
+            request_id = request_id.unwrap_or_default()
+        )
+        .entered();
+        let body = String::from_utf8_lossy(&response.body);
+        if !(200..300).contains(&response.status) {
+            return Err(
+                HttpError::from_response(response.status, &body).with_request_id(request_id)
+            );
+        }
+        self.validate(&body, &response.headers)
+            .map_err(|reason| HttpError::Validation {
+                reason,
+                request_id: request_id.map(|id| id.to_string()),
+            })?;
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl Middleware for WxPay2Validator {
+    async fn handle(
+        &self,
+        request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        self.check_response(next.run(request).await?)
+    }
+
+    #[cfg(feature = "blocking")]
+    fn handle_blocking(
+        &self,
+        request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        self.check_response(next.run_blocking(request)?)
+    }
+}
+
+impl HostSelector {
+    /// Returns whether the next host should be tried after `error` on `host`.
+    fn should_fail_over(&self, host: &str, error: &HttpError) -> bool {
+        let connect_failure = error.is_connect_failure();
+        if connect_failure || error.is_timeout() {
+            self.record_failure(host);
+        }
+        // the request may have been processed unless the connection was never established.
+        if connect_failure {
+            warn!("Failed to connect to {} for: {}, failing over", host, error);
+        }
+        connect_failure
+    }
+}
+
+fn no_host() -> HttpError {
+    HttpError::Transport(TransportError::new(
+        TransportErrorKind::Other,
+        "no host to send the request to",
+    ))
+}
+
+#[async_trait]
+impl Middleware for HostSelector {
+    async fn handle(
+        &self,
+        request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        let mut last_error = None;
+        for host in self.candidates() {
+            let mut request = request.clone();
+            request.host = Some(host.to_string());
+            match next.run(request).await {
+                Ok(response) => {
+                    self.record_success(host);
+                    return Ok(response);
+                }
+                Err(e) => {
+                    if !self.should_fail_over(host, &e) {
+                        return Err(e);
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(no_host))
+    }
+
+    #[cfg(feature = "blocking")]
+    fn handle_blocking(
+        &self,
+        request: HttpRequest,
+        next: Next<'_>,
+    ) -> Result<HttpResponse, HttpError> {
+        let mut last_error = None;
+        for host in self.candidates() {
+            let mut request = request.clone();
+            request.host = Some(host.to_string());
+            match next.run_blocking(request) {
+                Ok(response) => {
+                    self.record_success(host);
+                    return Ok(response);
+                }
+                Err(e) => {
+                    if !self.should_fail_over(host, &e) {
+                        return Err(e);
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(no_host))
+    }
+}
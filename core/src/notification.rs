@@ -1,13 +1,29 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+use crate::event::{self, Event, EventType};
+use crate::keyring::ApiV3KeyRing;
 use crate::{
+    audit::{AuditKind, AuditRedaction, AuditSink},
+    auth::RESPONSE_EXPIRED_SECONDS,
+    header::{HttpHeaders, WechatPayHeaders},
+    metrics::{self, VerificationFailure},
     prelude::*,
-    verify::{CertificatesVerifier, Verifier},
+    redact::{Body, Secret},
+    serial::WechatPaySerial,
+    verify::Verifier,
 };
-use security::aes::decrypt;
-use serde::{Deserialize, Serialize};
+use security::secret::SecretBytes;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 pub trait Request {
     /// A function to get http header `Wechatpay-Serial`
-    fn get_serial_number(&self) -> &str;
+    fn get_serial_number(&self) -> &WechatPaySerial;
+
+    /// A function to get http header `Wechatpay-Timestamp`, in seconds since the unix epoch.
+    fn get_timestamp(&self) -> u64;
 
     /// A function to get bytes to be verified.
     fn get_message(&self) -> &[u8];
@@ -24,85 +40,170 @@ pub trait Builder {
     fn build(self) -> Self::Target;
 }
 
-pub struct NotificationRequestBuilder<'a> {
-    serial_number: &'a str,
-    timestamp: &'a str,
-    nonce: &'a str,
-    signature: &'a str,
-    body: &'a str,
+/// Builds a [NotificationRequest] from its signature headers and body.
+#[derive(Debug, Default, Clone)]
+pub struct NotificationRequestBuilder {
+    serial_number: String,
+    timestamp: String,
+    nonce: String,
+    signature: String,
+    body: String,
 }
 
-impl<'a> NotificationRequestBuilder<'a> {
-    pub fn with_serial_number(&'a mut self, serial_number: impl AsRef<&'a str>) -> &'a mut Self {
-        self.serial_number = serial_number.as_ref();
+impl NotificationRequestBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_serial_number(mut self, serial_number: impl AsRef<str>) -> Self {
+        self.serial_number = serial_number.as_ref().to_string();
         self
     }
 
-    pub fn with_timestamp(mut self, timestamp: impl AsRef<&'a str>) -> Self {
-        self.timestamp = timestamp.as_ref();
+    /// Seconds since the unix epoch as sent, a value which isn't a number is rejected as stale.
+    pub fn with_timestamp(mut self, timestamp: impl AsRef<str>) -> Self {
+        self.timestamp = timestamp.as_ref().to_string();
         self
     }
 
-    pub fn width_nonce(mut self, nonce: impl AsRef<&'a str>) -> Self {
-        self.nonce = nonce.as_ref();
+    pub fn with_nonce(mut self, nonce: impl AsRef<str>) -> Self {
+        self.nonce = nonce.as_ref().to_string();
         self
     }
 
-    pub fn with_signature(mut self, signature: impl AsRef<&'a str>) -> Self {
-        self.signature = signature.as_ref();
+    pub fn with_signature(mut self, signature: impl AsRef<str>) -> Self {
+        self.signature = signature.as_ref().to_string();
         self
     }
 
-    pub fn with_body(mut self, payload: impl AsRef<&'a str>) -> Self {
-        self.body = payload.as_ref();
+    /// The body exactly as received.
+    pub fn with_body(mut self, payload: impl AsRef<str>) -> Self {
+        self.body = payload.as_ref().to_string();
         self
     }
 }
 
-impl Builder for NotificationRequestBuilder<'_> {
+impl Builder for NotificationRequestBuilder {
     type Target = NotificationRequest;
     fn build(self) -> Self::Target {
         let verify_message = format!("{}\n{}\n{}\n", self.timestamp, self.nonce, self.body);
         let message = verify_message.as_bytes().to_vec();
         NotificationRequest {
-            serial_number: self.serial_number.to_string(),
-            signature: self.signature.to_string(),
+            serial_number: WechatPaySerial::new(self.serial_number),
+            timestamp: self.timestamp.trim().parse().unwrap_or_default(),
+            signature: self.signature,
             message,
-            body: self.body.to_string(),
+            body: self.body,
         }
     }
 }
 
 pub struct NotificationRequest {
-    pub serial_number: String,
+    pub serial_number: WechatPaySerial,
+    pub timestamp: u64,
     pub signature: String,
     pub message: Vec<u8>,
     pub body: String,
 }
 
+impl NotificationRequest {
+    pub fn builder() -> NotificationRequestBuilder {
+        NotificationRequestBuilder::new()
+    }
+
+    /// Create a request from the headers and the raw body of a notification, the signed message
+    /// is made of the exact bytes received.
+    pub fn from_parts(headers: &HttpHeaders, body: &[u8]) -> Result<Self, String> {
+        let headers = WechatPayHeaders::parse(headers).inspect_err(|_| {
+            metrics::recorder().increment_verification_failure(VerificationFailure::MissingHeader)
+        })?;
+        let mut message = Vec::with_capacity(body.len() + 64);
+        message.extend_from_slice(headers.timestamp.to_string().as_bytes());
+        message.push(b'\n');
+        message.extend_from_slice(headers.nonce.as_bytes());
+        message.push(b'\n');
+        message.extend_from_slice(body);
+        message.push(b'\n');
+        let body = String::from_utf8(body.to_vec()).map_err(|_| "body is not valid utf-8")?;
+        Ok(Self {
+            serial_number: headers.serial_number,
+            timestamp: headers.timestamp,
+            signature: headers.signature,
+            message,
+            body,
+        })
+    }
+
+    /// Create a request from a notification received by a http server.
+    pub fn from_http_request(request: &http::Request<Bytes>) -> Result<Self, String> {
+        Self::from_parts(&HttpHeaders::from(request.headers()), request.body())
+    }
+}
+
+impl TryFrom<&http::Request<Bytes>> for NotificationRequest {
+    type Error = String;
+
+    fn try_from(request: &http::Request<Bytes>) -> Result<Self, Self::Error> {
+        Self::from_http_request(request)
+    }
+}
+
+impl TryFrom<http::Request<Bytes>> for NotificationRequest {
+    type Error = String;
+
+    fn try_from(request: http::Request<Bytes>) -> Result<Self, Self::Error> {
+        Self::from_http_request(&request)
+    }
+}
+
+impl Request for NotificationRequest {
+    fn get_serial_number(&self) -> &WechatPaySerial {
+        &self.serial_number
+    }
+
+    fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    fn get_message(&self) -> &[u8] {
+        &self.message
+    }
+
+    fn get_signature(&self) -> &str {
+        &self.signature
+    }
+
+    fn get_body(&self) -> &str {
+        &self.body
+    }
+}
+
 impl std::fmt::Debug for NotificationRequest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let msg = String::from_utf8_lossy(self.message.as_slice());
-        writeln!(
-            f,
-            "NotificationRequest={{serial_number={}, signature={}, message={}, body={}}}",
-            self.serial_number, self.signature, msg, self.body
-        )
+        f.debug_struct("NotificationRequest")
+            .field("serial_number", &self.serial_number)
+            .field("timestamp", &self.timestamp)
+            .field("signature", &Secret(&self.signature))
+            .field("body", &Body(self.body.as_bytes()))
+            .finish()
     }
 }
 
 impl std::fmt::Display for NotificationRequest {
+    /// The signature is redacted, the message is left out as it repeats the body.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let msg = String::from_utf8_lossy(self.message.as_slice());
         writeln!(
             f,
-            "NotificationRequest={{serial_number={}, signature={}, message={}, body={}}}",
-            self.serial_number, self.signature, msg, self.body
+            "NotificationRequest={{serial_number={}, timestamp={}, signature={}, body={}}}",
+            self.serial_number,
+            self.timestamp,
+            Secret(&self.signature),
+            Body(self.body.as_bytes())
         )
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Notification {
     pub id: String,
     pub create_time: String,
@@ -111,9 +212,58 @@ pub struct Notification {
     pub summary: String,
     pub resource: Resource,
     pub decrypt_data: Option<String>,
+    /// Id of the APIv3 key which decrypted the resource.
+    #[serde(skip)]
+    pub api_v3_key_id: Option<String>,
+}
+
+impl std::fmt::Debug for Notification {
+    /// The decrypted resource is redacted.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Notification")
+            .field("id", &self.id)
+            .field("create_time", &self.create_time)
+            .field("event_type", &self.event_type)
+            .field("resource_type", &self.resource_type)
+            .field("summary", &self.summary)
+            .field("resource", &self.resource)
+            .field("decrypt_data", &self.decrypt_data.as_ref().map(Secret))
+            .field("api_v3_key_id", &self.api_v3_key_id)
+            .finish()
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Notification {
+    pub fn get_event_type(&self) -> EventType {
+        self.event_type.parse().unwrap_or_else(|e| match e {})
+    }
+
+    /// Convert to an [Event] with the decrypted resource deserialized as `T`.
+    pub fn into_event<T: DeserializeOwned>(self) -> Result<Event<T>, String> {
+        let create_time = event::parse_time(&self.create_time)
+            .map_err(|_| format!("invalid create_time {}", self.create_time))?;
+        let decrypt_data = self.decrypt_data.as_deref().unwrap_or_default();
+        let resource = serde_json::from_str(decrypt_data).map_err(|e| {
+            error!(
+                "Failed to parse resource of notification {} for: {}",
+                self.id, e
+            );
+            format!("failed to parse resource as {}", std::any::type_name::<T>())
+        })?;
+        Ok(Event {
+            event_type: self.get_event_type(),
+            id: self.id,
+            create_time,
+            resource_type: self.resource_type,
+            summary: self.summary,
+            original_type: self.resource.original_type,
+            resource,
+            api_v3_key_id: self.api_v3_key_id,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Resource {
     pub algorithm: String,
     #[serde(alias = "ciphertext")]
@@ -123,21 +273,91 @@ pub struct Resource {
     pub original_type: String,
 }
 
+/// Id of the APIv3 key of a handler created with a single key.
+pub const DEFAULT_API_V3_KEY_ID: &str = "default";
+
 pub struct NotificationHandler {
-    api_v3_key: Vec<u8>,
-    verifier: CertificatesVerifier,
+    api_v3_keys: Arc<ApiV3KeyRing>,
+    verifier: Box<dyn Verifier>,
+    audit: Option<(Arc<dyn AuditSink>, AuditRedaction)>,
+    timestamp_tolerance: Duration,
 }
 
 impl NotificationHandler {
-    pub fn new(api_v3_key: impl AsRef<[u8]>, verifier: CertificatesVerifier) -> Self {
+    /// Create a handler with a copy of `api_v3_key`, see [NotificationHandler::from_secret].
+    pub fn new(api_v3_key: impl AsRef<[u8]>, verifier: impl Verifier + 'static) -> Self {
+        Self::from_secret(SecretBytes::from_slice(api_v3_key.as_ref()), verifier)
+    }
+
+    /// Create a handler taking ownership of `api_v3_key`, which is wiped on drop.
+    ///
+    /// Signatures are checked with `verifier`, which can be shared with the client in an `Arc`.
+    pub fn from_secret(api_v3_key: SecretBytes, verifier: impl Verifier + 'static) -> Self {
+        let api_v3_keys = ApiV3KeyRing::new(DEFAULT_API_V3_KEY_ID, api_v3_key);
+        Self::from_key_ring(Arc::new(api_v3_keys), verifier)
+    }
+
+    /// Create a handler decrypting with `api_v3_keys`, which can be rotated while in use.
+    pub fn from_key_ring(
+        api_v3_keys: Arc<ApiV3KeyRing>,
+        verifier: impl Verifier + 'static,
+    ) -> Self {
         Self {
-            api_v3_key: api_v3_key.as_ref().to_vec(),
-            verifier,
+            api_v3_keys,
+            verifier: Box::new(verifier),
+            audit: None,
+            timestamp_tolerance: Duration::from_secs(RESPONSE_EXPIRED_SECONDS),
+        }
+    }
+
+    /// Reject notifications whose `Wechatpay-Timestamp` is further than `tolerance` from now,
+    /// in the past or in the future, 5 minutes by default as for responses.
+    pub fn with_timestamp_tolerance(mut self, tolerance: Duration) -> Self {
+        self.timestamp_tolerance = tolerance;
+        self
+    }
+
+    /// Record every notification to `sink`, bodies are redacted according to `redaction`.
+    pub fn with_audit_sink(mut self, sink: Arc<dyn AuditSink>, redaction: AuditRedaction) -> Self {
+        self.audit = Some((sink, redaction));
+        self
+    }
+
+    fn audit(&self, body: &str, result: &Result<Notification, String>) {
+        let Some((sink, redaction)) = &self.audit else {
+            return;
+        };
+        let event_type = match result {
+            Ok(notification) => notification.event_type.clone(),
+            Err(_) => String::new(),
+        };
+        let mut record = redaction.new_record(AuditKind::Notification, event_type, body.as_bytes());
+        match result {
+            Ok(notification) => record.request_id = Some(notification.id.clone()),
+            Err(e) => record.error = Some(e.clone()),
         }
+        sink.record(&record);
     }
 }
 
 impl NotificationHandler {
+    fn check_timestamp(&self, timestamp: u64) -> Result<(), String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let tolerance = self.timestamp_tolerance.as_secs();
+        if now.saturating_sub(timestamp) > tolerance {
+            metrics::recorder().increment_verification_failure(VerificationFailure::Expired);
+            return Err("notification is expired".to_string());
+        }
+        if timestamp.saturating_sub(now) > tolerance {
+            metrics::recorder().increment_verification_failure(VerificationFailure::Expired);
+            return Err("notification timestamp is in the future".to_string());
+        }
+        Ok(())
+    }
+
     fn set_decrypt_data(&self, notification: &mut Notification) -> Result<(), String> {
         let resource = &notification.resource;
         let associated_data = resource
@@ -146,14 +366,15 @@ impl NotificationHandler {
             .map(|s| s.as_bytes())
             .unwrap_or(b"");
         let nonce = resource.nonce.as_bytes();
-        let cipher_text = resource.cipher_text.as_bytes();
-        let decrypt_data = decrypt(
-            self.api_v3_key.as_slice(),
-            associated_data,
-            nonce,
-            cipher_text,
-        )?;
-        notification.decrypt_data = Some(decrypt_data);
+        let cipher_text = base64::decode(&resource.cipher_text).map_err(|_| {
+            metrics::recorder().increment_decrypt_failure();
+            "resource.cipher_text is not base64".to_string()
+        })?;
+        let decrypted = self
+            .api_v3_keys
+            .decrypt(associated_data, nonce, &cipher_text)?;
+        notification.decrypt_data = Some(decrypted.plain_text);
+        notification.api_v3_key_id = Some(decrypted.key_id);
         Ok(())
     }
     fn is_empty_and_return(value: &str, tag: &str) -> Result<(), String> {
@@ -193,8 +414,38 @@ impl NotificationHandler {
             }
         }
     }
-    pub fn parse(&self, request: impl Request) -> Result<Notification, String> {
+
+    /// Verify and decrypt a notification, deserializing its resource as `T`, e.g. a
+    /// transaction, or [serde_json::Value] to dispatch on [Event::event_type] first.
+    pub fn parse<T: DeserializeOwned>(&self, request: impl Request) -> Result<Event<T>, String> {
+        self.parse_raw(request)?.into_event()
+    }
+
+    /// Verify and decrypt a notification, keeping the decrypted resource as a string.
+    pub fn parse_raw(&self, request: impl Request) -> Result<Notification, String> {
+        let span = tracing::info_span!(
+            "wechat_pay.notification",
+            serial_number = %request.get_serial_number(),
+            id = tracing::field::Empty,
+            event_type = tracing::field::Empty,
+        );
+        let _span = span.enter();
+        let body = request.get_body().to_string();
+        let result = self.verify_and_parse(request);
+        self.audit(&body, &result);
+        match &result {
+            Ok(notification) => {
+                span.record("id", notification.id.as_str());
+                span.record("event_type", notification.event_type.as_str());
+            }
+            Err(e) => tracing::warn!(error = %e, "Rejected WeChat Pay notification"),
+        }
+        result
+    }
+
+    fn verify_and_parse(&self, request: impl Request) -> Result<Notification, String> {
         if request.get_serial_number().is_empty() {
+            metrics::recorder().increment_verification_failure(VerificationFailure::MissingHeader);
             return Err("serial_number is empty".to_string());
         }
 
@@ -203,9 +454,12 @@ impl NotificationHandler {
         }
 
         if request.get_signature().is_empty() {
+            metrics::recorder().increment_verification_failure(VerificationFailure::MissingHeader);
             return Err("signature is empty".to_string());
         }
 
+        self.check_timestamp(request.get_timestamp())?;
+
         self.verifier.verify(
             request.get_serial_number(),
             request.get_message(),
@@ -216,3 +470,180 @@ impl NotificationHandler {
         self.parse_body(request.get_body())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::{RsaSigner, Signer};
+    use crate::verify::{CertificatesVerifier, VerifyingKey};
+
+    const PLATFORM_SERIAL: &str = "2F85E92795F5E7F86BA33C5C365133FDFD5E7E49";
+    const PLATFORM_KEY: &str = include_str!("../testdata/platform_key.pem");
+    const PLATFORM_CERT: &str = include_str!("../testdata/platform_cert.pem");
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn new_http_request(api_v3_key: &[u8], plain_text: &str) -> http::Request<Bytes> {
+        new_http_request_at(api_v3_key, plain_text, now())
+    }
+
+    /// A signed notification as sent by WeChat Pay at `timestamp`, with a pretty printed body.
+    fn new_http_request_at(
+        api_v3_key: &[u8],
+        plain_text: &str,
+        timestamp: u64,
+    ) -> http::Request<Bytes> {
+        let cipher_text =
+            security::aes::encrypt(api_v3_key, "transaction", "fdasflkja484", plain_text).unwrap();
+        let body = serde_json::json!({
+            "id": "EV-2018022511223320873",
+            "create_time": "2015-05-20T13:29:35+08:00",
+            "event_type": "TRANSACTION.SUCCESS",
+            "resource_type": "encrypt-resource",
+            "summary": "支付成功",
+            "resource": {
+                "algorithm": "AEAD_AES_256_GCM",
+                "ciphertext": base64::encode(cipher_text),
+                "associated_data": "transaction",
+                "nonce": "fdasflkja484",
+                "original_type": "transaction"
+            }
+        });
+        let body = serde_json::to_string_pretty(&body).unwrap();
+        let message = format!("{}\nnonce\n{}\n", timestamp, body);
+        let signature = RsaSigner::new(PLATFORM_SERIAL, PLATFORM_KEY)
+            .unwrap()
+            .sign(&message)
+            .unwrap();
+        http::Request::post("/notify")
+            .header("wechatpay-serial", PLATFORM_SERIAL)
+            .header("wechatpay-signature", signature.get_sign())
+            .header("wechatpay-timestamp", timestamp.to_string())
+            .header("wechatpay-nonce", "nonce")
+            .body(Bytes::from(body))
+            .unwrap()
+    }
+
+    fn new_request(api_v3_key: &[u8], plain_text: &str) -> NotificationRequest {
+        NotificationRequest::try_from(new_http_request(api_v3_key, plain_text)).unwrap()
+    }
+
+    #[test]
+    fn test_request_from_http() {
+        let http_request =
+            new_http_request_at(b"0123456789abcdef0123456789abcdef", "{}", 1554208460);
+        let request = NotificationRequest::from_http_request(&http_request).unwrap();
+        assert_eq!(WechatPaySerial::new(PLATFORM_SERIAL), request.serial_number);
+        assert_eq!(1554208460, request.timestamp);
+        let body = std::str::from_utf8(http_request.body()).unwrap();
+        assert_eq!(
+            format!("1554208460\nnonce\n{}\n", body).as_bytes(),
+            request.message
+        );
+
+        let built = NotificationRequest::builder()
+            .with_serial_number(PLATFORM_SERIAL)
+            .with_timestamp("1554208460")
+            .with_nonce("nonce")
+            .with_signature(&request.signature)
+            .with_body(body)
+            .build();
+        assert_eq!(request.message, built.message);
+        assert_eq!(request.timestamp, built.timestamp);
+
+        let mut headers = HttpHeaders::from(http_request.headers());
+        headers.remove("Wechatpay-Signature");
+        assert!(NotificationRequest::from_parts(&headers, http_request.body()).is_err());
+    }
+
+    #[test]
+    fn test_reject_stale_timestamp() {
+        let api_v3_key = b"0123456789abcdef0123456789abcdef";
+        let mut verifier = CertificatesVerifier::new();
+        verifier.add_key(VerifyingKey::from_certificate_pem(PLATFORM_CERT).unwrap());
+        let handler = NotificationHandler::new(api_v3_key, verifier);
+
+        let parse = |timestamp| {
+            let request = new_http_request_at(api_v3_key, "{}", timestamp);
+            handler.parse_raw(NotificationRequest::try_from(request).unwrap())
+        };
+        assert!(parse(now() - 60).is_ok());
+        assert_eq!(
+            Err("notification is expired".to_string()),
+            parse(now() - 10 * 60).map(|_| ())
+        );
+        assert_eq!(
+            Err("notification timestamp is in the future".to_string()),
+            parse(now() + 10 * 60).map(|_| ())
+        );
+
+        let handler = handler.with_timestamp_tolerance(Duration::from_secs(15 * 60));
+        let request = new_http_request_at(api_v3_key, "{}", now() - 10 * 60);
+        assert!(handler
+            .parse_raw(NotificationRequest::try_from(request).unwrap())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_parse_with_rotated_key() {
+        let old_key = b"0123456789abcdef0123456789abcdef";
+        let mut verifier = CertificatesVerifier::new();
+        verifier.add_key(VerifyingKey::from_certificate_pem(PLATFORM_CERT).unwrap());
+        let api_v3_keys = Arc::new(ApiV3KeyRing::new(
+            "2024-01",
+            SecretBytes::from_slice(old_key),
+        ));
+        let handler = NotificationHandler::from_key_ring(api_v3_keys.clone(), verifier);
+
+        let notification = handler
+            .parse_raw(new_request(old_key, r#"{"trade_state":"SUCCESS"}"#))
+            .unwrap();
+        assert_eq!(Some("2024-01"), notification.api_v3_key_id.as_deref());
+
+        api_v3_keys.rotate(
+            "2024-06",
+            SecretBytes::from_slice(b"fedcba9876543210fedcba9876543210"),
+        );
+        // a long resource, in flight since before the rotation.
+        let plain_text = format!(r#"{{"attach":"{}"}}"#, "a".repeat(512));
+        let notification = handler
+            .parse_raw(new_request(old_key, &plain_text))
+            .unwrap();
+        assert_eq!(Some("2024-01"), notification.api_v3_key_id.as_deref());
+        assert_eq!(Some(plain_text), notification.decrypt_data);
+    }
+
+    #[test]
+    fn test_parse_typed() {
+        #[derive(Deserialize)]
+        struct Transaction {
+            out_trade_no: String,
+            amount: serde_json::Value,
+        }
+
+        let api_v3_key = b"0123456789abcdef0123456789abcdef";
+        let mut verifier = CertificatesVerifier::new();
+        verifier.add_key(VerifyingKey::from_certificate_pem(PLATFORM_CERT).unwrap());
+        let handler = NotificationHandler::new(api_v3_key, verifier);
+
+        let plain_text =
+            r#"{"out_trade_no":"1217752501201407033233368018","amount":{"total":100}}"#;
+        let event = handler
+            .parse::<Transaction>(new_request(api_v3_key, plain_text))
+            .unwrap();
+        assert_eq!(EventType::TransactionSuccess, event.event_type);
+        assert_eq!(1432099775, event.create_time.unix_timestamp());
+        assert_eq!("transaction", event.original_type);
+        assert_eq!("1217752501201407033233368018", event.resource.out_trade_no);
+        assert_eq!(100, event.resource.amount["total"]);
+        assert!(format!("{:?}", event).contains("resource: [REDACTED]"));
+
+        let result = handler.parse::<Transaction>(new_request(api_v3_key, r#"{"amount":1}"#));
+        assert!(result.unwrap_err().starts_with("failed to parse resource"));
+    }
+}
@@ -0,0 +1,293 @@
+//! Client side rate limiting per merchant and api path prefix.
+//!
+//! Every merchant and path prefix gets a token bucket. When WeChat Pay replies
+//! `FREQUENCY_LIMITED` the rate of the bucket is halved, and recovers slowly on success.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Requests allowed per second with a burst.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    rate: f64,
+    burst: u32,
+}
+
+impl Quota {
+    /// `rate` requests per second, up to `burst` at once.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate: rate.max(f64::MIN_POSITIVE),
+            burst: burst.max(1),
+        }
+    }
+
+    pub fn per_second(rate: u32) -> Self {
+        Self::new(rate as f64, rate)
+    }
+}
+
+/// A token bucket, tokens may be reserved ahead so that waiters are served in order.
+#[derive(Debug)]
+struct TokenBucket {
+    quota: Quota,
+    /// Current rate, lowered on `FREQUENCY_LIMITED`.
+    rate: f64,
+    /// Available tokens, negative when tokens are reserved by waiters.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(quota: Quota) -> Self {
+        Self {
+            quota,
+            rate: quota.rate,
+            tokens: quota.burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.quota.burst as f64);
+        self.last_refill = now;
+    }
+
+    /// Reserve a token, returns the time to wait for it, or `None` if it exceeds `max_wait`.
+    fn reserve(&mut self, max_wait: Duration) -> Option<Duration> {
+        self.refill(Instant::now());
+        let wait = if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+        };
+        if wait > max_wait {
+            return None;
+        }
+        self.tokens -= 1.0;
+        Some(wait)
+    }
+
+    fn slow_down(&mut self, min_rate: f64) {
+        self.refill(Instant::now());
+        self.rate = (self.rate / 2.0).max(min_rate.min(self.quota.rate));
+        self.tokens = self.tokens.min(0.0);
+    }
+
+    fn speed_up(&mut self) {
+        self.refill(Instant::now());
+        self.rate = (self.rate + self.quota.rate / 20.0).min(self.quota.rate);
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    merchant_id: Option<String>,
+    path_prefix: String,
+    quota: Quota,
+}
+
+/// Token bucket rate limiter keyed by merchant id and api path prefix.
+#[derive(Debug)]
+pub struct RateLimiter {
+    default_quota: Option<Quota>,
+    rules: Vec<Rule>,
+    max_wait: Duration,
+    min_rate: f64,
+    buckets: Mutex<HashMap<(String, String), TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// Create a limiter applying `default_quota` to every merchant and path without a rule.
+    pub fn new(default_quota: Quota) -> Self {
+        Self {
+            default_quota: Some(default_quota),
+            ..Self::unlimited()
+        }
+    }
+
+    /// Create a limiter only applying to paths with a rule.
+    pub fn unlimited() -> Self {
+        Self {
+            default_quota: None,
+            rules: Vec::new(),
+            max_wait: Duration::from_secs(5),
+            min_rate: 0.1,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Limit paths starting with `path_prefix` of every merchant.
+    pub fn with_path_quota(self, path_prefix: impl AsRef<str>, quota: Quota) -> Self {
+        self.with_rule(None, path_prefix.as_ref(), quota)
+    }
+
+    /// Limit paths starting with `path_prefix` of a merchant, use `/` for all of its paths.
+    pub fn with_merchant_quota(
+        self,
+        merchant_id: impl AsRef<str>,
+        path_prefix: impl AsRef<str>,
+        quota: Quota,
+    ) -> Self {
+        self.with_rule(Some(merchant_id.as_ref()), path_prefix.as_ref(), quota)
+    }
+
+    fn with_rule(mut self, merchant_id: Option<&str>, path_prefix: &str, quota: Quota) -> Self {
+        self.rules.push(Rule {
+            merchant_id: merchant_id.map(|id| id.to_string()),
+            path_prefix: path_prefix.to_string(),
+            quota,
+        });
+        self
+    }
+
+    /// Max time a request may be queued before it fails.
+    pub fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+
+    /// Lower bound of the rate when slowing down on `FREQUENCY_LIMITED`.
+    pub fn with_min_rate(mut self, min_rate: f64) -> Self {
+        self.min_rate = min_rate.max(f64::MIN_POSITIVE);
+        self
+    }
+
+    pub fn get_max_wait(&self) -> Duration {
+        self.max_wait
+    }
+
+    /// The most specific rule: merchant rules first, then the longest path prefix.
+    fn find_quota(&self, merchant_id: &str, path: &str) -> Option<(String, Quota)> {
+        self.rules
+            .iter()
+            .filter(|rule| {
+                rule.merchant_id
+                    .as_deref()
+                    .is_none_or(|id| id == merchant_id)
+            })
+            .filter(|rule| path.starts_with(rule.path_prefix.as_str()))
+            .max_by_key(|rule| (rule.merchant_id.is_some(), rule.path_prefix.len()))
+            .map(|rule| (rule.path_prefix.clone(), rule.quota))
+            .or_else(|| self.default_quota.map(|quota| (String::new(), quota)))
+    }
+
+    fn with_bucket<T>(
+        &self,
+        merchant_id: &str,
+        path: &str,
+        f: impl FnOnce(&mut TokenBucket) -> T,
+    ) -> Option<T> {
+        let path = path.split('?').next().unwrap_or_default();
+        let (prefix, quota) = self.find_quota(merchant_id, path)?;
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .entry((merchant_id.to_string(), prefix))
+            .or_insert_with(|| TokenBucket::new(quota));
+        Some(f(bucket))
+    }
+
+    /// Reserve a request, returns the time to wait before sending it,
+    /// or `None` if it can't be sent within `max_wait`.
+    pub fn acquire(&self, merchant_id: &str, path: &str, max_wait: Duration) -> Option<Duration> {
+        self.with_bucket(merchant_id, path, |bucket| bucket.reserve(max_wait))
+            .unwrap_or(Some(Duration::ZERO))
+    }
+
+    /// WeChat Pay replied `FREQUENCY_LIMITED`, halve the rate.
+    pub fn on_frequency_limited(&self, merchant_id: &str, path: &str) {
+        self.with_bucket(merchant_id, path, |bucket| bucket.slow_down(self.min_rate));
+    }
+
+    /// A request was not limited, recover the rate gradually.
+    pub fn on_success(&self, merchant_id: &str, path: &str) {
+        self.with_bucket(merchant_id, path, |bucket| bucket.speed_up());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_WAIT: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn test_burst_and_deadline() {
+        let limiter = RateLimiter::new(Quota::new(10.0, 2));
+        assert_eq!(
+            Some(Duration::ZERO),
+            limiter.acquire("1900000001", "/v3/bill", MAX_WAIT)
+        );
+        assert_eq!(
+            Some(Duration::ZERO),
+            limiter.acquire("1900000001", "/v3/bill", MAX_WAIT)
+        );
+        let wait = limiter.acquire("1900000001", "/v3/bill", MAX_WAIT).unwrap();
+        assert!(wait > Duration::from_millis(50) && wait <= Duration::from_millis(100));
+        // queued after the previous waiter.
+        let wait = limiter.acquire("1900000001", "/v3/bill", MAX_WAIT).unwrap();
+        assert!(wait > Duration::from_millis(150));
+        assert_eq!(
+            None,
+            limiter.acquire("1900000001", "/v3/bill", Duration::from_millis(10))
+        );
+        // another merchant has its own bucket.
+        assert_eq!(
+            Some(Duration::ZERO),
+            limiter.acquire("1900000002", "/v3/bill", MAX_WAIT)
+        );
+    }
+
+    #[test]
+    fn test_rules() {
+        let limiter = RateLimiter::unlimited()
+            .with_path_quota("/v3/bill", Quota::new(1.0, 1))
+            .with_merchant_quota("1900000001", "/v3/bill/tradebill", Quota::new(1.0, 2));
+        for _ in 0..10 {
+            assert_eq!(
+                Some(Duration::ZERO),
+                limiter.acquire("1900000001", "/v3/pay", MAX_WAIT)
+            );
+        }
+        assert_eq!(
+            Some(Duration::ZERO),
+            limiter.acquire("1900000002", "/v3/bill/tradebill", MAX_WAIT)
+        );
+        assert_eq!(
+            None,
+            limiter.acquire("1900000002", "/v3/bill/fundflowbill", Duration::ZERO)
+        );
+        let path = "/v3/bill/tradebill?bill_date=2019-06-11";
+        assert_eq!(
+            Some(Duration::ZERO),
+            limiter.acquire("1900000001", path, Duration::ZERO)
+        );
+        assert_eq!(
+            Some(Duration::ZERO),
+            limiter.acquire("1900000001", path, Duration::ZERO)
+        );
+        assert_eq!(None, limiter.acquire("1900000001", path, Duration::ZERO));
+    }
+
+    #[test]
+    fn test_frequency_limited() {
+        let limiter = RateLimiter::new(Quota::new(10.0, 1)).with_min_rate(2.0);
+        limiter.on_frequency_limited("1900000001", "/v3/pay");
+        limiter.on_frequency_limited("1900000001", "/v3/pay");
+        limiter.on_frequency_limited("1900000001", "/v3/pay");
+        let wait = limiter.acquire("1900000001", "/v3/pay", MAX_WAIT).unwrap();
+        // 2 requests per second after slowing down
+        assert!(wait > Duration::from_millis(400));
+
+        for _ in 0..100 {
+            limiter.on_success("1900000001", "/v3/pay");
+        }
+        let rate = limiter
+            .with_bucket("1900000001", "/v3/pay", |bucket| bucket.rate)
+            .unwrap();
+        assert_eq!(10.0, rate);
+    }
+}
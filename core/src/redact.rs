@@ -0,0 +1,112 @@
+//! Redaction of secrets and personal data, see [security::redact].
+use serde_json::Value;
+
+pub use security::redact::{is_secret_header, mask, Personal, Secret, REDACTED, UNSAFE_DEBUG};
+
+/// Fields of WeChat Pay payloads holding personal data.
+pub const PERSONAL_FIELDS: &[&str] = &[
+    "openid",
+    "sub_openid",
+    "name",
+    "user_name",
+    "mobile",
+    "phone",
+    "id_card_number",
+    "bank_account",
+    "account_number",
+    "email",
+    "address",
+];
+
+/// Mask values of `fields` at any depth of a json value, regardless of `unsafe-debug`.
+pub fn mask_json<S: AsRef<str>>(value: &mut Value, fields: &[S]) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if fields.iter().any(|f| f.as_ref().eq_ignore_ascii_case(key)) {
+                    *value = match value {
+                        Value::String(s) => Value::String(mask(s)),
+                        _ => Value::String(mask("")),
+                    };
+                } else {
+                    mask_json(value, fields);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| mask_json(v, fields)),
+        _ => {}
+    }
+}
+
+/// A body printed with personal data masked, or only its length if it is not json.
+pub struct Body<'a>(pub &'a [u8]);
+
+impl std::fmt::Display for Body<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if UNSAFE_DEBUG {
+            return f.write_str(&String::from_utf8_lossy(self.0));
+        }
+        match serde_json::from_slice::<Value>(self.0) {
+            Ok(mut value) => {
+                mask_json(&mut value, PERSONAL_FIELDS);
+                write!(f, "{}", value)
+            }
+            Err(_) => write!(f, "<{} bytes>", self.0.len()),
+        }
+    }
+}
+
+impl std::fmt::Debug for Body<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(all(test, not(feature = "unsafe-debug")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_body() {
+        let body = br#"{"payer":{"openid":"o4GgauInH_RCEdvrrNGrntXDuXXX"},"amount":{"total":1}}"#;
+        assert_eq!(
+            r#"{"amount":{"total":1},"payer":{"openid":"o4G***XXX"}}"#,
+            Body(body).to_string()
+        );
+        assert_eq!("<5 bytes>", Body(b"plain").to_string());
+    }
+
+    #[test]
+    fn test_debug_and_display() {
+        use crate::cipher::SignatureResult;
+        use crate::header::HttpHeaders;
+        use crate::middleware::HttpRequest;
+
+        let mut request = HttpRequest::new(
+            http::Method::POST,
+            "/v3/pay/transactions/jsapi",
+            Some(r#"{"payer":{"openid":"o4GgauInH_RCEdvrrNGrntXDuXXX"}}"#.to_string()),
+        );
+        request
+            .headers
+            .insert("Authorization", "WECHATPAY2-SHA256-RSA2048 token");
+        let debug = format!("{:?}", request);
+        assert!(!debug.contains("token"), "{}", debug);
+        assert!(!debug.contains("o4GgauInH_RCEdvrrNGrntXDuXXX"), "{}", debug);
+
+        let mut headers = HttpHeaders::default();
+        headers.insert("Wechatpay-Signature", "c2lnbmF0dXJl");
+        headers.insert(
+            "Wechatpay-Serial",
+            "5157F09EFDC096DE15EBE81A47057A7232F1B8E1",
+        );
+        let display = headers.to_string();
+        assert!(display.contains("Wechatpay-Signature=[REDACTED]"));
+        assert!(display.contains("5157F09EFDC096DE15EBE81A47057A7232F1B8E1"));
+
+        let signature =
+            SignatureResult::new("c2lnbmF0dXJl".to_string(), "5157F09E".parse().unwrap());
+        assert!(!signature.to_string().contains("c2lnbmF0dXJl"));
+        assert!(!format!("{:?}", signature).contains("c2lnbmF0dXJl"));
+    }
+}
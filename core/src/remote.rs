@@ -0,0 +1,314 @@
+//! Signing with a remote signing service, so that merchant private keys stay off application hosts.
+//!
+//! The service signs `SHA256withRSA` with the key of the merchant certificate `key_id`:
+//!
+//! ```text
+//! request:  {"key_id":"<serial number>","algorithm":"SHA256withRSA","message":"<base64>"}
+//! response: {"signature":"<base64>"} or {"error":"<reason>"}
+//! ```
+//!
+//! The request is either posted to an http url, or written as a single line to a unix socket
+//! answering with a single line.
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use http::Method;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cipher::{AsyncSigner, SignatureResult, SHA256_RSA2048},
+    cons::headers,
+    header::HttpHeaders,
+    middleware::HttpRequest,
+    prelude::*,
+    serial::SerialNumber,
+    transport::HttpTransport,
+};
+
+const ALGORITHM: &str = "SHA256withRSA";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SignRequest {
+    key_id: String,
+    algorithm: String,
+    /// Base64 encoded message.
+    message: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SignResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+enum Channel {
+    Http {
+        transport: Arc<dyn HttpTransport>,
+        host: String,
+        path: String,
+        headers: HttpHeaders,
+    },
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Signs asynchronously through a remote signing service, see [remote](crate::remote).
+pub struct RemoteSigner {
+    serial_number: SerialNumber,
+    channel: Channel,
+    timeout: Duration,
+}
+
+impl RemoteSigner {
+    /// Sign with the key of merchant certificate `serial_number` by posting to `url`
+    /// through `transport`, e.g. a [ReqwestTransport](crate::transport::ReqwestTransport).
+    pub fn http(
+        serial_number: impl AsRef<str>,
+        url: impl AsRef<str>,
+        transport: Arc<dyn HttpTransport>,
+    ) -> Result<Self, String> {
+        let url = url
+            .as_ref()
+            .parse::<Url>()
+            .map_err(|e| format!("invalid signing service url for: {}", e))?;
+        let path = url[url::Position::BeforePath..].to_string();
+        let host = url[..url::Position::BeforePath].to_string();
+        Self::new(
+            serial_number,
+            Channel::Http {
+                transport,
+                host,
+                path,
+                headers: HttpHeaders::default(),
+            },
+        )
+    }
+
+    /// Sign with the key of merchant certificate `serial_number` through the unix socket `path`.
+    #[cfg(unix)]
+    pub fn unix(serial_number: impl AsRef<str>, path: impl Into<PathBuf>) -> Result<Self, String> {
+        Self::new(serial_number, Channel::Unix(path.into()))
+    }
+
+    fn new(serial_number: impl AsRef<str>, channel: Channel) -> Result<Self, String> {
+        Ok(Self {
+            serial_number: SerialNumber::from_hex(serial_number)?,
+            channel,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Add a header to http requests, e.g. the token authenticating to the service.
+    pub fn with_header(mut self, name: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+        if let Channel::Http { headers, .. } = &mut self.channel {
+            headers.insert(name.as_ref(), value.as_ref());
+        }
+        self
+    }
+
+    /// Fail signing after `timeout`, 5 seconds by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn call(&self, body: Vec<u8>) -> Result<Vec<u8>, String> {
+        match &self.channel {
+            Channel::Http {
+                transport,
+                host,
+                path,
+                headers,
+            } => {
+                let body = String::from_utf8(body).map_err(|e| e.to_string())?;
+                let mut request = HttpRequest::new(Method::POST, path, Some(body));
+                request.host = Some(host.clone());
+                request.headers = headers.clone();
+                request
+                    .headers
+                    .insert(headers::CONTENT_TYPE, "application/json");
+                request.timeout = Some(self.timeout);
+                let response = transport
+                    .send(request)
+                    .await
+                    .map_err(|e| format!("signing service unreachable for: {}", e))?;
+                if !(200..300).contains(&response.status) {
+                    let error = serde_json::from_slice::<SignResponse>(&response.body)
+                        .ok()
+                        .and_then(|r| r.error)
+                        .unwrap_or_default();
+                    return Err(format!(
+                        "signing service responded {}: {}",
+                        response.status, error
+                    ));
+                }
+                Ok(response.body)
+            }
+            #[cfg(unix)]
+            Channel::Unix(path) => {
+                use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+                let exchange = async {
+                    let mut stream = tokio::net::UnixStream::connect(path).await?;
+                    let mut line = body;
+                    line.push(b'\n');
+                    stream.write_all(&line).await?;
+                    let mut response = Vec::new();
+                    BufReader::new(stream)
+                        .read_until(b'\n', &mut response)
+                        .await?;
+                    Ok::<_, std::io::Error>(response)
+                };
+                tokio::time::timeout(self.timeout, exchange)
+                    .await
+                    .map_err(|_| "signing service timed out".to_string())?
+                    .map_err(|e| format!("signing service unreachable for: {}", e))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncSigner for RemoteSigner {
+    async fn sign(&self, message: &str) -> Result<SignatureResult, String> {
+        let request = SignRequest {
+            key_id: self.serial_number.to_string(),
+            algorithm: ALGORITHM.to_string(),
+            message: base64::encode(message),
+        };
+        let body = serde_json::to_vec(&request).map_err(|e| e.to_string())?;
+        let response = self.call(body).await?;
+        let response = serde_json::from_slice::<SignResponse>(&response)
+            .map_err(|e| format!("invalid signing service response for: {}", e))?;
+        match response {
+            SignResponse {
+                signature: Some(signature),
+                ..
+            } => Ok(SignatureResult::new(signature, self.serial_number.clone())),
+            SignResponse { error, .. } => Err(format!(
+                "signing service failed for: {}",
+                error.unwrap_or_default()
+            )),
+        }
+    }
+
+    fn get_algorithm(&self) -> &str {
+        SHA256_RSA2048
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Credential, WxPay2Credential};
+    use crate::cipher::{RsaSigner, Signer};
+    use crate::middleware::HttpResponse;
+    use crate::transport::InMemoryTransport;
+    use crate::verify::VerifyingKey;
+
+    const MERCHANT_SERIAL: &str = "6048A6A668D316A4EBA392BD0CA4FEAABDCB611E";
+    const MERCHANT_KEY: &str = include_str!("../testdata/merchant_key.pem");
+    const MERCHANT_CERT: &str = include_str!("../testdata/merchant_cert.pem");
+
+    /// Answer a sign request as the signing service would.
+    fn serve(request: &[u8]) -> SignResponse {
+        let request = serde_json::from_slice::<SignRequest>(request).unwrap();
+        if request.key_id != MERCHANT_SERIAL {
+            return SignResponse {
+                error: Some("key not found".to_string()),
+                ..Default::default()
+            };
+        }
+        let message = String::from_utf8(base64::decode(request.message).unwrap()).unwrap();
+        let signature = RsaSigner::new(MERCHANT_SERIAL, MERCHANT_KEY)
+            .unwrap()
+            .sign(&message)
+            .unwrap();
+        SignResponse {
+            signature: Some(signature.get_sign().to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn verify(message: &str, signature: &SignatureResult) {
+        let key = VerifyingKey::from_certificate_pem(MERCHANT_CERT).unwrap();
+        assert_eq!(MERCHANT_SERIAL, signature.get_serial_number());
+        key.verify(message.as_bytes(), signature.get_sign())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http() {
+        let transport = Arc::new(InMemoryTransport::new(|request| {
+            let response = serve(request.body.as_deref().unwrap().as_bytes());
+            Ok(HttpResponse {
+                host: request.host.clone().unwrap_or_default(),
+                status: if response.error.is_some() { 404 } else { 200 },
+                headers: Default::default(),
+                body: serde_json::to_vec(&response).unwrap(),
+            })
+        }));
+        let signer = RemoteSigner::http(
+            MERCHANT_SERIAL,
+            "http://127.0.0.1:8200/v1/sign?version=1",
+            transport.clone(),
+        )
+        .unwrap()
+        .with_header("Authorization", "Bearer token");
+        let signature = signer.sign("GET\n/v3/certificates\n").await.unwrap();
+        verify("GET\n/v3/certificates\n", &signature);
+
+        let requests = transport.get_requests();
+        assert_eq!(Some("http://127.0.0.1:8200"), requests[0].host.as_deref());
+        assert_eq!("/v1/sign?version=1", requests[0].path);
+        assert_eq!(
+            Some("Bearer token"),
+            requests[0].headers.get("Authorization").map(|v| v.as_str())
+        );
+
+        let signer = RemoteSigner::http("1", "http://127.0.0.1:8200/v1/sign", transport).unwrap();
+        let error = signer.sign("message").await.unwrap_err();
+        assert!(error.contains("key not found"), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let path = std::env::temp_dir().join(format!("signer-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut request = Vec::new();
+            stream.read_until(b'\n', &mut request).await.unwrap();
+            let mut response = serde_json::to_vec(&serve(&request)).unwrap();
+            response.push(b'\n');
+            stream.get_mut().write_all(&response).await.unwrap();
+        });
+
+        let credential = WxPay2Credential::from_async_signer(
+            "1900000001",
+            RemoteSigner::unix(MERCHANT_SERIAL, &path).unwrap(),
+        );
+        let authorization = credential
+            .get_authorization_async("/v3/certificates", "GET", "")
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(authorization.starts_with("WECHATPAY2-SHA256-RSA2048 mchid=\"1900000001\""));
+        assert!(authorization.contains(MERCHANT_SERIAL));
+        // an async signer can't be awaited by blocking calls.
+        assert!(credential
+            .get_authorization("/v3/certificates", "GET", "")
+            .is_err());
+    }
+}
@@ -0,0 +1,265 @@
+//! Retry policy for WeChat Pay api calls.
+//!
+//! Only requests which are safe to be repeated are retried: `GET` requests and `POST`
+//! requests carrying an idempotency key such as `out_trade_no` or `out_refund_no`.
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::{http::HttpError, prelude::*};
+
+/// Body fields which make a `POST` request idempotent on WeChat Pay side.
+pub const DEFAULT_IDEMPOTENCY_KEYS: [&str; 8] = [
+    "out_trade_no",
+    "out_refund_no",
+    "out_batch_no",
+    "out_order_no",
+    "out_request_no",
+    "out_bill_no",
+    "out_detail_no",
+    "out_return_no",
+];
+
+/// Scale of a token in [RetryBudget], allows depositing fractions of a retry.
+const TOKEN_SCALE: i64 = 1000;
+
+/// A retry budget shared by all requests of a client.
+///
+/// Every original request deposits `ratio` of a retry, every retry withdraws a whole one,
+/// so that retries can never exceed `ratio` of the traffic plus `min_retries` in reserve.
+#[derive(Debug)]
+pub struct RetryBudget {
+    deposit: i64,
+    max_balance: i64,
+    balance: AtomicI64,
+}
+
+impl RetryBudget {
+    /// Create a budget allowing `ratio` retries per request with `min_retries` in reserve.
+    pub fn new(ratio: f64, min_retries: u32) -> Self {
+        let deposit = (ratio.max(0.0) * TOKEN_SCALE as f64) as i64;
+        let reserve = min_retries as i64 * TOKEN_SCALE;
+        Self {
+            deposit,
+            max_balance: reserve + deposit * 100,
+            balance: AtomicI64::new(reserve),
+        }
+    }
+
+    /// Record an original request.
+    pub fn deposit(&self) {
+        let _ = self
+            .balance
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |balance| {
+                Some((balance + self.deposit).min(self.max_balance))
+            });
+    }
+
+    /// Try to withdraw a retry, returns `false` if the budget is exhausted.
+    pub fn withdraw(&self) -> bool {
+        self.balance
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |balance| {
+                if balance >= TOKEN_SCALE {
+                    Some(balance - TOKEN_SCALE)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        RetryBudget::new(0.2, 10)
+    }
+}
+
+/// Retry policy with exponential backoff and jitter.
+#[derive(Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+    jitter: f64,
+    idempotency_keys: Vec<String>,
+    budget: RetryBudget,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            multiplier: 2.0,
+            jitter: 0.2,
+            idempotency_keys: DEFAULT_IDEMPOTENCY_KEYS
+                .iter()
+                .map(|key| key.to_string())
+                .collect(),
+            budget: RetryBudget::default(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy never retrying.
+    pub fn disabled() -> Self {
+        Self::default().with_max_attempts(1)
+    }
+
+    /// Max attempts including the first one, `1` disables retrying.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Backoff before the first retry, and the upper bound of all backoffs.
+    pub fn with_backoff(mut self, initial_backoff: Duration, max_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self.max_backoff = max_backoff.max(initial_backoff);
+        self
+    }
+
+    /// Factor applied to the backoff after every retry.
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Randomize backoffs by `±jitter` (`0.0..=1.0`) of their value.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Replace body fields which make a `POST` request idempotent.
+    pub fn with_idempotency_keys(mut self, keys: &[&str]) -> Self {
+        self.idempotency_keys = keys.iter().map(|key| key.to_string()).collect();
+        self
+    }
+
+    /// Replace the retry budget.
+    pub fn with_budget(mut self, budget: RetryBudget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub fn get_max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub fn get_budget(&self) -> &RetryBudget {
+        &self.budget
+    }
+
+    /// Backoff before retry number `retry` (starting from `1`), jitter included.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(32) as i32;
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+        let backoff = backoff.min(self.max_backoff.as_secs_f64());
+        let backoff = if self.jitter > 0.0 {
+            let factor = rand::thread_rng().gen_range(-self.jitter..=self.jitter);
+            backoff * (1.0 + factor)
+        } else {
+            backoff
+        };
+        Duration::from_secs_f64(backoff.max(0.0))
+    }
+
+    /// Returns the backoff before the next attempt, or `None` if the call should not be retried.
+    pub(crate) fn next_backoff(
+        &self,
+        retryable: bool,
+        attempt: u32,
+        error: &HttpError,
+    ) -> Option<Duration> {
+        if !retryable || !error.is_retryable() || attempt >= self.max_attempts {
+            return None;
+        }
+        if !self.budget.withdraw() {
+            warn!(
+                "Retry budget exhausted, giving up after attempt {}",
+                attempt
+            );
+            return None;
+        }
+        let backoff = self.backoff(attempt);
+        warn!(
+            "Attempt {} failed for: {}, retrying in {:?}",
+            attempt, error, backoff
+        );
+        Some(backoff)
+    }
+
+    /// Whether a request is safe to be repeated.
+    pub fn is_idempotent(&self, method: &str, body: Option<&str>) -> bool {
+        if method.eq_ignore_ascii_case("GET") {
+            return true;
+        }
+        if !method.eq_ignore_ascii_case("POST") {
+            return false;
+        }
+        let body = match body.map(serde_json::from_str::<serde_json::Value>) {
+            Some(Ok(serde_json::Value::Object(body))) => body,
+            _ => return false,
+        };
+        self.idempotency_keys.iter().any(|key| match body.get(key) {
+            Some(serde_json::Value::String(value)) => !value.is_empty(),
+            _ => false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default()
+            .with_backoff(Duration::from_millis(100), Duration::from_millis(350))
+            .with_jitter(0.0);
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(350));
+
+        let policy = policy.with_jitter(0.5);
+        for _ in 0..100 {
+            let backoff = policy.backoff(1);
+            assert!(backoff >= Duration::from_millis(50) && backoff <= Duration::from_millis(150));
+        }
+    }
+
+    #[test]
+    fn test_idempotent() {
+        let policy = RetryPolicy::default();
+        assert!(policy.is_idempotent("GET", None));
+        assert!(policy.is_idempotent(
+            "POST",
+            Some(r#"{"out_trade_no":"1217752501201407033233368018"}"#)
+        ));
+        assert!(policy.is_idempotent(
+            "POST",
+            Some(r#"{"out_refund_no":"1217752501201407033233368018"}"#)
+        ));
+        assert!(!policy.is_idempotent("POST", Some(r#"{"out_trade_no":""}"#)));
+        assert!(!policy.is_idempotent("POST", Some(r#"{"transaction_id":"42"}"#)));
+        assert!(!policy.is_idempotent("POST", None));
+        assert!(!policy.is_idempotent("DELETE", None));
+    }
+
+    #[test]
+    fn test_budget() {
+        let budget = RetryBudget::new(0.5, 1);
+        assert!(budget.withdraw());
+        assert!(!budget.withdraw());
+        budget.deposit();
+        assert!(!budget.withdraw());
+        budget.deposit();
+        assert!(budget.withdraw());
+    }
+}
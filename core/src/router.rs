@@ -0,0 +1,527 @@
+//! Dispatch notifications to async handlers by event type.
+//!
+//! WeChat Pay retries a notification until it receives a `2xx` reply, a failed reply carries
+//! `{"code":"FAIL","message":...}`.
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use http::StatusCode;
+use serde::de::DeserializeOwned;
+use tracing::Instrument;
+
+use crate::event::{Event, EventType};
+use crate::inbox::{Delivery, InboxStatus, NotificationInbox};
+use crate::notification::{Notification, NotificationHandler, NotificationRequest, Request};
+use crate::prelude::*;
+
+type HandlerFuture = Pin<Box<dyn Future<Output = Result<(), String>> + Send>>;
+type BoxHandler = Box<dyn Fn(Notification) -> HandlerFuture + Send + Sync>;
+
+/// What to reply when a handler fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// Reply `FAIL` so that WeChat Pay retries the notification later.
+    #[default]
+    Retry,
+    /// Log the error and acknowledge, e.g. for events which can't succeed on retry.
+    Acknowledge,
+}
+
+struct Route {
+    handler: BoxHandler,
+    policy: ErrorPolicy,
+}
+
+/// Reply to a notification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotificationReply {
+    status: StatusCode,
+    message: Option<String>,
+}
+
+impl NotificationReply {
+    /// `204 No Content`, WeChat Pay won't send the notification again.
+    pub fn success() -> Self {
+        Self {
+            status: StatusCode::NO_CONTENT,
+            message: None,
+        }
+    }
+
+    /// An error `status` with `{"code":"FAIL","message":...}`, WeChat Pay retries later.
+    pub fn fail(status: StatusCode, message: impl AsRef<str>) -> Self {
+        Self {
+            status,
+            message: Some(message.as_ref().to_string()),
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status.is_success()
+    }
+
+    pub fn get_status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Json body of a failed reply.
+    pub fn get_body(&self) -> Option<String> {
+        self.message
+            .as_ref()
+            .map(|message| serde_json::json!({"code": "FAIL", "message": message}).to_string())
+    }
+
+    pub fn into_http_response(self) -> http::Response<Bytes> {
+        let builder = http::Response::builder().status(self.status);
+        match self.get_body() {
+            Some(body) => builder
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Bytes::from(body)),
+            None => builder.body(Bytes::new()),
+        }
+        .expect("a valid status and header")
+    }
+}
+
+impl std::fmt::Display for NotificationReply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.message {
+            Some(message) => write!(f, "{} {}", self.status, message),
+            None => write!(f, "{}", self.status),
+        }
+    }
+}
+
+impl From<NotificationReply> for http::Response<Bytes> {
+    fn from(reply: NotificationReply) -> Self {
+        reply.into_http_response()
+    }
+}
+
+/// Verifies notifications with a [NotificationHandler] and dispatches them by [EventType].
+///
+/// Events without a route go to the fallback handler, or are acknowledged with a warning if
+/// there is none.
+pub struct NotificationRouter {
+    handler: Arc<NotificationHandler>,
+    routes: HashMap<EventType, Route>,
+    fallback: Option<Route>,
+    inbox: Option<NotificationInbox>,
+}
+
+impl NotificationRouter {
+    pub fn new(handler: impl Into<Arc<NotificationHandler>>) -> Self {
+        Self {
+            handler: handler.into(),
+            routes: HashMap::new(),
+            fallback: None,
+            inbox: None,
+        }
+    }
+
+    /// Persist notifications in `inbox` before dispatching them, duplicates of processed
+    /// events are acknowledged without dispatching, failed events are dead-lettered after too
+    /// many attempts, or right away for routes acknowledging errors.
+    pub fn with_inbox(mut self, inbox: NotificationInbox) -> Self {
+        self.inbox = Some(inbox);
+        self
+    }
+
+    /// Handle `event_type` with `handler`, its resource deserialized as `T`, retried on errors.
+    pub fn route<T, F, Fut>(self, event_type: EventType, handler: F) -> Self
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(Event<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.route_with_policy(event_type, ErrorPolicy::Retry, handler)
+    }
+
+    /// Handle `event_type` with `handler`, replying to errors as `policy` says.
+    pub fn route_with_policy<T, F, Fut>(
+        mut self,
+        event_type: EventType,
+        policy: ErrorPolicy,
+        handler: F,
+    ) -> Self
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(Event<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let route = Route {
+            handler: Self::box_handler(handler),
+            policy,
+        };
+        self.routes.insert(event_type, route);
+        self
+    }
+
+    /// Handle events without a route, with the resource as json, retried on errors.
+    pub fn with_fallback<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(Event<serde_json::Value>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.fallback = Some(Route {
+            handler: Self::box_handler(handler),
+            policy: ErrorPolicy::Retry,
+        });
+        self
+    }
+
+    fn box_handler<T, F, Fut>(handler: F) -> BoxHandler
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(Event<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        Box::new(move |notification: Notification| -> HandlerFuture {
+            match notification.into_event::<T>() {
+                Ok(event) => Box::pin(handler(event)),
+                Err(e) => Box::pin(std::future::ready(Err(e))),
+            }
+        })
+    }
+
+    /// Verify `request` and dispatch it, rejected notifications are replied `400`, failed
+    /// handlers `500` unless their policy acknowledges errors.
+    pub async fn handle(&self, request: impl Request) -> NotificationReply {
+        let notification = match self.handler.parse_raw(request) {
+            Ok(notification) => notification,
+            Err(e) => return NotificationReply::fail(StatusCode::BAD_REQUEST, e),
+        };
+        let Some(inbox) = &self.inbox else {
+            return match self.dispatch(notification).await {
+                Ok(()) => NotificationReply::success(),
+                Err((ErrorPolicy::Acknowledge, _)) => NotificationReply::success(),
+                Err((ErrorPolicy::Retry, e)) => {
+                    NotificationReply::fail(StatusCode::INTERNAL_SERVER_ERROR, e)
+                }
+            };
+        };
+        // persisted before dispatching, so that a crash can't lose the event.
+        let mut entry = match inbox.receive(notification) {
+            Ok(Delivery::Claimed(entry)) => *entry,
+            Ok(Delivery::Duplicate(_)) => return NotificationReply::success(),
+            Ok(Delivery::InFlight) => {
+                return NotificationReply::fail(
+                    StatusCode::CONFLICT,
+                    "notification is being processed",
+                )
+            }
+            Err(e) => {
+                error!("Failed to persist notification: {}", e);
+                return NotificationReply::fail(StatusCode::INTERNAL_SERVER_ERROR, e);
+            }
+        };
+        match self.dispatch(entry.notification.clone()).await {
+            Ok(()) => {
+                if let Err(e) = inbox.complete(&mut entry) {
+                    error!("Failed to complete notification {}: {}", entry.get_id(), e);
+                }
+                NotificationReply::success()
+            }
+            Err((policy, e)) => {
+                match inbox.fail(&mut entry, &e, policy == ErrorPolicy::Acknowledge) {
+                    Ok(true) => NotificationReply::success(),
+                    Ok(false) => NotificationReply::fail(StatusCode::INTERNAL_SERVER_ERROR, e),
+                    Err(store_error) => {
+                        error!("Failed to record notification failure: {}", store_error);
+                        NotificationReply::fail(StatusCode::INTERNAL_SERVER_ERROR, e)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Dispatch a notification of the inbox again, e.g. a dead letter once its handler is
+    /// fixed, the entry keeps its status on failure. Entries in flight are refused.
+    pub async fn replay(&self, id: &str) -> Result<(), String> {
+        let Some(inbox) = &self.inbox else {
+            return Err("no inbox is configured".to_string());
+        };
+        let Some(entry) = inbox.get_store().get(id)? else {
+            return Err(format!("notification {} not found", id));
+        };
+        if entry.status == InboxStatus::Processed {
+            return Ok(());
+        }
+        // a dead letter failing again stays one.
+        let dead_letter = entry.status == InboxStatus::DeadLetter;
+        let Some(mut entry) = inbox.claim(id, entry.status)? else {
+            return Err(format!("notification {} is being processed", id));
+        };
+        match self.dispatch(entry.notification.clone()).await {
+            Ok(()) => inbox.complete(&mut entry),
+            Err((_, e)) => {
+                inbox.fail(&mut entry, &e, dead_letter)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Run the handler of a notification, errors come with the policy of its route.
+    async fn dispatch(&self, notification: Notification) -> Result<(), (ErrorPolicy, String)> {
+        let event_type = notification.get_event_type();
+        let Some(route) = self.routes.get(&event_type).or(self.fallback.as_ref()) else {
+            warn!(
+                "No route for notification {} of {}, acknowledged",
+                notification.id, event_type
+            );
+            return Ok(());
+        };
+        let span = tracing::info_span!(
+            "wechat_pay.notification.dispatch",
+            id = notification.id.as_str(),
+            event_type = event_type.as_str(),
+        );
+        (route.handler)(notification)
+            .instrument(span)
+            .await
+            .map_err(|e| {
+                match route.policy {
+                    ErrorPolicy::Acknowledge => {
+                        warn!("Acknowledged failed notification of {}: {}", event_type, e)
+                    }
+                    ErrorPolicy::Retry => {
+                        error!("Failed to handle notification of {}: {}", event_type, e)
+                    }
+                }
+                (route.policy, e)
+            })
+    }
+
+    /// Verify and dispatch a raw http notification.
+    pub async fn handle_http(&self, request: &http::Request<Bytes>) -> http::Response<Bytes> {
+        match NotificationRequest::from_http_request(request) {
+            Ok(request) => self.handle(request).await,
+            Err(e) => NotificationReply::fail(StatusCode::BAD_REQUEST, e),
+        }
+        .into_http_response()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::inbox::InMemoryInboxStore;
+    use crate::testing::NotificationSimulator;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const API_V3_KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    /// A handler verifying notifications of [new_request].
+    pub(crate) fn new_handler() -> NotificationHandler {
+        NotificationSimulator::new(API_V3_KEY).get_handler()
+    }
+
+    /// A signed notification with `plain_text` as resource.
+    pub(crate) fn new_request(event_type: &str, plain_text: &str) -> http::Request<Bytes> {
+        let resource = serde_json::from_str::<serde_json::Value>(plain_text).unwrap();
+        NotificationSimulator::new(API_V3_KEY)
+            .notification(event_type.parse().unwrap(), &resource)
+            .build()
+    }
+
+    #[derive(serde::Deserialize)]
+    pub(crate) struct Refund {
+        pub(crate) out_refund_no: String,
+    }
+
+    #[tokio::test]
+    async fn test_router() {
+        let unrouted = Arc::new(AtomicUsize::new(0));
+        let counter = unrouted.clone();
+        let router = NotificationRouter::new(new_handler())
+            .route(
+                EventType::RefundSuccess,
+                |event: Event<Refund>| async move {
+                    assert_eq!(
+                        "R1217752501201407033233368018",
+                        event.resource.out_refund_no
+                    );
+                    Ok(())
+                },
+            )
+            .route(EventType::RefundAbnormal, |_: Event<Refund>| async {
+                Err("bank account is closed".to_string())
+            })
+            .route_with_policy(
+                EventType::RefundClosed,
+                ErrorPolicy::Acknowledge,
+                |_: Event<Refund>| async { Err("already closed".to_string()) },
+            )
+            .with_fallback(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async { Ok(()) }
+            });
+
+        let refund = r#"{"out_refund_no":"R1217752501201407033233368018"}"#;
+        let response = router
+            .handle_http(&new_request("REFUND.SUCCESS", refund))
+            .await;
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        assert!(response.body().is_empty());
+
+        let response = router
+            .handle_http(&new_request("REFUND.ABNORMAL", refund))
+            .await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+        assert_eq!(
+            r#"{"code":"FAIL","message":"bank account is closed"}"#,
+            response.body()
+        );
+
+        let response = router
+            .handle_http(&new_request("REFUND.CLOSED", refund))
+            .await;
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+
+        let response = router
+            .handle_http(&new_request("VEHICLE.ENTRANCE", "{}"))
+            .await;
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        assert_eq!(1, unrouted.load(Ordering::SeqCst));
+
+        // a typed resource which can't be deserialized is retried.
+        let response = router
+            .handle_http(&new_request("REFUND.SUCCESS", "{}"))
+            .await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+
+        let mut request = new_request("REFUND.SUCCESS", refund);
+        request.headers_mut().remove("Wechatpay-Nonce");
+        let response = router.handle_http(&request).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+    }
+
+    #[tokio::test]
+    async fn test_router_with_inbox() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let failure = failing.clone();
+        let inbox = NotificationInbox::new(InMemoryInboxStore::new()).with_max_attempts(2);
+        let router = NotificationRouter::new(new_handler())
+            .route(EventType::RefundSuccess, move |_: Event<Refund>| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let failing = failure.load(Ordering::SeqCst);
+                async move {
+                    match failing {
+                        true => Err("database is down".to_string()),
+                        false => Ok(()),
+                    }
+                }
+            })
+            .with_inbox(inbox.clone());
+
+        let refund = r#"{"out_refund_no":"R1"}"#;
+        let request = new_request("REFUND.SUCCESS", refund);
+        let response = router.handle_http(&request).await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+        // dead-lettered and acknowledged on the second failure, then skipped.
+        let response = router.handle_http(&request).await;
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        let response = router.handle_http(&request).await;
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+
+        let dead_letters = inbox.dead_letters(10).unwrap();
+        assert_eq!(1, dead_letters.len());
+        let id = dead_letters[0].get_id();
+        assert!(router.replay(id).await.is_err());
+
+        failing.store(false, Ordering::SeqCst);
+        router.replay(id).await.unwrap();
+        assert_eq!(4, calls.load(Ordering::SeqCst));
+        let entry = inbox.get_store().get(id).unwrap().unwrap();
+        assert_eq!(InboxStatus::Processed, entry.status);
+        assert_eq!(3, entry.attempts);
+        assert!(inbox.dead_letters(10).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replay_with_concurrent_delivery() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let failure = failing.clone();
+        let inbox = NotificationInbox::new(InMemoryInboxStore::new()).with_max_attempts(1);
+        let router = NotificationRouter::new(new_handler())
+            .route(EventType::RefundSuccess, move |_: Event<Refund>| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let failing = failure.load(Ordering::SeqCst);
+                async move {
+                    // in flight while the other calls run.
+                    tokio::task::yield_now().await;
+                    match failing {
+                        true => Err("database is down".to_string()),
+                        false => Ok(()),
+                    }
+                }
+            })
+            .with_inbox(inbox.clone());
+
+        let request = new_request("REFUND.SUCCESS", r#"{"out_refund_no":"R1"}"#);
+        let response = router.handle_http(&request).await;
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        let id = inbox.dead_letters(10).unwrap()[0].get_id().to_string();
+
+        // a failed replay keeps the dead letter.
+        assert!(router.replay(&id).await.is_err());
+        let entry = inbox.get_store().get(&id).unwrap().unwrap();
+        assert_eq!(InboxStatus::DeadLetter, entry.status);
+        assert_eq!(2, entry.attempts);
+
+        failing.store(false, Ordering::SeqCst);
+        let (replayed, replayed_again, redelivered) = tokio::join!(
+            router.replay(&id),
+            router.replay(&id),
+            router.handle_http(&request)
+        );
+        replayed.unwrap();
+        assert_eq!(
+            Err(format!("notification {} is being processed", id)),
+            replayed_again
+        );
+        assert_eq!(StatusCode::CONFLICT, redelivered.status());
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+        let entry = inbox.get_store().get(&id).unwrap().unwrap();
+        assert_eq!(InboxStatus::Processed, entry.status);
+        assert_eq!(None, entry.claimed_at);
+    }
+
+    #[tokio::test]
+    async fn test_router_with_inflight_notification() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let inbox = NotificationInbox::new(InMemoryInboxStore::new());
+        let router = NotificationRouter::new(new_handler())
+            .route(EventType::RefundSuccess, move |_: Event<Refund>| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async { Ok(()) }
+            })
+            .with_inbox(inbox.clone());
+
+        let request = new_request("REFUND.SUCCESS", r#"{"out_refund_no":"R1"}"#);
+        // claimed by a delivery still being handled.
+        let notification = new_handler()
+            .parse_raw(NotificationRequest::from_http_request(&request).unwrap())
+            .unwrap();
+        let Ok(Delivery::Claimed(mut entry)) = inbox.receive(notification) else {
+            panic!("the first delivery claims the notification");
+        };
+        let response = router.handle_http(&request).await;
+        assert_eq!(StatusCode::CONFLICT, response.status());
+        assert_eq!(0, calls.load(Ordering::SeqCst));
+
+        inbox.complete(&mut entry).unwrap();
+        let response = router.handle_http(&request).await;
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        assert_eq!(0, calls.load(Ordering::SeqCst));
+    }
+}
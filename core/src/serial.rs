@@ -0,0 +1,283 @@
+//! Serial numbers of merchant and platform certificates.
+use std::cmp::Ordering;
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+
+use num_bigint_dig::BigUint;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use x509_parser::certificate::X509Certificate;
+
+/// Serial number of a certificate, compared by value and printed in upper case hex as in
+/// `Wechatpay-Serial`, e.g. `5157F09EFDC096DE15EBE81A47057A7232F1B8E1`.
+///
+/// Kept as big endian bytes without leading zero bytes, each printed with two digits so that a
+/// serial like `0A1B` keeps its leading zero.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct SerialNumber(Vec<u8>);
+
+impl SerialNumber {
+    /// Parse hex in any case, optionally prefixed with `0x` or separated with `:`.
+    pub fn from_hex(hex: impl AsRef<str>) -> Result<Self, String> {
+        let hex = hex.as_ref().trim();
+        let digits = hex
+            .strip_prefix("0x")
+            .or_else(|| hex.strip_prefix("0X"))
+            .unwrap_or(hex)
+            .replace(':', "");
+        Self::parse(&digits, 16).ok_or_else(|| format!("invalid serial number {}", hex))
+    }
+
+    /// Parse decimal digits, as printed by some x.509 tools.
+    pub fn from_decimal(decimal: impl AsRef<str>) -> Result<Self, String> {
+        let decimal = decimal.as_ref().trim();
+        Self::parse(decimal, 10).ok_or_else(|| format!("invalid serial number {}", decimal))
+    }
+
+    fn parse(digits: &str, radix: u32) -> Option<Self> {
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return None;
+        }
+        BigUint::parse_bytes(digits.as_bytes(), radix).map(Self::from)
+    }
+
+    /// From big endian bytes, e.g. the raw serial of a x.509 certificate.
+    pub fn from_bytes_be(bytes: &[u8]) -> Self {
+        let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+        match &bytes[start..] {
+            [] => Self(vec![0]),
+            bytes => Self(bytes.to_vec()),
+        }
+    }
+
+    pub fn from_certificate(certificate: &X509Certificate) -> Self {
+        Self::from_bytes_be(certificate.raw_serial())
+    }
+
+    pub fn to_bytes_be(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+}
+
+impl PartialOrd for SerialNumber {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SerialNumber {
+    /// By value, bytes have no leading zero.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.0.len(), &self.0).cmp(&(other.0.len(), &other.0))
+    }
+}
+
+impl FromStr for SerialNumber {
+    type Err = String;
+
+    /// Parse hex, the format of WeChat Pay.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_hex(s)
+    }
+}
+
+impl From<BigUint> for SerialNumber {
+    fn from(value: BigUint) -> Self {
+        Self::from_bytes_be(&value.to_bytes_be())
+    }
+}
+
+impl Display for SerialNumber {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02X}", b))
+    }
+}
+
+impl Debug for SerialNumber {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SerialNumber({})", self)
+    }
+}
+
+impl PartialEq<str> for SerialNumber {
+    /// Whether `other` is hex of the same serial number.
+    fn eq(&self, other: &str) -> bool {
+        Self::from_hex(other).is_ok_and(|other| *self == other)
+    }
+}
+
+impl PartialEq<&str> for SerialNumber {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+
+impl PartialEq<SerialNumber> for str {
+    fn eq(&self, other: &SerialNumber) -> bool {
+        other == self
+    }
+}
+
+impl PartialEq<SerialNumber> for &str {
+    fn eq(&self, other: &SerialNumber) -> bool {
+        other == self
+    }
+}
+
+impl Serialize for SerialNumber {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SerialNumber {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        Self::from_hex(hex).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Value of `Wechatpay-Serial`, parsed once: the serial number of a platform certificate, or
+/// the id of a WeChat Pay public key, e.g. `PUB_KEY_ID_0114232134912410000000000000`.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum WechatPaySerial {
+    Certificate(SerialNumber),
+    PublicKey(String),
+}
+
+impl WechatPaySerial {
+    /// A certificate serial number when `value` is hex, a public key id otherwise.
+    pub fn new(value: impl AsRef<str>) -> Self {
+        let value = value.as_ref().trim();
+        match SerialNumber::from_hex(value) {
+            Ok(serial_number) => WechatPaySerial::Certificate(serial_number),
+            Err(_) => WechatPaySerial::PublicKey(value.to_string()),
+        }
+    }
+
+    pub fn get_certificate_serial_number(&self) -> Option<&SerialNumber> {
+        match self {
+            WechatPaySerial::Certificate(serial_number) => Some(serial_number),
+            WechatPaySerial::PublicKey(_) => None,
+        }
+    }
+
+    pub fn get_public_key_id(&self) -> Option<&str> {
+        match self {
+            WechatPaySerial::Certificate(_) => None,
+            WechatPaySerial::PublicKey(key_id) => Some(key_id),
+        }
+    }
+
+    /// Whether no serial was given.
+    pub fn is_empty(&self) -> bool {
+        matches!(self, WechatPaySerial::PublicKey(key_id) if key_id.is_empty())
+    }
+}
+
+impl Default for WechatPaySerial {
+    fn default() -> Self {
+        WechatPaySerial::PublicKey(String::new())
+    }
+}
+
+impl FromStr for WechatPaySerial {
+    type Err = String;
+
+    /// Fails only if `s` is blank.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let serial = Self::new(s);
+        if serial.is_empty() {
+            return Err("serial number is empty".to_string());
+        }
+        Ok(serial)
+    }
+}
+
+impl From<SerialNumber> for WechatPaySerial {
+    fn from(serial_number: SerialNumber) -> Self {
+        WechatPaySerial::Certificate(serial_number)
+    }
+}
+
+impl From<&str> for WechatPaySerial {
+    fn from(value: &str) -> Self {
+        Self::new(value)
+    }
+}
+
+impl Display for WechatPaySerial {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WechatPaySerial::Certificate(serial_number) => Display::fmt(serial_number, f),
+            WechatPaySerial::PublicKey(key_id) => f.write_str(key_id),
+        }
+    }
+}
+
+impl Debug for WechatPaySerial {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WechatPaySerial::Certificate(serial_number) => Debug::fmt(serial_number, f),
+            WechatPaySerial::PublicKey(key_id) => write!(f, "PublicKey({})", key_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wechat_pay_serial() {
+        let serial = WechatPaySerial::new("5157f09efdc096de15ebe81a47057a7232f1b8e1");
+        assert_eq!(
+            Some(&SerialNumber::from_hex("5157F09EFDC096DE15EBE81A47057A7232F1B8E1").unwrap()),
+            serial.get_certificate_serial_number()
+        );
+        assert_eq!(
+            "5157F09EFDC096DE15EBE81A47057A7232F1B8E1",
+            serial.to_string()
+        );
+
+        let serial: WechatPaySerial = "PUB_KEY_ID_0114232134912410000000000000".parse().unwrap();
+        assert_eq!(
+            Some("PUB_KEY_ID_0114232134912410000000000000"),
+            serial.get_public_key_id()
+        );
+        assert!(serial.get_certificate_serial_number().is_none());
+        assert!(" ".parse::<WechatPaySerial>().is_err());
+    }
+
+    #[test]
+    fn test_serial_number() {
+        let serial = SerialNumber::from_hex("5157F09EFDC096DE15EBE81A47057A7232F1B8E1").unwrap();
+        assert_eq!(
+            serial,
+            SerialNumber::from_hex("0x5157f09efdc096de15ebe81a47057a7232f1b8e1").unwrap()
+        );
+        assert_eq!(
+            serial,
+            SerialNumber::from_hex("51:57:F0:9E:FD:C0:96:DE:15:EB:E8:1A:47:05:7A:72:32:F1:B8:E1")
+                .unwrap()
+        );
+        assert_eq!(serial, "5157f09efdc096de15ebe81a47057a7232f1b8e1");
+        assert_eq!(
+            SerialNumber::from_bytes_be(&[0x07, 0x00, 0x71]),
+            SerialNumber::from_decimal("458865").unwrap()
+        );
+        assert!(SerialNumber::from_hex("PUB_KEY_ID_0114232134912410000000000000").is_err());
+        assert!(SerialNumber::from_hex("FF").unwrap() < SerialNumber::from_hex("0100").unwrap());
+
+        // leading zero nibbles are kept, leading zero bytes are not.
+        let padded = SerialNumber::from_hex("0A1B").unwrap();
+        assert_eq!("0A1B", padded.to_string());
+        assert_eq!(padded, SerialNumber::from_hex("A1B").unwrap());
+        assert_eq!(padded, SerialNumber::from_bytes_be(&[0x00, 0x0A, 0x1B]));
+        assert_eq!("00", SerialNumber::from_bytes_be(&[]).to_string());
+        assert!(SerialNumber::from_decimal("7A").is_err());
+
+        let json = serde_json::to_string(&serial).unwrap();
+        assert_eq!(r#""5157F09EFDC096DE15EBE81A47057A7232F1B8E1""#, json);
+        assert_eq!(serial, serde_json::from_str::<SerialNumber>(&json).unwrap());
+    }
+}
@@ -0,0 +1,978 @@
+//! An in-process mock of WeChat Pay listening on localhost, for integration tests without
+//! network access.
+//!
+//! The server verifies the `WECHATPAY2-SHA256-RSA2048` Authorization of every request against
+//! the merchant certificate, signs responses with the test platform key of its
+//! [NotificationSimulator] and keeps the state of transactions and refunds. Point a client at
+//! it with `with_hosts(HostSelector::single(server.get_base_url()))` over a transport allowing
+//! plain http, e.g. `ReqwestTransport::builder().with_https_only(false)`, and validate
+//! responses with [get_verifier](MockServer::get_verifier).
+//!
+//! Mocked endpoints:
+//!
+//! ```text
+//! POST /v3/pay/transactions/{jsapi,app,h5,native}
+//! GET  /v3/pay/transactions/out-trade-no/{out_trade_no}
+//! GET  /v3/pay/transactions/id/{transaction_id}
+//! POST /v3/pay/transactions/out-trade-no/{out_trade_no}/close
+//! POST /v3/refund/domestic/refunds
+//! GET  /v3/refund/domestic/refunds/{out_refund_no}
+//! GET  /v3/certificates
+//! GET  /v3/bill/tradebill
+//! GET  /v3/billdownload/file
+//! ```
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::header::{AUTHORIZATION, CONTENT_TYPE};
+use http::{HeaderMap, Method, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Server};
+use rand::Rng;
+use security::hash::{Hash, HashDigest};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+
+use super::{format_time, random_string, NotificationSimulator};
+use crate::cipher::Signer;
+use crate::event::EventType;
+use crate::prelude::*;
+use crate::serial::SerialNumber;
+use crate::verify::{CertificatesVerifier, VerifyingKey};
+
+/// APIv3 key of a server built without one.
+pub const MOCK_API_V3_KEY: &[u8; 32] = b"mock-api-v3-key-0123456789abcdef";
+const SCHEMA: &str = "WECHATPAY2-SHA256-RSA2048";
+const AUTHORIZATION_EXPIRED_SECONDS: u64 = 5 * 60;
+
+/// A request received by a [MockServer].
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: Method,
+    /// Path with query.
+    pub path: String,
+    pub body: String,
+    /// Whether the Authorization was verified.
+    pub authorized: bool,
+}
+
+/// Builds a [MockServer].
+pub struct MockServerBuilder {
+    merchant_id: String,
+    merchant_certificate: String,
+    api_v3_key: Vec<u8>,
+}
+
+impl MockServerBuilder {
+    /// Encrypt resources with `api_v3_key` instead of [MOCK_API_V3_KEY].
+    pub fn with_api_v3_key(mut self, api_v3_key: impl AsRef<[u8]>) -> Self {
+        self.api_v3_key = api_v3_key.as_ref().to_vec();
+        self
+    }
+
+    /// Listen on a free port of localhost until the server is dropped.
+    pub async fn start(self) -> Result<MockServer, String> {
+        let merchant_key = VerifyingKey::from_certificate_pem(&self.merchant_certificate)?;
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(|e| e.to_string())?;
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        let address = listener.local_addr().map_err(|e| e.to_string())?;
+        let inner = Arc::new(Inner {
+            merchant_id: self.merchant_id,
+            merchant_key,
+            simulator: NotificationSimulator::new(&self.api_v3_key),
+            api_v3_key: self.api_v3_key,
+            base_url: format!("http://{}", address),
+            state: Mutex::new(State::default()),
+        });
+
+        let service_inner = inner.clone();
+        let make_service = make_service_fn(move |_| {
+            let inner = service_inner.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |request| handle(inner.clone(), request))) }
+        });
+        let server = Server::from_tcp(listener)
+            .map_err(|e| e.to_string())?
+            .serve(make_service);
+        let (shutdown, signal) = oneshot::channel::<()>();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = signal.await;
+        }));
+        debug!("Mock WeChat Pay server listening on {}", inner.base_url);
+        Ok(MockServer {
+            inner,
+            shutdown: Some(shutdown),
+        })
+    }
+}
+
+/// A mock of WeChat Pay, stopped on drop.
+pub struct MockServer {
+    inner: Arc<Inner>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockServer {
+    /// Accept requests of `merchant_id` signed with the key of `merchant_certificate` in pem.
+    pub fn builder(
+        merchant_id: impl AsRef<str>,
+        merchant_certificate: impl AsRef<str>,
+    ) -> MockServerBuilder {
+        MockServerBuilder {
+            merchant_id: merchant_id.as_ref().to_string(),
+            merchant_certificate: merchant_certificate.as_ref().to_string(),
+            api_v3_key: MOCK_API_V3_KEY.to_vec(),
+        }
+    }
+
+    /// e.g. `http://127.0.0.1:52344`.
+    pub fn get_base_url(&self) -> &str {
+        &self.inner.base_url
+    }
+
+    pub fn get_api_v3_key(&self) -> &[u8] {
+        &self.inner.api_v3_key
+    }
+
+    /// A verifier of the responses and notifications of the server.
+    pub fn get_verifier(&self) -> CertificatesVerifier {
+        self.inner.simulator.get_verifier()
+    }
+
+    /// The simulator building the notifications of the server.
+    pub fn get_simulator(&self) -> &NotificationSimulator {
+        &self.inner.simulator
+    }
+
+    /// Reply once `status` and `body` to the next `method` request to `path`, ignoring the
+    /// query, before the stateful endpoints, e.g. to script a `SYSTEM_ERROR`.
+    pub fn script(
+        &self,
+        method: Method,
+        path: impl AsRef<str>,
+        status: u16,
+        body: impl AsRef<str>,
+    ) {
+        self.inner.lock().scripted.push_back(Scripted {
+            method,
+            path: path.as_ref().to_string(),
+            status: StatusCode::from_u16(status).expect("a valid status"),
+            body: body.as_ref().to_string(),
+        });
+    }
+
+    /// Requests received so far.
+    pub fn get_requests(&self) -> Vec<RecordedRequest> {
+        self.inner.lock().requests.clone()
+    }
+
+    pub fn get_transaction(&self, out_trade_no: &str) -> Option<Value> {
+        let state = self.inner.lock();
+        state.transactions.get(out_trade_no).map(|t| t.body.clone())
+    }
+
+    /// Simulate the payment of a `NOTPAY` transaction, notified to its `notify_url`, returns
+    /// the status replied by the callback.
+    pub async fn pay(&self, out_trade_no: &str) -> Result<StatusCode, String> {
+        let (notify_url, transaction) = {
+            let mut state = self.inner.lock();
+            let transaction = state
+                .transactions
+                .get_mut(out_trade_no)
+                .ok_or_else(|| format!("transaction {} not found", out_trade_no))?;
+            if transaction.body["trade_state"] != "NOTPAY" {
+                return Err(format!(
+                    "transaction {} is {}",
+                    out_trade_no, transaction.body["trade_state"]
+                ));
+            }
+            let body = &mut transaction.body;
+            body["transaction_id"] = json!(format!("42000000{}", random_digits(20)));
+            body["trade_state"] = json!("SUCCESS");
+            body["trade_state_desc"] = json!("支付成功");
+            body["bank_type"] = json!("OTHERS");
+            body["success_time"] = json!(format_time(now()));
+            body["amount"]["payer_total"] = body["amount"]["total"].clone();
+            body["amount"]["payer_currency"] = body["amount"]["currency"].clone();
+            (transaction.notify_url.clone(), transaction.body.clone())
+        };
+        self.notify(&notify_url, EventType::TransactionSuccess, &transaction)
+            .await
+    }
+
+    /// Simulate the success of a `PROCESSING` refund, notified to its `notify_url`, returns
+    /// the status replied by the callback.
+    pub async fn complete_refund(&self, out_refund_no: &str) -> Result<StatusCode, String> {
+        let (notify_url, resource) = {
+            let mut state = self.inner.lock();
+            let refund = state
+                .refunds
+                .get_mut(out_refund_no)
+                .ok_or_else(|| format!("refund {} not found", out_refund_no))?;
+            if refund.body["status"] != "PROCESSING" {
+                return Err(format!(
+                    "refund {} is {}",
+                    out_refund_no, refund.body["status"]
+                ));
+            }
+            refund.body["status"] = json!("SUCCESS");
+            refund.body["success_time"] = json!(format_time(now()));
+            let body = refund.body.clone();
+            let notify_url = refund.notify_url.clone();
+            if let Some(transaction) = state.transactions.get_mut(get_str(&body, "out_trade_no")) {
+                transaction.body["trade_state"] = json!("REFUND");
+                transaction.body["trade_state_desc"] = json!("转入退款");
+            }
+            let resource = json!({
+                "mchid": self.inner.merchant_id,
+                "out_trade_no": body["out_trade_no"],
+                "transaction_id": body["transaction_id"],
+                "out_refund_no": body["out_refund_no"],
+                "refund_id": body["refund_id"],
+                "refund_status": "SUCCESS",
+                "success_time": body["success_time"],
+                "user_received_account": body["user_received_account"],
+                "amount": body["amount"],
+            });
+            (notify_url, resource)
+        };
+        self.notify(&notify_url, EventType::RefundSuccess, &resource)
+            .await
+    }
+
+    /// Send a notification of `event_type` to `url`, returns the status replied.
+    pub async fn notify(
+        &self,
+        url: &str,
+        event_type: EventType,
+        resource: &impl Serialize,
+    ) -> Result<StatusCode, String> {
+        let request = self
+            .inner
+            .simulator
+            .notification(event_type, resource)
+            .build();
+        let (mut parts, body) = request.into_parts();
+        parts.uri = url
+            .parse()
+            .map_err(|_| format!("invalid notify_url {}", url))?;
+        let request = hyper::Request::from_parts(parts, Body::from(body));
+        let response = hyper::Client::new()
+            .request(request)
+            .await
+            .map_err(|e| format!("failed to notify {}: {}", url, e))?;
+        Ok(response.status())
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+struct Scripted {
+    method: Method,
+    path: String,
+    status: StatusCode,
+    body: String,
+}
+
+/// A transaction or a refund with the url to notify its outcome.
+struct Stored {
+    notify_url: String,
+    body: Value,
+}
+
+#[derive(Default)]
+struct State {
+    transactions: HashMap<String, Stored>,
+    refunds: HashMap<String, Stored>,
+    scripted: VecDeque<Scripted>,
+    requests: Vec<RecordedRequest>,
+}
+
+struct Inner {
+    merchant_id: String,
+    merchant_key: VerifyingKey,
+    simulator: NotificationSimulator,
+    api_v3_key: Vec<u8>,
+    base_url: String,
+    state: Mutex<State>,
+}
+
+struct Reply {
+    status: StatusCode,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Reply {
+    fn ok(body: Value) -> Self {
+        Self {
+            status: StatusCode::OK,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    fn no_content() -> Self {
+        Self {
+            status: StatusCode::NO_CONTENT,
+            content_type: "application/json",
+            body: String::new(),
+        }
+    }
+
+    fn error(status: StatusCode, code: &str, message: impl AsRef<str>) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: json!({"code": code, "message": message.as_ref()}).to_string(),
+        }
+    }
+
+    fn param_error(message: impl AsRef<str>) -> Self {
+        Self::error(StatusCode::BAD_REQUEST, "PARAM_ERROR", message)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn random_digits(length: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..length)
+        .map(|_| char::from(b'0' + rng.gen_range(0..10)))
+        .collect()
+}
+
+fn get_str<'a>(value: &'a Value, field: &str) -> &'a str {
+    value[field].as_str().unwrap_or_default()
+}
+
+/// The fields at `pointers` of a json request body, e.g. `/amount/total`.
+fn require(body: &Value, pointers: &[&str]) -> Result<(), Reply> {
+    match pointers.iter().find(|p| body.pointer(p).is_none()) {
+        Some(pointer) => Err(Reply::param_error(format!(
+            "missing field {}",
+            pointer.trim_start_matches('/').replace('/', ".")
+        ))),
+        None => Ok(()),
+    }
+}
+
+async fn handle(
+    inner: Arc<Inner>,
+    request: hyper::Request<Body>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    let (parts, body) = request.into_parts();
+    let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+    let body = String::from_utf8_lossy(&body).to_string();
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/")
+        .to_string();
+    let authorization = inner.authorize(&parts.method, &path, &parts.headers, &body);
+    inner.lock().requests.push(RecordedRequest {
+        method: parts.method.clone(),
+        path: path.clone(),
+        body: body.clone(),
+        authorized: authorization.is_ok(),
+    });
+    let reply = match authorization {
+        Ok(()) => inner.respond(&parts.method, &path, &body),
+        Err(e) => {
+            warn!("Mock rejected {} {}: {}", parts.method, path, e);
+            Reply::error(StatusCode::UNAUTHORIZED, "SIGN_ERROR", e)
+        }
+    };
+    Ok(inner.sign(reply))
+}
+
+impl Inner {
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Verify the Authorization of a request signed by the merchant.
+    fn authorize(
+        &self,
+        method: &Method,
+        path: &str,
+        headers: &HeaderMap,
+        body: &str,
+    ) -> Result<(), String> {
+        let authorization = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .ok_or("missing http header Authorization")?;
+        let token = authorization
+            .strip_prefix(SCHEMA)
+            .and_then(|token| token.strip_prefix(' '))
+            .ok_or("unsupported authorization schema")?;
+        let params = token
+            .split(',')
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                Some((name.trim(), value.trim().trim_matches('"')))
+            })
+            .collect::<HashMap<_, _>>();
+        let get = |name: &str| {
+            params
+                .get(name)
+                .copied()
+                .ok_or_else(|| format!("missing {} in Authorization", name))
+        };
+
+        if get("mchid")? != self.merchant_id {
+            return Err(format!("unknown mchid {}", get("mchid")?));
+        }
+        let serial_number = SerialNumber::from_hex(get("serial_no")?).ok();
+        if serial_number.as_ref() != Some(self.merchant_key.get_serial_number()) {
+            return Err(format!("unknown serial_no {}", get("serial_no")?));
+        }
+        let timestamp = get("timestamp")?
+            .parse::<u64>()
+            .map_err(|_| "invalid timestamp in Authorization")?;
+        if now().abs_diff(timestamp) > AUTHORIZATION_EXPIRED_SECONDS {
+            return Err("timestamp in Authorization is expired".to_string());
+        }
+        let message = format!(
+            "{}\n{}\n{}\n{}\n{}\n",
+            method,
+            path,
+            timestamp,
+            get("nonce_str")?,
+            body
+        );
+        self.merchant_key
+            .verify(message.as_bytes(), get("signature")?)
+            .map_err(|_| "signature verification failed".to_string())
+    }
+
+    fn respond(&self, method: &Method, path: &str, body: &str) -> Reply {
+        let (route, query) = path.split_once('?').unwrap_or((path, ""));
+        if let Some(reply) = self.take_scripted(method, route) {
+            return reply;
+        }
+        let body = match body {
+            "" => Value::Null,
+            body => match serde_json::from_str::<Value>(body) {
+                Ok(body) => body,
+                Err(_) => return Reply::param_error("body is not valid json"),
+            },
+        };
+        let query = url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect::<HashMap<String, String>>();
+        let segments = route.trim_start_matches('/').split('/').collect::<Vec<_>>();
+        let result = match (method.as_str(), segments.as_slice()) {
+            ("POST", ["v3", "pay", "transactions", trade_type])
+                if ["jsapi", "app", "h5", "native"].contains(trade_type) =>
+            {
+                self.create_transaction(trade_type, &body)
+            }
+            ("GET", ["v3", "pay", "transactions", "out-trade-no", out_trade_no]) => {
+                self.query_transaction(|t| get_str(t, "out_trade_no") == *out_trade_no)
+            }
+            ("GET", ["v3", "pay", "transactions", "id", transaction_id]) => {
+                self.query_transaction(|t| get_str(t, "transaction_id") == *transaction_id)
+            }
+            ("POST", ["v3", "pay", "transactions", "out-trade-no", out_trade_no, "close"]) => {
+                self.close_transaction(out_trade_no)
+            }
+            ("POST", ["v3", "refund", "domestic", "refunds"]) => self.create_refund(&body),
+            ("GET", ["v3", "refund", "domestic", "refunds", out_refund_no]) => {
+                self.query_refund(out_refund_no)
+            }
+            ("GET", ["v3", "certificates"]) => Ok(self.get_certificates()),
+            ("GET", ["v3", "bill", "tradebill"]) => self.get_trade_bill(&query),
+            ("GET", ["v3", "billdownload", "file"]) => self.download_bill(&query),
+            _ => Err(Reply::error(
+                StatusCode::NOT_FOUND,
+                "NOT_FOUND",
+                format!("no mock of {} {}", method, route),
+            )),
+        };
+        result.unwrap_or_else(|reply| reply)
+    }
+
+    fn take_scripted(&self, method: &Method, route: &str) -> Option<Reply> {
+        let mut state = self.lock();
+        let index = state
+            .scripted
+            .iter()
+            .position(|s| s.method == method && s.path == route)?;
+        let scripted = state.scripted.remove(index)?;
+        Some(Reply {
+            status: scripted.status,
+            content_type: "application/json",
+            body: scripted.body,
+        })
+    }
+
+    fn create_transaction(&self, trade_type: &str, body: &Value) -> Result<Reply, Reply> {
+        require(
+            body,
+            &[
+                "/appid",
+                "/mchid",
+                "/description",
+                "/out_trade_no",
+                "/notify_url",
+                "/amount/total",
+            ],
+        )?;
+        if get_str(body, "mchid") != self.merchant_id {
+            return Err(Reply::error(
+                StatusCode::FORBIDDEN,
+                "MCH_NOT_EXISTS",
+                "mchid doesn't match the Authorization",
+            ));
+        }
+        let out_trade_no = get_str(body, "out_trade_no");
+        let mut state = self.lock();
+        let transaction = state
+            .transactions
+            .entry(out_trade_no.to_string())
+            .or_insert_with(|| Stored {
+                notify_url: get_str(body, "notify_url").to_string(),
+                body: json!({
+                    "appid": body["appid"],
+                    "mchid": body["mchid"],
+                    "out_trade_no": out_trade_no,
+                    "trade_type": trade_type.to_uppercase(),
+                    "trade_state": "NOTPAY",
+                    "trade_state_desc": "未支付",
+                    "attach": body.get("attach").cloned().unwrap_or(json!("")),
+                    "payer": body.get("payer").cloned().unwrap_or(json!({})),
+                    "amount": {
+                        "total": body["amount"]["total"],
+                        "currency": body["amount"].get("currency").cloned().unwrap_or(json!("CNY")),
+                    },
+                    "prepay_id": format!("wx{}", random_digits(32)),
+                }),
+            });
+        if transaction.body["trade_state"] != "NOTPAY" {
+            return Err(Reply::error(
+                StatusCode::BAD_REQUEST,
+                "ORDERPAID",
+                format!("transaction is {}", transaction.body["trade_state"]),
+            ));
+        }
+        let prepay_id = get_str(&transaction.body, "prepay_id");
+        Ok(Reply::ok(match trade_type {
+            "native" => json!({"code_url": format!("weixin://wxpay/bizpayurl?pr={}", prepay_id)}),
+            "h5" => json!({
+                "h5_url": format!(
+                    "https://wx.tenpay.com/cgi-bin/mmpayweb-bin/checkmweb?prepay_id={}",
+                    prepay_id
+                )
+            }),
+            _ => json!({"prepay_id": prepay_id}),
+        }))
+    }
+
+    fn query_transaction(&self, matches: impl Fn(&Value) -> bool) -> Result<Reply, Reply> {
+        let state = self.lock();
+        let transaction = state
+            .transactions
+            .values()
+            .find(|t| matches(&t.body))
+            .ok_or_else(|| Reply::error(StatusCode::NOT_FOUND, "ORDER_NOT_EXIST", "订单不存在"))?;
+        let mut body = transaction.body.clone();
+        if let Some(body) = body.as_object_mut() {
+            body.remove("prepay_id");
+        }
+        Ok(Reply::ok(body))
+    }
+
+    fn close_transaction(&self, out_trade_no: &str) -> Result<Reply, Reply> {
+        let mut state = self.lock();
+        let transaction = state
+            .transactions
+            .get_mut(out_trade_no)
+            .ok_or_else(|| Reply::error(StatusCode::NOT_FOUND, "ORDER_NOT_EXIST", "订单不存在"))?;
+        match get_str(&transaction.body, "trade_state") {
+            "NOTPAY" | "CLOSED" => {
+                transaction.body["trade_state"] = json!("CLOSED");
+                transaction.body["trade_state_desc"] = json!("已关闭");
+                Ok(Reply::no_content())
+            }
+            _ => Err(Reply::error(
+                StatusCode::BAD_REQUEST,
+                "ORDERPAID",
+                "订单已支付",
+            )),
+        }
+    }
+
+    fn create_refund(&self, body: &Value) -> Result<Reply, Reply> {
+        require(
+            body,
+            &[
+                "/out_refund_no",
+                "/amount/refund",
+                "/amount/total",
+                "/amount/currency",
+            ],
+        )?;
+        let mut state = self.lock();
+        let out_refund_no = get_str(body, "out_refund_no");
+        if let Some(refund) = state.refunds.get(out_refund_no) {
+            return Ok(Reply::ok(refund.body.clone()));
+        }
+        let transaction = state
+            .transactions
+            .values()
+            .find(|t| {
+                let out_trade_no = get_str(body, "out_trade_no");
+                let transaction_id = get_str(body, "transaction_id");
+                (!out_trade_no.is_empty() && get_str(&t.body, "out_trade_no") == out_trade_no)
+                    || (!transaction_id.is_empty()
+                        && get_str(&t.body, "transaction_id") == transaction_id)
+            })
+            .ok_or_else(|| {
+                Reply::error(StatusCode::NOT_FOUND, "RESOURCE_NOT_EXISTS", "订单不存在")
+            })?;
+        if !matches!(
+            get_str(&transaction.body, "trade_state"),
+            "SUCCESS" | "REFUND"
+        ) {
+            return Err(Reply::error(
+                StatusCode::FORBIDDEN,
+                "INVALID_REQUEST",
+                "订单未支付",
+            ));
+        }
+        let total = &transaction.body["amount"]["total"];
+        let refund = &body["amount"]["refund"];
+        if &body["amount"]["total"] != total || refund.as_u64() > total.as_u64() {
+            return Err(Reply::param_error("invalid amount"));
+        }
+        let refund_body = json!({
+            "refund_id": format!("50000000{}", random_digits(21)),
+            "out_refund_no": out_refund_no,
+            "transaction_id": transaction.body["transaction_id"],
+            "out_trade_no": transaction.body["out_trade_no"],
+            "channel": "ORIGINAL",
+            "user_received_account": "支付用户零钱",
+            "create_time": format_time(now()),
+            "status": "PROCESSING",
+            "amount": {
+                "total": total,
+                "refund": refund,
+                "payer_total": total,
+                "payer_refund": refund,
+                "currency": body["amount"]["currency"],
+            },
+        });
+        let notify_url = match get_str(body, "notify_url") {
+            "" => transaction.notify_url.clone(),
+            notify_url => notify_url.to_string(),
+        };
+        state.refunds.insert(
+            out_refund_no.to_string(),
+            Stored {
+                notify_url,
+                body: refund_body.clone(),
+            },
+        );
+        Ok(Reply::ok(refund_body))
+    }
+
+    fn query_refund(&self, out_refund_no: &str) -> Result<Reply, Reply> {
+        let state = self.lock();
+        let refund = state.refunds.get(out_refund_no).ok_or_else(|| {
+            Reply::error(StatusCode::NOT_FOUND, "RESOURCE_NOT_EXISTS", "退款单不存在")
+        })?;
+        Ok(Reply::ok(refund.body.clone()))
+    }
+
+    /// The platform certificate encrypted with the APIv3 key.
+    fn get_certificates(&self) -> Reply {
+        const ASSOCIATED_DATA: &str = "certificate";
+        let certificate = self.simulator.get_certificate();
+        let nonce = random_string(12);
+        let cipher_text =
+            security::aes::encrypt(&self.api_v3_key, ASSOCIATED_DATA, &nonce, certificate)
+                .expect("a valid APIv3 key");
+        let (effective_time, expire_time) =
+            x509_parser::pem::parse_x509_pem(certificate.as_bytes())
+                .ok()
+                .and_then(|(_, pem)| {
+                    let validity = pem.parse_x509().ok()?.validity().clone();
+                    Some((
+                        format_time(validity.not_before.timestamp() as u64),
+                        format_time(validity.not_after.timestamp() as u64),
+                    ))
+                })
+                .unwrap_or_default();
+        Reply::ok(json!({
+            "data": [{
+                "serial_no": self.simulator.get_serial_number(),
+                "effective_time": effective_time,
+                "expire_time": expire_time,
+                "encrypt_certificate": {
+                    "algorithm": "AEAD_AES_256_GCM",
+                    "nonce": nonce,
+                    "associated_data": ASSOCIATED_DATA,
+                    "ciphertext": base64::encode(cipher_text),
+                }
+            }]
+        }))
+    }
+
+    /// A trade bill of the successful transactions, the date is only checked for presence.
+    fn get_bill(&self) -> String {
+        let state = self.lock();
+        let mut bill =
+            "交易时间,公众账号ID,商户号,微信订单号,商户订单号,交易状态,订单金额\n".to_string();
+        let mut transactions = state
+            .transactions
+            .values()
+            .filter(|t| t.body["trade_state"] == "SUCCESS")
+            .map(|t| &t.body)
+            .collect::<Vec<_>>();
+        transactions.sort_by_key(|t| get_str(t, "success_time"));
+        for t in &transactions {
+            bill.push_str(&format!(
+                "`{},`{},`{},`{},`{},`SUCCESS,`{:.2}\n",
+                get_str(t, "success_time"),
+                get_str(t, "appid"),
+                get_str(t, "mchid"),
+                get_str(t, "transaction_id"),
+                get_str(t, "out_trade_no"),
+                t["amount"]["total"].as_u64().unwrap_or_default() as f64 / 100.0,
+            ));
+        }
+        bill.push_str(&format!("总交易单数\n`{}\n", transactions.len()));
+        bill
+    }
+
+    fn get_trade_bill(&self, query: &HashMap<String, String>) -> Result<Reply, Reply> {
+        let bill_date = query
+            .get("bill_date")
+            .ok_or_else(|| Reply::param_error("missing field bill_date"))?;
+        let hash_value = Hash::Sha1
+            .hash(self.get_bill())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        Ok(Reply::ok(json!({
+            "hash_type": "SHA1",
+            "hash_value": hash_value,
+            "download_url": format!(
+                "{}/v3/billdownload/file?token={}",
+                self.base_url, bill_date
+            ),
+        })))
+    }
+
+    fn download_bill(&self, query: &HashMap<String, String>) -> Result<Reply, Reply> {
+        if !query.contains_key("token") {
+            return Err(Reply::param_error("missing field token"));
+        }
+        Ok(Reply {
+            status: StatusCode::OK,
+            content_type: "text/csv",
+            body: self.get_bill(),
+        })
+    }
+
+    /// Sign a reply with the platform key as WeChat Pay does.
+    fn sign(&self, reply: Reply) -> hyper::Response<Body> {
+        let timestamp = now();
+        let nonce = random_string(32);
+        let message = format!("{}\n{}\n{}\n", timestamp, nonce, reply.body);
+        let signature = self.simulator.signer.sign(&message).expect("a signature");
+        hyper::Response::builder()
+            .status(reply.status)
+            .header(CONTENT_TYPE, reply.content_type)
+            .header("Request-ID", format!("08{}", random_digits(30)))
+            .header("Wechatpay-Serial", self.simulator.get_serial_number())
+            .header("Wechatpay-Timestamp", timestamp.to_string())
+            .header("Wechatpay-Nonce", nonce)
+            .header("Wechatpay-Signature", signature.get_sign())
+            .header("Wechatpay-Signature-Type", SCHEMA)
+            .body(Body::from(reply.body))
+            .expect("valid headers")
+    }
+}
+
+#[cfg(all(test, feature = "reqwest"))]
+mod tests {
+    use super::*;
+    use crate::auth::{WxPay2Credential, WxPay2Validator};
+    use crate::cipher::RsaSigner;
+    use crate::event::Event;
+    use crate::failover::HostSelector;
+    use crate::http::{DefaultHttpClient, HttpClient, HttpError};
+    use crate::notification::NotificationHandler;
+    use crate::retry::RetryPolicy;
+    use crate::router::NotificationRouter;
+    use crate::transport::ReqwestTransport;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    const MERCHANT_ID: &str = "1900000001";
+    const MERCHANT_SERIAL: &str = "6048A6A668D316A4EBA392BD0CA4FEAABDCB611E";
+    const MERCHANT_KEY: &str = include_str!("../../testdata/merchant_key.pem");
+    const MERCHANT_CERT: &str = include_str!("../../testdata/merchant_cert.pem");
+    const PLATFORM_KEY: &str = include_str!("../../testdata/platform_key.pem");
+
+    fn new_client(server: &MockServer, private_key: &str) -> DefaultHttpClient {
+        let signer = RsaSigner::new(MERCHANT_SERIAL, private_key).unwrap();
+        let transport = ReqwestTransport::builder()
+            .with_https_only(false)
+            .build()
+            .unwrap();
+        DefaultHttpClient::from_transport(
+            Arc::new(transport),
+            WxPay2Credential::new(MERCHANT_ID, signer),
+            WxPay2Validator::new(server.get_verifier()),
+        )
+        .with_retry_policy(
+            RetryPolicy::default().with_backoff(Duration::from_millis(1), Duration::from_millis(5)),
+        )
+        .with_hosts(HostSelector::single(server.get_base_url()))
+    }
+
+    /// Serve `router` on localhost, returns its url.
+    fn serve(router: NotificationRouter) -> String {
+        let router = Arc::new(router);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let url = format!("http://{}/notify", listener.local_addr().unwrap());
+        let make_service = make_service_fn(move |_| {
+            let router = router.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: hyper::Request<Body>| {
+                    let router = router.clone();
+                    async move {
+                        let (parts, body) = request.into_parts();
+                        let body = hyper::body::to_bytes(body).await.unwrap();
+                        let response = router
+                            .handle_http(&http::Request::from_parts(parts, body))
+                            .await;
+                        Ok::<_, Infallible>(response.map(Body::from))
+                    }
+                }))
+            }
+        });
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_service));
+        url
+    }
+
+    #[tokio::test]
+    async fn test_mock_server() {
+        let server = MockServer::builder(MERCHANT_ID, MERCHANT_CERT)
+            .start()
+            .await
+            .unwrap();
+        let client = new_client(&server, MERCHANT_KEY);
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let handler = NotificationHandler::new(server.get_api_v3_key(), server.get_verifier());
+        let notify_url = serve(NotificationRouter::new(handler).route(
+            EventType::TransactionSuccess,
+            move |event: Event<Value>| {
+                sender.send(event.resource).unwrap();
+                async { Ok(()) }
+            },
+        ));
+
+        let order = json!({
+            "appid": "wxd678efh567hg6787",
+            "mchid": MERCHANT_ID,
+            "description": "Image形象店-深圳腾大-QQ公仔",
+            "out_trade_no": "1217752501201407033233368018",
+            "notify_url": notify_url,
+            "amount": {"total": 100, "currency": "CNY"},
+        });
+        let response = client
+            .post::<_, Value>("/v3/pay/transactions/native", &order)
+            .await
+            .unwrap();
+        assert!(get_str(&response.into_body(), "code_url").starts_with("weixin://"));
+
+        let path = "/v3/pay/transactions/out-trade-no/1217752501201407033233368018";
+        server.script(
+            Method::GET,
+            path,
+            500,
+            r#"{"code":"SYSTEM_ERROR","message":"busy"}"#,
+        );
+        let response = client
+            .get::<Value>(&format!("{}?mchid={}", path, MERCHANT_ID))
+            .await
+            .unwrap();
+        assert_eq!("NOTPAY", response.into_body()["trade_state"]);
+
+        assert_eq!(
+            StatusCode::NO_CONTENT,
+            server.pay("1217752501201407033233368018").await.unwrap()
+        );
+        let transaction = receiver.recv().await.unwrap();
+        assert_eq!("SUCCESS", transaction["trade_state"]);
+        assert_eq!(100, transaction["amount"]["payer_total"]);
+
+        let refund = json!({
+            "out_trade_no": "1217752501201407033233368018",
+            "out_refund_no": "1217752501201407033233368018-1",
+            "amount": {"refund": 100, "total": 100, "currency": "CNY"},
+        });
+        let response = client
+            .post::<_, Value>("/v3/refund/domestic/refunds", &refund)
+            .await
+            .unwrap();
+        assert_eq!("PROCESSING", response.into_body()["status"]);
+        // acknowledged without a route.
+        let status = server
+            .complete_refund("1217752501201407033233368018-1")
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, status);
+
+        let response = client.get::<Value>("/v3/certificates").await.unwrap();
+        let certificate = &response.into_body()["data"][0];
+        assert_eq!(
+            "2F85E92795F5E7F86BA33C5C365133FDFD5E7E49",
+            certificate["serial_no"]
+        );
+        let encrypted = &certificate["encrypt_certificate"];
+        let plain_text = security::aes::decrypt(
+            server.get_api_v3_key(),
+            get_str(encrypted, "associated_data"),
+            get_str(encrypted, "nonce"),
+            base64::decode(get_str(encrypted, "ciphertext")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(server.get_simulator().get_certificate(), plain_text);
+
+        let response = client
+            .get::<Value>("/v3/bill/tradebill?bill_date=2019-06-11")
+            .await
+            .unwrap();
+        assert_eq!(40, get_str(&response.into_body(), "hash_value").len());
+
+        let result = new_client(&server, PLATFORM_KEY).get::<Value>(path).await;
+        assert!(matches!(
+            result,
+            Err(HttpError::Api { status: 401, ref code, .. }) if code == "SIGN_ERROR"
+        ));
+        let requests = server.get_requests();
+        assert!(!requests.last().unwrap().authorized);
+    }
+}
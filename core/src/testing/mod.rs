@@ -0,0 +1,373 @@
+//! Notifications indistinguishable from WeChat Pay's, to test callback endpoints.
+//!
+//! The simulator signs with a test platform key bundled with the crate, which WeChat Pay never
+//! trusts, and hands out the matching verifiers:
+//!
+//! ```ignore
+//! let simulator = NotificationSimulator::new(api_v3_key);
+//! let handler = NotificationHandler::new(api_v3_key, simulator.get_verifier());
+//! let request = simulator
+//!     .notification(EventType::RefundSuccess, &refund)
+//!     .with_tamper(Tamper::BadSignature)
+//!     .build();
+//! ```
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::{OffsetDateTime, UtcOffset};
+
+use crate::cipher::{RsaSigner, Signer};
+use crate::event::EventType;
+use crate::notification::{NotificationHandler, NotificationRequest};
+use crate::verify::{CertificatesVerifier, PublicKeyVerifier, VerifyingKey};
+
+#[cfg(feature = "mock-server")]
+pub mod mock;
+
+/// Serial number of the bundled test platform certificate.
+pub const TEST_PLATFORM_SERIAL_NUMBER: &str = "2F85E92795F5E7F86BA33C5C365133FDFD5E7E49";
+const TEST_PLATFORM_KEY: &str = include_str!("../../testdata/platform_key.pem");
+const TEST_PLATFORM_CERT: &str = include_str!("../../testdata/platform_cert.pem");
+const TEST_PLATFORM_PUBLIC_KEY: &str = include_str!("../../testdata/platform_public_key.pem");
+/// Signs notifications of [Tamper::WrongSigningKey].
+const OTHER_KEY: &str = include_str!("../../testdata/merchant_key.pem");
+
+/// Age of [Tamper::StaleTimestamp] notifications, outside the default timestamp window of
+/// [NotificationHandler].
+pub const STALE_SECONDS: u64 = 10 * 60;
+
+/// Ways to break a notification, each endpoint should reject.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tamper {
+    /// The signature doesn't match the message.
+    BadSignature,
+    /// Signed with another key under the platform serial number.
+    WrongSigningKey,
+    /// Sent with the serial number of an unknown certificate.
+    UnknownSerialNumber,
+    /// Correctly signed but sent 10 minutes ago.
+    StaleTimestamp,
+    /// The resource is encrypted with another APIv3 key.
+    WrongApiV3Key,
+    /// The resource lacks the tail of its ciphertext and authentication tag.
+    TruncatedCiphertext,
+}
+
+/// Builds notifications signed with a platform key and encrypted with an APIv3 key.
+pub struct NotificationSimulator {
+    api_v3_key: Vec<u8>,
+    signer: RsaSigner,
+    certificate: String,
+    public_key: Option<String>,
+}
+
+impl NotificationSimulator {
+    /// Simulate with the bundled test platform key.
+    pub fn new(api_v3_key: impl AsRef<[u8]>) -> Self {
+        Self {
+            api_v3_key: api_v3_key.as_ref().to_vec(),
+            signer: RsaSigner::new(TEST_PLATFORM_SERIAL_NUMBER, TEST_PLATFORM_KEY)
+                .expect("a valid test platform key"),
+            certificate: TEST_PLATFORM_CERT.to_string(),
+            public_key: Some(TEST_PLATFORM_PUBLIC_KEY.to_string()),
+        }
+    }
+
+    /// Simulate with another platform private key and its certificate in pem.
+    pub fn with_platform_key(
+        mut self,
+        private_key: impl AsRef<str>,
+        certificate: impl AsRef<str>,
+    ) -> Result<Self, String> {
+        let key = VerifyingKey::from_certificate_pem(certificate.as_ref())?;
+        self.signer = RsaSigner::new(key.get_serial_number().to_string(), private_key.as_ref())?;
+        self.certificate = certificate.as_ref().to_string();
+        self.public_key = None;
+        Ok(self)
+    }
+
+    pub fn get_serial_number(&self) -> String {
+        self.signer.get_serial_number().to_string()
+    }
+
+    /// The platform certificate in pem.
+    pub fn get_certificate(&self) -> &str {
+        &self.certificate
+    }
+
+    /// A verifier trusting the platform certificate.
+    pub fn get_verifier(&self) -> CertificatesVerifier {
+        let mut verifier = CertificatesVerifier::new();
+        let key = VerifyingKey::from_certificate_pem(&self.certificate)
+            .expect("a certificate parsed already");
+        verifier.add_key(key);
+        verifier
+    }
+
+    /// A verifier trusting the platform key as a WeChat Pay public key identified by `key_id`,
+    /// only available with the bundled test key. Notifications must then be sent
+    /// [with_serial_number](SimulatedNotification::with_serial_number) `key_id`.
+    pub fn get_public_key_verifier(&self, key_id: impl AsRef<str>) -> Option<PublicKeyVerifier> {
+        let public_key = self.public_key.as_ref()?;
+        Some(PublicKeyVerifier::new(key_id, public_key).expect("a valid test public key"))
+    }
+
+    /// A handler accepting the notifications of this simulator.
+    pub fn get_handler(&self) -> NotificationHandler {
+        NotificationHandler::new(&self.api_v3_key, self.get_verifier())
+    }
+
+    /// Start a notification of `event_type` with `resource` serialized as json.
+    pub fn notification(
+        &self,
+        event_type: EventType,
+        resource: &impl Serialize,
+    ) -> SimulatedNotification<'_> {
+        let resource = serde_json::to_string(resource).expect("a resource serializable as json");
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        // e.g. `refund` for `REFUND.SUCCESS`.
+        let original_type = event_type.as_str().split('.').next().unwrap_or_default();
+        SimulatedNotification {
+            simulator: self,
+            id: format!("EV-{}", random_string(20)),
+            summary: get_summary(&event_type).to_string(),
+            original_type: original_type.to_lowercase(),
+            event_type,
+            resource,
+            serial_number: self.get_serial_number(),
+            timestamp,
+            nonce: random_string(32),
+            tamper: None,
+        }
+    }
+}
+
+fn get_summary(event_type: &EventType) -> &str {
+    match event_type {
+        EventType::TransactionSuccess => "支付成功",
+        EventType::RefundSuccess => "退款成功",
+        EventType::RefundAbnormal => "退款异常",
+        EventType::RefundClosed => "退款关闭",
+        event_type => event_type.as_str(),
+    }
+}
+
+/// Format a unix timestamp as WeChat Pay does, e.g. `2018-06-08T10:34:56+08:00`.
+fn format_time(timestamp: u64) -> String {
+    OffsetDateTime::from_unix_timestamp(timestamp as i64)
+        .expect("a valid timestamp")
+        .to_offset(UtcOffset::from_hms(8, 0, 0).unwrap())
+        .format(&Rfc3339)
+        .expect("a formattable time")
+}
+
+fn random_string(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+/// A notification being built by a [NotificationSimulator].
+pub struct SimulatedNotification<'a> {
+    simulator: &'a NotificationSimulator,
+    id: String,
+    event_type: EventType,
+    summary: String,
+    original_type: String,
+    resource: String,
+    serial_number: String,
+    timestamp: u64,
+    nonce: String,
+    tamper: Option<Tamper>,
+}
+
+impl SimulatedNotification<'_> {
+    /// The notification id, random by default.
+    pub fn with_id(mut self, id: impl AsRef<str>) -> Self {
+        self.id = id.as_ref().to_string();
+        self
+    }
+
+    pub fn with_summary(mut self, summary: impl AsRef<str>) -> Self {
+        self.summary = summary.as_ref().to_string();
+        self
+    }
+
+    /// The original type of the resource, by default the lower case prefix of the event type,
+    /// e.g. `refund` for `REFUND.SUCCESS`.
+    pub fn with_original_type(mut self, original_type: impl AsRef<str>) -> Self {
+        self.original_type = original_type.as_ref().to_string();
+        self
+    }
+
+    /// Send in `Wechatpay-Serial` instead of the serial number of the platform certificate.
+    pub fn with_serial_number(mut self, serial_number: impl AsRef<str>) -> Self {
+        self.serial_number = serial_number.as_ref().to_string();
+        self
+    }
+
+    /// Unix timestamp in seconds of `Wechatpay-Timestamp`, now by default.
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn with_tamper(mut self, tamper: Tamper) -> Self {
+        self.tamper = Some(tamper);
+        self
+    }
+
+    fn get_body(&self) -> String {
+        let nonce = random_string(12);
+        let api_v3_key = match self.tamper {
+            Some(Tamper::WrongApiV3Key) => self.simulator.api_v3_key.iter().map(|b| !b).collect(),
+            _ => self.simulator.api_v3_key.clone(),
+        };
+        let mut cipher_text =
+            security::aes::encrypt(&api_v3_key, &self.original_type, &nonce, &self.resource)
+                .expect("a valid APIv3 key");
+        if self.tamper == Some(Tamper::TruncatedCiphertext) {
+            cipher_text.truncate(cipher_text.len() / 2);
+        }
+        serde_json::json!({
+            "id": self.id,
+            "create_time": format_time(self.timestamp),
+            "event_type": self.event_type,
+            "resource_type": "encrypt-resource",
+            "summary": self.summary,
+            "resource": {
+                "algorithm": "AEAD_AES_256_GCM",
+                "ciphertext": base64::encode(cipher_text),
+                "associated_data": self.original_type,
+                "nonce": nonce,
+                "original_type": self.original_type,
+            }
+        })
+        .to_string()
+    }
+
+    /// Build the http request WeChat Pay would send.
+    pub fn build(&self) -> http::Request<Bytes> {
+        let body = self.get_body();
+        let timestamp = match self.tamper {
+            Some(Tamper::StaleTimestamp) => self.timestamp.saturating_sub(STALE_SECONDS),
+            _ => self.timestamp,
+        };
+        let message = format!("{}\n{}\n{}\n", timestamp, self.nonce, body);
+        let signature = match self.tamper {
+            Some(Tamper::BadSignature) => self.sign(&format!("{}tampered", message)),
+            Some(Tamper::WrongSigningKey) => RsaSigner::new(TEST_PLATFORM_SERIAL_NUMBER, OTHER_KEY)
+                .expect("a valid test key")
+                .sign(&message)
+                .expect("a signature")
+                .get_sign()
+                .to_string(),
+            _ => self.sign(&message),
+        };
+        let serial_number = match self.tamper {
+            Some(Tamper::UnknownSerialNumber) => "5157F09EFDC096DE15EBE81A47057A7232F1B8E1",
+            _ => &self.serial_number,
+        };
+        http::Request::post("/notify")
+            .header("Content-Type", "application/json")
+            .header("Wechatpay-Serial", serial_number)
+            .header("Wechatpay-Signature", signature)
+            .header("Wechatpay-Timestamp", timestamp.to_string())
+            .header("Wechatpay-Nonce", &self.nonce)
+            .header("Wechatpay-Signature-Type", "WECHATPAY2-SHA256-RSA2048")
+            .body(Bytes::from(body))
+            .expect("valid headers")
+    }
+
+    /// Build the request for [NotificationHandler::parse].
+    pub fn build_request(&self) -> NotificationRequest {
+        NotificationRequest::from_http_request(&self.build()).expect("a complete request")
+    }
+
+    fn sign(&self, message: &str) -> String {
+        self.simulator
+            .signer
+            .sign(message)
+            .expect("a signature")
+            .get_sign()
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Event;
+    use crate::header::{HttpHeaders, WechatPayHeaders};
+
+    const API_V3_KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_simulator() {
+        let simulator = NotificationSimulator::new(API_V3_KEY);
+        let handler = simulator.get_handler();
+        let resource = serde_json::json!({"out_trade_no": "1217752501201407033233368018"});
+        let notification = simulator
+            .notification(EventType::TransactionSuccess, &resource)
+            .with_id("EV-2018022511223320873")
+            .with_summary("支付成功");
+        let event: Event<serde_json::Value> = handler.parse(notification.build_request()).unwrap();
+        assert_eq!("EV-2018022511223320873", event.id);
+        assert_eq!(EventType::TransactionSuccess, event.event_type);
+        assert_eq!("支付成功", event.summary);
+        assert_eq!(resource, event.resource);
+
+        const PUBLIC_KEY_ID: &str = "PUB_KEY_ID_0114232134912410000000000000";
+        let verifier = simulator.get_public_key_verifier(PUBLIC_KEY_ID).unwrap();
+        let handler = NotificationHandler::new(API_V3_KEY, verifier);
+        let request = simulator
+            .notification(EventType::RefundSuccess, &resource)
+            .with_serial_number(PUBLIC_KEY_ID)
+            .build_request();
+        assert!(handler.parse::<serde_json::Value>(request).is_ok());
+    }
+
+    #[test]
+    fn test_tamper() {
+        let simulator = NotificationSimulator::new(API_V3_KEY);
+        let handler = simulator.get_handler();
+        let resource = serde_json::json!({});
+        for tamper in [
+            Tamper::BadSignature,
+            Tamper::WrongSigningKey,
+            Tamper::UnknownSerialNumber,
+            Tamper::StaleTimestamp,
+            Tamper::WrongApiV3Key,
+            Tamper::TruncatedCiphertext,
+        ] {
+            let request = simulator
+                .notification(EventType::RefundClosed, &resource)
+                .with_tamper(tamper)
+                .build_request();
+            assert!(handler.parse_raw(request).is_err(), "{:?}", tamper);
+        }
+
+        // correctly signed, but outside the timestamp window of the handler.
+        let request = simulator
+            .notification(EventType::RefundClosed, &resource)
+            .with_tamper(Tamper::StaleTimestamp)
+            .build();
+        let headers = WechatPayHeaders::parse(&HttpHeaders::from(request.headers())).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        assert!(now.as_secs() - headers.get_timestamp() >= STALE_SECONDS);
+        let request = NotificationRequest::from_http_request(&request).unwrap();
+        assert_eq!(
+            Err("notification is expired".to_string()),
+            handler.parse_raw(request).map(|_| ())
+        );
+    }
+}
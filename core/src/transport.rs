@@ -0,0 +1,613 @@
+//! Http transports sending signed requests.
+//!
+//! A transport only moves bytes: it takes a request signed by the middleware stack and returns
+//! the status, headers and raw body. [ReqwestTransport] is provided with the `reqwest` feature,
+//! other http clients can be plugged by implementing [HttpTransport] or [BlockingHttpTransport].
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use crate::middleware::{HttpRequest, HttpResponse};
+
+/// Kind of a [TransportError], deciding whether a call can be retried or failed over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportErrorKind {
+    /// The connection was never established, the request was not sent.
+    Connect,
+    /// No response in time, the request may have been processed.
+    Timeout,
+    /// The connection was closed while sending the request or reading the response.
+    ConnectionReset,
+    Other,
+}
+
+/// Failure to send a request or to read its response.
+#[derive(Debug)]
+pub struct TransportError {
+    kind: TransportErrorKind,
+    source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl TransportError {
+    pub fn new(
+        kind: TransportErrorKind,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        Self {
+            kind,
+            source: source.into(),
+        }
+    }
+
+    pub fn get_kind(&self) -> TransportErrorKind {
+        self.kind
+    }
+}
+
+impl std::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.source)
+    }
+}
+
+impl std::error::Error for TransportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// Sends signed requests asynchronously.
+#[async_trait]
+pub trait HttpTransport: Send + Sync {
+    /// Send the request to `request.host`, with `request.headers` and `request.body` as is.
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse, TransportError>;
+}
+
+/// Sends signed requests, blocking the current thread.
+pub trait BlockingHttpTransport: Send + Sync {
+    /// Send the request to `request.host`, with `request.headers` and `request.body` as is.
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse, TransportError>;
+}
+
+/// Full url of a request.
+pub fn get_url(request: &HttpRequest) -> String {
+    format!(
+        "{}{}",
+        request.host.as_deref().unwrap_or_default(),
+        request.path
+    )
+}
+
+type Handler = dyn Fn(&HttpRequest) -> Result<HttpResponse, TransportError> + Send + Sync;
+
+/// A transport answering requests in memory, for tests.
+pub struct InMemoryTransport {
+    handler: Box<Handler>,
+    requests: Mutex<Vec<HttpRequest>>,
+}
+
+impl InMemoryTransport {
+    /// Answer every request with `handler`.
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: Fn(&HttpRequest) -> Result<HttpResponse, TransportError> + Send + Sync + 'static,
+    {
+        Self {
+            handler: Box::new(handler),
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Answer requests with `responses` in order, then with the last one, without any
+    /// response every request fails.
+    pub fn with_responses(responses: Vec<(u16, &'static str)>) -> Self {
+        let count = std::sync::atomic::AtomicUsize::new(0);
+        Self::new(move |request| {
+            let index = count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let Some((status, body)) = responses.get(index).or(responses.last()).copied() else {
+                return Err(TransportError::new(
+                    TransportErrorKind::Other,
+                    "no response is scripted",
+                ));
+            };
+            Ok(HttpResponse {
+                host: request.host.clone().unwrap_or_default(),
+                status,
+                headers: Default::default(),
+                body: body.as_bytes().to_vec(),
+            })
+        })
+    }
+
+    /// Requests received so far.
+    pub fn get_requests(&self) -> Vec<HttpRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn handle(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+        let response = (self.handler)(&request);
+        self.requests.lock().unwrap().push(request);
+        response
+    }
+}
+
+#[async_trait]
+impl HttpTransport for InMemoryTransport {
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+        self.handle(request)
+    }
+}
+
+impl BlockingHttpTransport for InMemoryTransport {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+        self.handle(request)
+    }
+}
+
+#[cfg(feature = "reqwest")]
+pub use self::reqwest_transport::ReqwestTransport;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::Method;
+
+    fn new_request() -> HttpRequest {
+        HttpRequest::new(Method::GET, "/v3/certificates", None)
+    }
+
+    #[test]
+    fn test_scripted_responses() {
+        let transport = InMemoryTransport::with_responses(vec![(500, "busy"), (200, "{}")]);
+        for status in [500, 200, 200] {
+            let response = BlockingHttpTransport::send(&transport, new_request()).unwrap();
+            assert_eq!(status, response.status);
+        }
+        assert_eq!(3, transport.get_requests().len());
+
+        let transport = InMemoryTransport::with_responses(Vec::new());
+        let error = BlockingHttpTransport::send(&transport, new_request()).unwrap_err();
+        assert_eq!(TransportErrorKind::Other, error.get_kind());
+    }
+}
+
+#[cfg(feature = "reqwest")]
+mod reqwest_transport {
+    use super::*;
+    use crate::{cons::get_user_agent, header::HttpHeaders};
+    use std::time::Duration;
+
+    fn from_reqwest(e: reqwest::Error) -> TransportError {
+        let kind = if e.is_connect() {
+            TransportErrorKind::Connect
+        } else if e.is_timeout() {
+            TransportErrorKind::Timeout
+        } else if is_connection_reset(&e) {
+            TransportErrorKind::ConnectionReset
+        } else {
+            TransportErrorKind::Other
+        };
+        TransportError::new(kind, e)
+    }
+
+    fn is_connection_reset(e: &(dyn std::error::Error + 'static)) -> bool {
+        let mut source = Some(e);
+        while let Some(e) = source {
+            if let Some(e) = e.downcast_ref::<std::io::Error>() {
+                return matches!(
+                    e.kind(),
+                    std::io::ErrorKind::ConnectionReset
+                        | std::io::ErrorKind::ConnectionAborted
+                        | std::io::ErrorKind::BrokenPipe
+                        | std::io::ErrorKind::UnexpectedEof
+                );
+            }
+            source = e.source();
+        }
+        false
+    }
+
+    /// Applies the options of a [ReqwestTransportBuilder] to an async or blocking client builder.
+    macro_rules! configure {
+        ($options:expr, $builder:expr) => {{
+            let options = $options;
+            let mut builder = $builder
+                .user_agent(options.get_user_agent())
+                .https_only(options.https_only);
+            if let Some(timeout) = options.connect_timeout {
+                builder = builder.connect_timeout(timeout);
+            }
+            if let Some(timeout) = options.timeout {
+                builder = builder.timeout(timeout);
+            }
+            if let Some(timeout) = options.pool_idle_timeout {
+                builder = builder.pool_idle_timeout(timeout);
+            }
+            if let Some(max) = options.pool_max_idle_per_host {
+                builder = builder.pool_max_idle_per_host(max);
+            }
+            for proxy in &options.proxies {
+                builder = builder.proxy(proxy.clone());
+            }
+            #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+            {
+                for certificate in &options.root_certificates {
+                    builder = builder.add_root_certificate(certificate.clone());
+                }
+                if options.pinned {
+                    builder = builder.tls_built_in_root_certs(false);
+                }
+            }
+            #[cfg(feature = "rustls-tls")]
+            {
+                builder = builder.use_rustls_tls();
+            }
+            builder.build().map_err(|e| e.to_string())
+        }};
+    }
+
+    const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Options of the `reqwest` clients of a [ReqwestTransport].
+    ///
+    /// Clients are https only, with a connect timeout of 10 seconds and a request timeout of
+    /// 30 seconds by default, so that a hung connection fails as a retryable timeout.
+    ///
+    /// The tls backend is chosen with the `native-tls` (default) or `rustls-tls` feature,
+    /// `rustls-tls` wins when both are enabled.
+    #[derive(Debug, Clone)]
+    pub struct ReqwestTransportBuilder {
+        connect_timeout: Option<Duration>,
+        timeout: Option<Duration>,
+        pool_idle_timeout: Option<Duration>,
+        pool_max_idle_per_host: Option<usize>,
+        proxies: Vec<reqwest::Proxy>,
+        #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+        root_certificates: Vec<reqwest::Certificate>,
+        #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+        pinned: bool,
+        https_only: bool,
+        user_agent_suffix: Option<String>,
+    }
+
+    impl Default for ReqwestTransportBuilder {
+        fn default() -> Self {
+            Self {
+                connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+                timeout: Some(DEFAULT_TIMEOUT),
+                pool_idle_timeout: None,
+                pool_max_idle_per_host: None,
+                proxies: Vec::new(),
+                #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+                root_certificates: Vec::new(),
+                #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+                pinned: false,
+                https_only: true,
+                user_agent_suffix: None,
+            }
+        }
+    }
+
+    impl ReqwestTransportBuilder {
+        pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+            self.connect_timeout = Some(timeout);
+            self
+        }
+
+        /// Default timeout of a request, overridden by the timeout of [HttpRequest].
+        pub fn with_timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = Some(timeout);
+            self
+        }
+
+        /// Close idle pooled connections after `timeout`.
+        pub fn with_pool_idle_timeout(mut self, timeout: Duration) -> Self {
+            self.pool_idle_timeout = Some(timeout);
+            self
+        }
+
+        /// Keep at most `max` idle connections per host.
+        pub fn with_pool_max_idle_per_host(mut self, max: usize) -> Self {
+            self.pool_max_idle_per_host = Some(max);
+            self
+        }
+
+        /// Send every request through the proxy at `url`, e.g. `http://10.0.0.1:3128`.
+        pub fn with_proxy(self, url: &str) -> Result<Self, String> {
+            self.with_reqwest_proxy(reqwest::Proxy::all(url))
+        }
+
+        /// Send `http` requests through the proxy at `url`.
+        pub fn with_http_proxy(self, url: &str) -> Result<Self, String> {
+            self.with_reqwest_proxy(reqwest::Proxy::http(url))
+        }
+
+        /// Send `https` requests through the proxy at `url`.
+        pub fn with_https_proxy(self, url: &str) -> Result<Self, String> {
+            self.with_reqwest_proxy(reqwest::Proxy::https(url))
+        }
+
+        fn with_reqwest_proxy(
+            mut self,
+            proxy: reqwest::Result<reqwest::Proxy>,
+        ) -> Result<Self, String> {
+            self.proxies.push(proxy.map_err(|e| e.to_string())?);
+            Ok(self)
+        }
+
+        /// Trust the pem encoded CA certificate in addition to the built-in roots.
+        #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+        pub fn with_root_certificate(mut self, pem: &[u8]) -> Result<Self, String> {
+            // rustls only parses the pem when the client is built, fail here for both backends.
+            let (_, parsed) = x509_parser::pem::parse_x509_pem(pem)
+                .map_err(|e| format!("invalid root certificate: {}", e))?;
+            parsed
+                .parse_x509()
+                .map_err(|e| format!("invalid root certificate: {}", e))?;
+            let certificate = reqwest::Certificate::from_pem(pem).map_err(|e| e.to_string())?;
+            self.root_certificates.push(certificate);
+            Ok(self)
+        }
+
+        /// Only trust the pem encoded CA certificate issuing the certificates of
+        /// `api.mch.weixin.qq.com`, the built-in roots are disabled.
+        #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+        pub fn with_pinned_issuer(self, pem: &[u8]) -> Result<Self, String> {
+            let mut builder = self.with_root_certificate(pem)?;
+            builder.pinned = true;
+            Ok(builder)
+        }
+
+        /// Refuse to send requests over plain `http`, `true` by default.
+        pub fn with_https_only(mut self, https_only: bool) -> Self {
+            self.https_only = https_only;
+            self
+        }
+
+        /// Append `suffix` to the user agent, e.g. `my-shop/1.2`.
+        pub fn with_user_agent_suffix(mut self, suffix: impl AsRef<str>) -> Self {
+            self.user_agent_suffix = Some(suffix.as_ref().to_string());
+            self
+        }
+
+        fn get_user_agent(&self) -> String {
+            match &self.user_agent_suffix {
+                Some(suffix) => format!("{} {}", get_user_agent(), suffix),
+                None => get_user_agent(),
+            }
+        }
+
+        pub fn build_client(&self) -> Result<reqwest::Client, String> {
+            configure!(self, reqwest::Client::builder())
+        }
+
+        #[cfg(feature = "blocking")]
+        pub fn build_blocking_client(&self) -> Result<reqwest::blocking::Client, String> {
+            configure!(self, reqwest::blocking::Client::builder())
+        }
+
+        pub fn build(self) -> Result<ReqwestTransport, String> {
+            let client = self.build_client()?;
+            Ok(ReqwestTransport {
+                client,
+                #[cfg(feature = "blocking")]
+                blocking_client: std::sync::OnceLock::new(),
+                #[cfg(feature = "blocking")]
+                options: self,
+            })
+        }
+    }
+
+    /// Transport with a `reqwest` client, which may be shared to reuse its connection pool.
+    pub struct ReqwestTransport {
+        client: reqwest::Client,
+        /// Built on first use, as a blocking client must not be created or dropped in a runtime.
+        #[cfg(feature = "blocking")]
+        blocking_client: std::sync::OnceLock<reqwest::blocking::Client>,
+        /// Options of the blocking client.
+        #[cfg(feature = "blocking")]
+        options: ReqwestTransportBuilder,
+    }
+
+    impl ReqwestTransport {
+        pub fn builder() -> ReqwestTransportBuilder {
+            ReqwestTransportBuilder::default()
+        }
+
+        /// Create a transport with the default options of [ReqwestTransportBuilder].
+        pub fn try_default() -> Result<Self, String> {
+            Self::builder().build()
+        }
+
+        /// Create a transport with a client built elsewhere.
+        pub fn new(client: reqwest::Client) -> Self {
+            Self {
+                client,
+                #[cfg(feature = "blocking")]
+                blocking_client: std::sync::OnceLock::new(),
+                #[cfg(feature = "blocking")]
+                options: ReqwestTransportBuilder::default(),
+            }
+        }
+
+        /// Use `client` for blocking calls.
+        #[cfg(feature = "blocking")]
+        pub fn with_blocking_client(mut self, client: reqwest::blocking::Client) -> Self {
+            self.blocking_client = std::sync::OnceLock::from(client);
+            self
+        }
+    }
+
+    #[async_trait]
+    impl HttpTransport for ReqwestTransport {
+        async fn send(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+            let mut builder = self
+                .client
+                .request(request.method.clone(), get_url(&request));
+            for (name, value) in request.headers.iter() {
+                builder = builder.header(name, value);
+            }
+            if let Some(timeout) = request.timeout {
+                builder = builder.timeout(timeout);
+            }
+            if let Some(body) = request.body {
+                builder = builder.body(body);
+            }
+            let response = builder.send().await.map_err(from_reqwest)?;
+            let status = response.status().as_u16();
+            let headers = HttpHeaders::from(response.headers());
+            let body = response.bytes().await.map_err(from_reqwest)?;
+            Ok(HttpResponse {
+                host: request.host.unwrap_or_default(),
+                status,
+                headers,
+                body: body.to_vec(),
+            })
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    impl BlockingHttpTransport for ReqwestTransport {
+        fn send(&self, request: HttpRequest) -> Result<HttpResponse, TransportError> {
+            let client = match self.blocking_client.get() {
+                Some(client) => client,
+                None => {
+                    let client = self
+                        .options
+                        .build_blocking_client()
+                        .map_err(|e| TransportError::new(TransportErrorKind::Other, e))?;
+                    self.blocking_client.get_or_init(|| client)
+                }
+            };
+            let mut builder = client.request(request.method.clone(), get_url(&request));
+            for (name, value) in request.headers.iter() {
+                builder = builder.header(name, value);
+            }
+            if let Some(timeout) = request.timeout {
+                builder = builder.timeout(timeout);
+            }
+            if let Some(body) = request.body {
+                builder = builder.body(body);
+            }
+            let response = builder.send().map_err(from_reqwest)?;
+            let status = response.status().as_u16();
+            let headers = HttpHeaders::from(response.headers());
+            let body = response.bytes().map_err(from_reqwest)?;
+            Ok(HttpResponse {
+                host: request.host.unwrap_or_default(),
+                status,
+                headers,
+                body: body.to_vec(),
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use http::Method;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        fn plain_http_transport() -> ReqwestTransport {
+            ReqwestTransport::builder()
+                .with_https_only(false)
+                .build()
+                .unwrap()
+        }
+
+        #[tokio::test]
+        async fn test_send() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let host = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 8192];
+                let n = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                assert!(request.starts_with("POST /v3/refund/domestic/refunds HTTP/1.1"));
+                assert!(request.contains("x-trace: 42"));
+                let body = r#"{"code":"PARAM_ERROR"}"#;
+                let response = format!(
+                    "HTTP/1.1 400 X\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            });
+
+            let mut request = HttpRequest::new(
+                Method::POST,
+                "/v3/refund/domestic/refunds",
+                Some("{}".into()),
+            );
+            request.host = Some(host.clone());
+            request.headers.insert("X-Trace", "42");
+            let transport = plain_http_transport();
+            let response = HttpTransport::send(&transport, request).await.unwrap();
+            assert_eq!(400, response.status);
+            assert_eq!(host, response.host);
+            assert_eq!(br#"{"code":"PARAM_ERROR"}"#.to_vec(), response.body);
+        }
+
+        #[tokio::test]
+        async fn test_proxy_and_user_agent() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 8192];
+                let n = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                // proxies receive the absolute url.
+                assert!(request.starts_with("GET http://api.mch.weixin.qq.com/v3/certificates"));
+                assert!(request.contains("my-shop/1.2"));
+                let response = "HTTP/1.1 204 X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+                stream.write_all(response.as_bytes()).await.unwrap();
+            });
+
+            let transport = ReqwestTransport::builder()
+                .with_https_only(false)
+                .with_proxy(&proxy)
+                .unwrap()
+                .with_user_agent_suffix("my-shop/1.2")
+                .build()
+                .unwrap();
+            let mut request = HttpRequest::new(Method::GET, "/v3/certificates", None);
+            request.host = Some("http://api.mch.weixin.qq.com".to_string());
+            let response = HttpTransport::send(&transport, request).await.unwrap();
+            assert_eq!(204, response.status);
+        }
+
+        #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+        #[test]
+        fn test_invalid_certificate() {
+            let result = ReqwestTransport::builder().with_pinned_issuer(b"not a certificate");
+            assert!(result.is_err());
+            assert!(crate::http::build_async_http_client(1000, 5000).is_ok());
+        }
+
+        #[tokio::test]
+        async fn test_https_only_by_default() {
+            let mut request = HttpRequest::new(Method::GET, "/v3/certificates", None);
+            request.host = Some("http://127.0.0.1:1".to_string());
+            let transport = ReqwestTransport::try_default().unwrap();
+            let error = HttpTransport::send(&transport, request).await.unwrap_err();
+            assert_eq!(TransportErrorKind::Other, error.get_kind());
+        }
+
+        #[tokio::test]
+        async fn test_connect_error() {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let host = format!("http://{}", listener.local_addr().unwrap());
+            drop(listener);
+
+            let mut request = HttpRequest::new(Method::GET, "/v3/certificates", None);
+            request.host = Some(host);
+            let transport = plain_http_transport();
+            let error = HttpTransport::send(&transport, request).await.unwrap_err();
+            assert_eq!(TransportErrorKind::Connect, error.get_kind());
+        }
+    }
+}
@@ -1,59 +1,323 @@
-use num_bigint_dig::BigUint;
 use security::prelude::*;
+use security::rsa::{RsaAlgorithm, RsaVerifyingKey};
 use std::collections::HashMap;
+use std::sync::Arc;
 
-pub trait Verifier {
+use crate::metrics::{self, VerificationFailure};
+use crate::serial::{SerialNumber, WechatPaySerial};
+
+/// Verifies signatures of WeChat Pay, object safe so that sources can be combined,
+/// see [CompositeVerifier].
+pub trait Verifier: Send + Sync {
     /// A function to verify signature
     fn verify(
         &self,
-        serial_number: impl AsRef<str>,
-        message: impl AsRef<[u8]>,
-        signature: impl AsRef<str>,
+        serial: &WechatPaySerial,
+        message: &[u8],
+        signature: &str,
     ) -> Result<(), String>;
 
-    /// A function to get x509 certificate bytes in `[u8]`
-    fn get_valid_certificate(&self) -> &[u8];
+    /// Whether a key with `serial` is known, a remote verifier which can't tell
+    /// returns `true`.
+    fn has_key(&self, serial: &WechatPaySerial) -> bool {
+        let _ = serial;
+        true
+    }
+}
+
+impl<V: Verifier + ?Sized> Verifier for Box<V> {
+    fn verify(
+        &self,
+        serial: &WechatPaySerial,
+        message: &[u8],
+        signature: &str,
+    ) -> Result<(), String> {
+        (**self).verify(serial, message, signature)
+    }
+
+    fn has_key(&self, serial: &WechatPaySerial) -> bool {
+        (**self).has_key(serial)
+    }
+}
+
+impl<V: Verifier + ?Sized> Verifier for Arc<V> {
+    fn verify(
+        &self,
+        serial: &WechatPaySerial,
+        message: &[u8],
+        signature: &str,
+    ) -> Result<(), String> {
+        (**self).verify(serial, message, signature)
+    }
+
+    fn has_key(&self, serial: &WechatPaySerial) -> bool {
+        (**self).has_key(serial)
+    }
 }
 
-/// Verify with certificates.
-pub struct CertificatesVerifier(HashMap<BigUint, Vec<u8>>);
+/// A platform public key with the serial number of its certificate, parsed once.
+#[derive(Debug, Clone)]
+pub struct VerifyingKey {
+    serial_number: SerialNumber,
+    key: RsaVerifyingKey,
+}
+
+impl VerifyingKey {
+    /// Parse the serial number and public key of a platform certificate in pem.
+    pub fn from_certificate_pem(certificate: impl AsRef<[u8]>) -> Result<Self, String> {
+        let certificate = certificate.as_ref();
+        let serial_number =
+            security::rsa::parse_x509_serial_number(certificate).map_err(|e| e.to_string())?;
+        let key = RsaVerifyingKey::from_x509_pem(certificate).map_err(|e| e.to_string())?;
+        Ok(Self {
+            serial_number: SerialNumber::from_bytes_be(&serial_number),
+            key,
+        })
+    }
+
+    /// Parse a `#pkcs8` platform public key in pem, identified by `serial_number` in hex.
+    pub fn from_public_key_pem(
+        serial_number: impl AsRef<str>,
+        public_key: impl AsRef<str>,
+    ) -> Result<Self, String> {
+        let serial_number = SerialNumber::from_hex(serial_number)?;
+        let key =
+            RsaVerifyingKey::from_public_key_pem(public_key.as_ref()).map_err(|e| e.to_string())?;
+        Ok(Self { serial_number, key })
+    }
+
+    pub fn get_serial_number(&self) -> &SerialNumber {
+        &self.serial_number
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &str) -> Result<(), String> {
+        self.key
+            .verify(&RsaAlgorithm::Sha256withRsa, message, signature)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Verify with platform keys parsed once.
+pub struct CertificatesVerifier(HashMap<SerialNumber, VerifyingKey>);
 
 impl CertificatesVerifier {
     pub fn new() -> Self {
         CertificatesVerifier(HashMap::new())
     }
 
-    pub fn update_certificates(&mut self, certificates: HashMap<BigUint, Vec<u8>>) {
+    /// Replace keys with platform certificates in pem by serial number,
+    /// certificates which can't be parsed are skipped.
+    pub fn update_certificates(&mut self, certificates: HashMap<SerialNumber, Vec<u8>>) {
         self.0.clear();
-        self.0.extend(certificates.into_iter());
+        for (serial_number, certificate) in certificates {
+            let serial = serial_number.to_string();
+            match VerifyingKey::from_certificate_pem(&certificate) {
+                Ok(key) => {
+                    metrics::record_certificate(&serial, &certificate);
+                    self.add_key(key);
+                }
+                Err(e) => error!("Skipped invalid certificate {} for: {}", serial, e),
+            }
+        }
     }
 
-    fn __verify(certificate: &[u8], message: &[u8], signature: &str) -> Result<(), String> {
-        // verify_with_x509_pem(certificate, message, signature)
-        todo!()
+    /// Add a parsed platform key.
+    pub fn add_key(&mut self, key: VerifyingKey) {
+        self.0.insert(key.serial_number.clone(), key);
+    }
+}
+
+impl CertificatesVerifier {
+    fn get_key(&self, serial: &WechatPaySerial) -> Option<&VerifyingKey> {
+        serial
+            .get_certificate_serial_number()
+            .and_then(|serial_number| self.0.get(serial_number))
     }
 }
 
 impl Verifier for CertificatesVerifier {
     fn verify(
         &self,
-        serial_number: impl AsRef<str>,
-        message: impl AsRef<[u8]>,
-        signature: impl AsRef<str>,
+        serial: &WechatPaySerial,
+        message: &[u8],
+        signature: &str,
+    ) -> Result<(), String> {
+        let Some(key) = self.get_key(serial) else {
+            return Err(certificate_not_found(serial));
+        };
+        key.verify(message, signature).inspect_err(|_| {
+            metrics::recorder().increment_verification_failure(VerificationFailure::BadSignature)
+        })
+    }
+
+    fn has_key(&self, serial: &WechatPaySerial) -> bool {
+        self.get_key(serial).is_some()
+    }
+}
+
+fn certificate_not_found(serial: &WechatPaySerial) -> String {
+    error!("Can't found certificate with serial number: {}", serial);
+    metrics::recorder().increment_verification_failure(VerificationFailure::CertificateNotFound);
+    "certificate not found".to_string()
+}
+
+/// Verify with a WeChat Pay public key, which replaces platform certificates for new merchants
+/// and is identified by its id, e.g. `PUB_KEY_ID_0114232134912410000000000000`.
+#[derive(Debug, Clone)]
+pub struct PublicKeyVerifier {
+    key_id: String,
+    key: RsaVerifyingKey,
+}
+
+impl PublicKeyVerifier {
+    /// Parse the `#pkcs8` public key in pem, sent as `key_id` in `Wechatpay-Serial`.
+    pub fn new(key_id: impl AsRef<str>, public_key: impl AsRef<str>) -> Result<Self, String> {
+        let key =
+            RsaVerifyingKey::from_public_key_pem(public_key.as_ref()).map_err(|e| e.to_string())?;
+        Ok(Self {
+            key_id: key_id.as_ref().to_string(),
+            key,
+        })
+    }
+}
+
+impl Verifier for PublicKeyVerifier {
+    fn verify(
+        &self,
+        serial: &WechatPaySerial,
+        message: &[u8],
+        signature: &str,
     ) -> Result<(), String> {
-        let val = BigUint::parse_bytes(serial_number.as_ref().as_bytes(), 16).unwrap();
-        let cert = self.0.get(&val);
-        if cert.is_none() {
-            error!(
-                "Can't found certificate with serial number: {}",
-                serial_number.as_ref()
-            );
-            return Err("certificate not found".to_string());
+        if !self.has_key(serial) {
+            return Err(certificate_not_found(serial));
         }
-        Self::__verify(cert.as_ref().unwrap(), message.as_ref(), signature.as_ref())
+        self.key
+            .verify(&RsaAlgorithm::Sha256withRsa, message, signature)
+            .map_err(|e| e.to_string())
+            .inspect_err(|_| {
+                metrics::recorder()
+                    .increment_verification_failure(VerificationFailure::BadSignature)
+            })
+    }
+
+    fn has_key(&self, serial: &WechatPaySerial) -> bool {
+        serial.get_public_key_id() == Some(self.key_id.as_str())
+    }
+}
+
+/// Verify with the first of several sources holding the key, e.g. platform certificates,
+/// a public key and a remote verifier, tried in the order they were added.
+#[derive(Default)]
+pub struct CompositeVerifier(Vec<Box<dyn Verifier>>);
+
+impl CompositeVerifier {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn get_valid_certificate(&self) -> &[u8] {
-        todo!()
+    /// Try `verifier` after the ones already added.
+    pub fn with_verifier(mut self, verifier: impl Verifier + 'static) -> Self {
+        self.0.push(Box::new(verifier));
+        self
+    }
+}
+
+impl Verifier for CompositeVerifier {
+    /// Succeeds as soon as a source verifies the signature, otherwise fails with the error of
+    /// the last source tried.
+    fn verify(
+        &self,
+        serial: &WechatPaySerial,
+        message: &[u8],
+        signature: &str,
+    ) -> Result<(), String> {
+        let mut result = None;
+        for verifier in self.0.iter().filter(|v| v.has_key(serial)) {
+            match verifier.verify(serial, message, signature) {
+                Ok(()) => return Ok(()),
+                Err(e) => result = Some(Err(e)),
+            }
+        }
+        result.unwrap_or_else(|| Err(certificate_not_found(serial)))
+    }
+
+    fn has_key(&self, serial: &WechatPaySerial) -> bool {
+        self.0.iter().any(|v| v.has_key(serial))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::{RsaSigner, Signer};
+
+    const PLATFORM_KEY: &str = include_str!("../testdata/platform_key.pem");
+    const PLATFORM_CERT: &str = include_str!("../testdata/platform_cert.pem");
+    const PLATFORM_PUBLIC_KEY: &str = include_str!("../testdata/platform_public_key.pem");
+
+    #[test]
+    fn test_verifying_key() {
+        let key = VerifyingKey::from_certificate_pem(PLATFORM_CERT).unwrap();
+        assert_eq!(
+            "2F85E92795F5E7F86BA33C5C365133FDFD5E7E49",
+            key.get_serial_number()
+        );
+        let signer = RsaSigner::new(key.get_serial_number().to_string(), PLATFORM_KEY).unwrap();
+        let signature = signer.sign("1554208460\nnonce\n{}\n").unwrap();
+
+        let mut verifier = CertificatesVerifier::new();
+        verifier.add_key(key);
+        let serial_number = &WechatPaySerial::from(signature.get_serial_number().clone());
+        assert!(verifier
+            .verify(
+                serial_number,
+                b"1554208460\nnonce\n{}\n",
+                signature.get_sign()
+            )
+            .is_ok());
+        assert!(verifier
+            .verify(serial_number, b"tampered", signature.get_sign())
+            .is_err());
+        assert!(verifier
+            .verify(
+                &"not hex".into(),
+                b"1554208460\nnonce\n{}\n",
+                signature.get_sign()
+            )
+            .is_err());
+        assert!(RsaSigner::new("1", "not a key").is_err());
+    }
+
+    #[test]
+    fn test_composite_verifier() {
+        const PUBLIC_KEY_ID: &str = "PUB_KEY_ID_0114232134912410000000000000";
+        let mut certificates = CertificatesVerifier::new();
+        certificates.add_key(VerifyingKey::from_certificate_pem(PLATFORM_CERT).unwrap());
+        let verifier: Box<dyn Verifier> = Box::new(
+            CompositeVerifier::new()
+                .with_verifier(certificates)
+                .with_verifier(PublicKeyVerifier::new(PUBLIC_KEY_ID, PLATFORM_PUBLIC_KEY).unwrap()),
+        );
+
+        let message = b"1554208460\nnonce\n{}\n";
+        let signer =
+            RsaSigner::new("2F85E92795F5E7F86BA33C5C365133FDFD5E7E49", PLATFORM_KEY).unwrap();
+        let signature = signer.sign("1554208460\nnonce\n{}\n").unwrap();
+        for serial_number in ["2f85e92795f5e7f86ba33c5c365133fdfd5e7e49", PUBLIC_KEY_ID] {
+            let serial_number = &WechatPaySerial::new(serial_number);
+            assert!(verifier.has_key(serial_number));
+            assert!(verifier
+                .verify(serial_number, message, signature.get_sign())
+                .is_ok());
+            assert!(verifier
+                .verify(serial_number, b"tampered", signature.get_sign())
+                .is_err());
+        }
+        let unknown = WechatPaySerial::new("PUB_KEY_ID_0000");
+        assert!(!verifier.has_key(&unknown));
+        assert_eq!(
+            Err("certificate not found".to_string()),
+            verifier.verify(&unknown, message, "c2lnbmF0dXJl")
+        );
     }
 }
@@ -0,0 +1,123 @@
+//! Verified notifications on actix-web.
+//!
+//! Register the [NotificationHandler] as `web::Data<NotificationHandler>` and extract a
+//! [VerifiedNotification] in a handler.
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::body::BoxBody;
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::web::{Bytes, Data};
+use actix_web::{FromRequest, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde::de::DeserializeOwned;
+
+use crate::event::Event;
+use crate::header::HttpHeaders;
+use crate::notification::NotificationHandler;
+use crate::prelude::*;
+use crate::router::NotificationReply;
+
+impl ResponseError for NotificationReply {
+    fn status_code(&self) -> StatusCode {
+        self.get_status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.get_status());
+        match self.get_body() {
+            Some(body) => response.content_type("application/json").body(body),
+            None => response.finish(),
+        }
+    }
+}
+
+impl Responder for NotificationReply {
+    type Body = BoxBody;
+
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse {
+        self.error_response()
+    }
+}
+
+/// A verified notification with its resource decrypted as `T`, rejected with a
+/// [NotificationReply].
+#[derive(Debug)]
+pub struct VerifiedNotification<T>(pub Event<T>);
+
+impl<T: DeserializeOwned + 'static> FromRequest for VerifiedNotification<T> {
+    type Error = NotificationReply;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(request: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let handler = request.app_data::<Data<NotificationHandler>>().cloned();
+        let headers = request
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+            .collect::<HttpHeaders>();
+        let body = Bytes::from_request(request, payload);
+        Box::pin(async move {
+            let Some(handler) = handler else {
+                error!("No `Data<NotificationHandler>` is registered for notifications");
+                return Err(NotificationReply::fail(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "notification handler is not configured",
+                ));
+            };
+            let body = body
+                .await
+                .map_err(|e| NotificationReply::fail(StatusCode::BAD_REQUEST, e.to_string()))?;
+            let notification = super::verify(&handler, &headers, &body)?;
+            super::into_event(notification).map(VerifiedNotification)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::EventType;
+    use crate::router::tests::{new_handler, new_request, Refund};
+    use actix_web::test::TestRequest;
+
+    fn to_test_request(request: http::Request<bytes::Bytes>) -> TestRequest {
+        let mut test_request = TestRequest::post().uri(&request.uri().to_string());
+        for (name, value) in request.headers() {
+            test_request = test_request.insert_header((name.clone(), value.clone()));
+        }
+        test_request.set_payload(request.into_body())
+    }
+
+    #[tokio::test]
+    async fn test_extractor() {
+        let handler = Data::new(new_handler());
+        let refund = r#"{"out_refund_no":"R1"}"#;
+        let (request, mut payload) = to_test_request(new_request("REFUND.SUCCESS", refund))
+            .app_data(handler.clone())
+            .to_http_parts();
+        let notification = VerifiedNotification::<Refund>::from_request(&request, &mut payload)
+            .await
+            .unwrap();
+        assert_eq!(EventType::RefundSuccess, notification.0.event_type);
+        assert_eq!("R1", notification.0.resource.out_refund_no);
+
+        let mut tampered = new_request("REFUND.SUCCESS", refund);
+        *tampered.body_mut() = bytes::Bytes::from_static(b"{}");
+        let (request, mut payload) = to_test_request(tampered).app_data(handler).to_http_parts();
+        let reply = VerifiedNotification::<Refund>::from_request(&request, &mut payload)
+            .await
+            .err()
+            .unwrap();
+        let response = reply.error_response();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        let (request, mut payload) =
+            to_test_request(new_request("REFUND.SUCCESS", refund)).to_http_parts();
+        let reply = VerifiedNotification::<Refund>::from_request(&request, &mut payload)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, reply.get_status());
+    }
+}
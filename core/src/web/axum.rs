@@ -0,0 +1,247 @@
+//! Verified notifications on axum.
+//!
+//! Either extract a [VerifiedNotification] in a handler, with the [NotificationHandler] in the
+//! router state, or verify all requests of a route with [NotificationLayer] and take the
+//! [Notification](crate::notification::Notification) with `Extension<Notification>`.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::async_trait;
+use axum::body::{Body, Bytes, HttpBody};
+use axum::extract::{FromRef, FromRequest};
+use axum::http::{Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::BoxError;
+use http_body::{LengthLimitError, Limited};
+use serde::de::DeserializeOwned;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::event::Event;
+use crate::header::HttpHeaders;
+use crate::notification::NotificationHandler;
+use crate::router::NotificationReply;
+
+impl IntoResponse for NotificationReply {
+    fn into_response(self) -> Response {
+        let (parts, body) = self.into_http_response().into_parts();
+        Response::from_parts(parts, axum::body::boxed(axum::body::Full::new(body)))
+    }
+}
+
+/// A verified notification with its resource decrypted as `T`, rejected with a
+/// [NotificationReply].
+#[derive(Debug)]
+pub struct VerifiedNotification<T>(pub Event<T>);
+
+#[async_trait]
+impl<S, B, T> FromRequest<S, B> for VerifiedNotification<T>
+where
+    Arc<NotificationHandler>: FromRef<S>,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+    T: DeserializeOwned,
+{
+    type Rejection = NotificationReply;
+
+    async fn from_request(request: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let handler = Arc::<NotificationHandler>::from_ref(state);
+        let headers = HttpHeaders::from(request.headers());
+        let body = Bytes::from_request(request, state)
+            .await
+            .map_err(|e| NotificationReply::fail(e.status(), e.body_text()))?;
+        let notification = super::verify(&handler, &headers, &body)?;
+        super::into_event(notification).map(VerifiedNotification)
+    }
+}
+
+/// Default limit of the body read by [NotificationLayer], notifications are a few KiB.
+pub const DEFAULT_BODY_LIMIT: usize = 64 * 1024;
+
+/// Verifies requests before the inner service, which finds the
+/// [Notification](crate::notification::Notification) in the request
+/// extensions, rejected requests are replied with a [NotificationReply].
+#[derive(Clone)]
+pub struct NotificationLayer {
+    handler: Arc<NotificationHandler>,
+    body_limit: usize,
+}
+
+impl NotificationLayer {
+    pub fn new(handler: impl Into<Arc<NotificationHandler>>) -> Self {
+        Self {
+            handler: handler.into(),
+            body_limit: DEFAULT_BODY_LIMIT,
+        }
+    }
+
+    /// Reply `413 Payload Too Large` to bodies longer than `limit` bytes, see
+    /// [DEFAULT_BODY_LIMIT].
+    pub fn with_body_limit(mut self, limit: usize) -> Self {
+        self.body_limit = limit;
+        self
+    }
+}
+
+impl<S> Layer<S> for NotificationLayer {
+    type Service = NotificationService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        NotificationService {
+            handler: self.handler.clone(),
+            body_limit: self.body_limit,
+            inner,
+        }
+    }
+}
+
+/// Service of [NotificationLayer].
+#[derive(Clone)]
+pub struct NotificationService<S> {
+    handler: Arc<NotificationHandler>,
+    body_limit: usize,
+    inner: S,
+}
+
+impl<S> Service<Request<Body>> for NotificationService<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        // the ready service handles this request, a clone waits for the next one.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let handler = self.handler.clone();
+        let body_limit = self.body_limit;
+        Box::pin(async move {
+            let (mut parts, body) = request.into_parts();
+            let body = match hyper::body::to_bytes(Limited::new(body, body_limit)).await {
+                Ok(body) => body,
+                Err(e) => {
+                    let status = if e.is::<LengthLimitError>() {
+                        StatusCode::PAYLOAD_TOO_LARGE
+                    } else {
+                        StatusCode::BAD_REQUEST
+                    };
+                    let reply = NotificationReply::fail(status, e.to_string());
+                    return Ok(reply.into_response());
+                }
+            };
+            let headers = HttpHeaders::from(&parts.headers);
+            match super::verify(&handler, &headers, &body) {
+                Ok(notification) => {
+                    parts.extensions.insert(notification);
+                    inner
+                        .call(Request::from_parts(parts, Body::from(body)))
+                        .await
+                }
+                Err(reply) => Ok(reply.into_response()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::EventType;
+    use crate::notification::Notification;
+    use crate::router::tests::{new_handler, new_request, Refund};
+    use axum::routing::post;
+    use axum::{Extension, Router};
+
+    async fn send(router: &mut Router, request: Request<Bytes>) -> (StatusCode, Bytes) {
+        let response = router.call(request.map(Body::from)).await.unwrap();
+        let status = response.status();
+        (status, hyper::body::to_bytes(response).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_extractor() {
+        let handler = Arc::new(new_handler());
+        let mut router = Router::new()
+            .route(
+                "/notify",
+                post(|notification: VerifiedNotification<Refund>| async move {
+                    assert_eq!(EventType::RefundSuccess, notification.0.event_type);
+                    assert_eq!("R1", notification.0.resource.out_refund_no);
+                    NotificationReply::success()
+                }),
+            )
+            .with_state(handler);
+
+        let refund = r#"{"out_refund_no":"R1"}"#;
+        let (status, _) = send(&mut router, new_request("REFUND.SUCCESS", refund)).await;
+        assert_eq!(StatusCode::NO_CONTENT, status);
+
+        let (status, _) = send(&mut router, new_request("REFUND.SUCCESS", "{}")).await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status);
+
+        let mut request = new_request("REFUND.SUCCESS", refund);
+        request.headers_mut().remove("Wechatpay-Signature");
+        let (status, body) = send(&mut router, request).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!(
+            r#"{"code":"FAIL","message":"missing http header Wechatpay-Signature"}"#,
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn test_layer() {
+        let mut router = Router::new()
+            .route(
+                "/notify",
+                post(
+                    |Extension(notification): Extension<Notification>| async move {
+                        assert_eq!("REFUND.CLOSED", notification.event_type);
+                        NotificationReply::success()
+                    },
+                ),
+            )
+            .layer(NotificationLayer::new(new_handler()));
+
+        let request = new_request("REFUND.CLOSED", "{}");
+        let (status, _) = send(&mut router, request).await;
+        assert_eq!(StatusCode::NO_CONTENT, status);
+
+        let mut request = new_request("REFUND.CLOSED", "{}");
+        *request.body_mut() = Bytes::from_static(b"{}");
+        let (status, body) = send(&mut router, request).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert!(body.starts_with(br#"{"code":"FAIL""#));
+    }
+
+    #[tokio::test]
+    async fn test_layer_body_limit() {
+        let layer = NotificationLayer::new(new_handler());
+        let mut router = Router::new()
+            .route("/notify", post(|| async { NotificationReply::success() }))
+            .layer(layer.clone().with_body_limit(16));
+
+        let (status, body) = send(&mut router, new_request("REFUND.CLOSED", "{}")).await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, status);
+        assert!(body.starts_with(br#"{"code":"FAIL""#));
+
+        let mut request = new_request("REFUND.CLOSED", "{}");
+        *request.body_mut() = Bytes::from(vec![b' '; DEFAULT_BODY_LIMIT + 1]);
+        let mut router = Router::new()
+            .route("/notify", post(|| async { NotificationReply::success() }))
+            .layer(layer);
+        let (status, _) = send(&mut router, request).await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, status);
+    }
+}
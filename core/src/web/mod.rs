@@ -0,0 +1,37 @@
+//! Verified notification endpoints on web frameworks, see the `axum` and `actix-web` features.
+//!
+//! Signatures are verified over the exact body bytes received, failures are replied as
+//! [NotificationReply] so that WeChat Pay retries.
+use http::StatusCode;
+use serde::de::DeserializeOwned;
+
+use crate::event::Event;
+use crate::header::HttpHeaders;
+use crate::notification::{Notification, NotificationHandler, NotificationRequest};
+use crate::router::NotificationReply;
+
+#[cfg(feature = "actix-web")]
+pub mod actix;
+#[cfg(feature = "axum")]
+pub mod axum;
+
+/// Verify and decrypt a notification, rejected requests are replied `400`.
+pub(crate) fn verify(
+    handler: &NotificationHandler,
+    headers: &HttpHeaders,
+    body: &[u8],
+) -> Result<Notification, NotificationReply> {
+    NotificationRequest::from_parts(headers, body)
+        .and_then(|request| handler.parse_raw(request))
+        .map_err(|e| NotificationReply::fail(StatusCode::BAD_REQUEST, e))
+}
+
+/// Deserialize the resource of a verified notification, failures are replied `500` so that
+/// the notification is retried once the type is fixed.
+pub(crate) fn into_event<T: DeserializeOwned>(
+    notification: Notification,
+) -> Result<Event<T>, NotificationReply> {
+    notification
+        .into_event()
+        .map_err(|e| NotificationReply::fail(StatusCode::INTERNAL_SERVER_ERROR, e))
+}
@@ -0,0 +1,79 @@
+//! Per call cost removed by parsing keys once: signing with a pkcs8 pem parsed on every call
+//! and verifying with a certificate parsed and re-encoded to a public key pem on every call,
+//! against the parsed [RsaSigningKey] and [RsaVerifyingKey].
+//!
+//! Run with `cargo bench -p security --features __hash,__rsa`.
+use std::hint::black_box;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rsa::pkcs8::{DecodePublicKey, EncodePublicKey, LineEnding};
+use rsa::RsaPublicKey;
+use security::rsa::{RsaAlgorithm, RsaSigningKey, RsaVerifyingKey};
+
+const PRIVATE_KEY: &str = include_str!("../../core/testdata/platform_key.pem");
+const CERTIFICATE: &[u8] = include_bytes!("../../core/testdata/platform_cert.pem");
+const MESSAGE: &str = "GET\n/v3/certificates\n1554208460\n593BEC0C930BF1AFEB40B4A08C8FB242\n\n";
+
+/// The public key of `CERTIFICATE` as verification used to get it before keys were parsed
+/// once: the certificate parsed, its key decoded and re-encoded to a pem parsed again.
+fn reencoded_public_key() -> RsaVerifyingKey {
+    let (_, pem) = x509_parser::pem::parse_x509_pem(CERTIFICATE).unwrap();
+    let x509 = pem.parse_x509().unwrap();
+    let key = RsaPublicKey::from_public_key_der(x509.public_key().raw).unwrap();
+    let pem = key.to_public_key_pem(LineEnding::CRLF).unwrap();
+    RsaVerifyingKey::from_public_key_pem(&pem).unwrap()
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    group.bench_function("pkcs8 pem", |b| {
+        b.iter(|| RsaSigningKey::from_pkcs8_pem(black_box(PRIVATE_KEY)).unwrap())
+    });
+    group.bench_function("x509 pem, re-encoded", |b| b.iter(reencoded_public_key));
+    group.finish();
+}
+
+fn sign(c: &mut Criterion) {
+    let algorithm = RsaAlgorithm::Sha256withRsa;
+    let key = RsaSigningKey::from_pkcs8_pem(PRIVATE_KEY).unwrap();
+    let mut group = c.benchmark_group("sign");
+    group.measurement_time(Duration::from_secs(15));
+    group.bench_function("pem per call", |b| {
+        b.iter(|| {
+            RsaSigningKey::from_pkcs8_pem(PRIVATE_KEY)
+                .unwrap()
+                .sign(&algorithm, black_box(MESSAGE))
+                .unwrap()
+        })
+    });
+    group.bench_function("parsed key", |b| {
+        b.iter(|| key.sign(&algorithm, black_box(MESSAGE)).unwrap())
+    });
+    group.finish();
+}
+
+fn verify(c: &mut Criterion) {
+    let algorithm = RsaAlgorithm::Sha256withRsa;
+    let signature = algorithm.sign(MESSAGE, PRIVATE_KEY).unwrap();
+    let key = RsaVerifyingKey::from_x509_pem(CERTIFICATE).unwrap();
+    let mut group = c.benchmark_group("verify");
+    group.measurement_time(Duration::from_secs(10));
+    group.bench_function("x509 per call, re-encoded", |b| {
+        b.iter(|| {
+            reencoded_public_key()
+                .verify(&algorithm, black_box(MESSAGE), &signature)
+                .unwrap()
+        })
+    });
+    group.bench_function("parsed key", |b| {
+        b.iter(|| {
+            key.verify(&algorithm, black_box(MESSAGE), &signature)
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse, sign, verify);
+criterion_main!(benches);
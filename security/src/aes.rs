@@ -1,5 +1,22 @@
 use crate::prelude::*;
-use aes_gcm::{aead::heapless::Vec, AeadInPlace, Aes256Gcm, KeyInit, Nonce};
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+
+/// Length in bytes of the nonce of aes-256 gcm.
+const NONCE_LENGTH: usize = 12;
+
+fn new_cipher(key: &[u8], nonce: &[u8]) -> Result<Aes256Gcm, String> {
+    if nonce.len() != NONCE_LENGTH {
+        error!("Invalid length for nonce size: {}", nonce.len());
+        return Err("invalid nonce len".to_string());
+    }
+    Aes256Gcm::new_from_slice(key).map_err(|_| {
+        error!("Invalid length for key size: {}", key.len());
+        "invalid key len".to_string()
+    })
+}
 
 /// A function to execute aes-256 gcm alg
 pub fn decrypt(
@@ -8,29 +25,36 @@ pub fn decrypt(
     nonce: impl AsRef<[u8]>,
     cipher_text: impl AsRef<[u8]>,
 ) -> Result<String, String> {
-    let cipher = match Aes256Gcm::new_from_slice(key.as_ref()) {
-        Ok(key) => key,
-        Err(_) => {
-            error!("Invalid length for key size: {}", key.as_ref().len());
-            return Err("invalid key len".to_string());
-        }
+    let cipher = new_cipher(key.as_ref(), nonce.as_ref())?;
+    let payload = Payload {
+        msg: cipher_text.as_ref(),
+        aad: associated_data.as_ref(),
     };
-
-    let mut buffer: Vec<u8, 128> = Vec::new();
-    buffer
-        .extend_from_slice(cipher_text.as_ref())
-        .map_err(|e| {
-            error!("Failed to extend buffer for: {:?}", e);
-            "extend buffer error".to_string()
-        })?;
-    let nonce = Nonce::from_slice(nonce.as_ref());
-    cipher
-        .decrypt_in_place(nonce, associated_data.as_ref(), &mut buffer)
+    let buffer = cipher
+        .decrypt(Nonce::from_slice(nonce.as_ref()), payload)
         .map_err(|e| {
             error!("Failed to decrypt cipher text for: {:?}", e);
             "decrypt error".to_string()
         })?;
-    let buffer = buffer.to_vec();
-    let buffer = unsafe { String::from_utf8_unchecked(buffer) };
-    Ok(buffer)
+    String::from_utf8(buffer).map_err(|_| "decrypted text is not valid utf-8".to_string())
+}
+
+/// Encrypt with aes-256 gcm, the tag is appended to the cipher text.
+pub fn encrypt(
+    key: impl AsRef<[u8]>,
+    associated_data: impl AsRef<[u8]>,
+    nonce: impl AsRef<[u8]>,
+    plain_text: impl AsRef<[u8]>,
+) -> Result<Vec<u8>, String> {
+    let cipher = new_cipher(key.as_ref(), nonce.as_ref())?;
+    let payload = Payload {
+        msg: plain_text.as_ref(),
+        aad: associated_data.as_ref(),
+    };
+    cipher
+        .encrypt(Nonce::from_slice(nonce.as_ref()), payload)
+        .map_err(|e| {
+            error!("Failed to encrypt plain text for: {:?}", e);
+            "encrypt error".to_string()
+        })
 }
@@ -3,6 +3,8 @@ pub(crate) mod error;
 #[cfg(feature = "__hash")]
 pub mod hash;
 pub(crate) mod macros;
+pub mod redact;
+pub mod secret;
 pub mod util;
 
 #[cfg(all(feature = "__hash", feature = "__rsa"))]
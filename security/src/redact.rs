@@ -0,0 +1,93 @@
+//! Redaction of secrets and personal data in logs, `Debug` and `Display`.
+//!
+//! Secrets (keys, signatures, tokens, decrypted payloads) are never printed, personal data
+//! (openid, names, phone numbers) is masked. The `unsafe-debug` feature prints both as is,
+//! it is meant for local troubleshooting and must not be enabled in production.
+use std::fmt::{Debug, Display, Formatter, Result};
+
+/// Printed in place of a secret.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Whether the `unsafe-debug` feature is enabled.
+pub const UNSAFE_DEBUG: bool = cfg!(feature = "unsafe-debug");
+
+/// A secret which is printed as [REDACTED].
+#[derive(Clone, Copy)]
+pub struct Secret<T>(pub T);
+
+impl<T: Display> Display for Secret<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        if UNSAFE_DEBUG {
+            self.0.fmt(f)
+        } else {
+            f.write_str(REDACTED)
+        }
+    }
+}
+
+impl<T: Display> Debug for Secret<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// Personal data which is printed masked, e.g. `o4G***XXX`.
+#[derive(Clone, Copy)]
+pub struct Personal<T>(pub T);
+
+impl<T: AsRef<str>> Display for Personal<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        if UNSAFE_DEBUG {
+            f.write_str(self.0.as_ref())
+        } else {
+            f.write_str(&mask(self.0.as_ref()))
+        }
+    }
+}
+
+impl<T: AsRef<str>> Debug for Personal<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// Keep the first and last 3 characters of values long enough to stay unidentifiable.
+pub fn mask(value: &str) -> String {
+    let chars = value.chars().collect::<Vec<_>>();
+    if chars.len() < 10 {
+        return "***".to_string();
+    }
+    let head = chars[..3].iter().collect::<String>();
+    let tail = chars[chars.len() - 3..].iter().collect::<String>();
+    format!("{}***{}", head, tail)
+}
+
+/// Whether the value of a http header is a secret.
+pub fn is_secret_header(name: &str) -> bool {
+    [
+        "Authorization",
+        "Wechatpay-Signature",
+        "Cookie",
+        "Set-Cookie",
+    ]
+    .iter()
+    .any(|secret| secret.eq_ignore_ascii_case(name))
+}
+
+#[cfg(all(test, not(feature = "unsafe-debug")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(REDACTED, Secret("signature").to_string());
+        assert_eq!(REDACTED, format!("{:?}", Secret("signature")));
+        assert_eq!(
+            "o4G***XXX",
+            Personal("o4GgauInH_RCEdvrrNGrntXDuXXX").to_string()
+        );
+        assert_eq!("***", Personal("Alice").to_string());
+        assert!(is_secret_header("authorization"));
+        assert!(!is_secret_header("Wechatpay-Serial"));
+    }
+}
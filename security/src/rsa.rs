@@ -2,7 +2,7 @@
 use ::rsa::RsaPrivateKey;
 use anyhow::Error;
 use log::*;
-use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePublicKey, LineEnding};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
 use rsa::{hash::Hash, PaddingScheme, PublicKey, RsaPublicKey};
 use x509_parser::pem::parse_x509_pem;
 
@@ -45,21 +45,59 @@ pub enum RsaAlgorithm {
 }
 
 impl RsaAlgorithm {
-    /// Calculate signature
+    fn digest(&self, src: &[u8]) -> (Vec<u8>, Hash) {
+        match self {
+            RsaAlgorithm::Sha256withRsa => (HashAlg::Sha256.hash(src), Hash::SHA2_256),
+            RsaAlgorithm::Sha512withRsa => (HashAlg::Sha512.hash(src), Hash::SHA2_512),
+        }
+    }
+
+    /// Calculate signature, parsing the key first, see [RsaSigningKey] to parse it once.
     pub fn sign(
         &self,
         src: impl AsRef<[u8]>,
         private_key: impl AsRef<str>,
     ) -> Result<String, Error> {
-        let pri_key = RsaPrivateKey::from_pkcs8_pem(private_key.as_ref()).map_err(|e| {
+        RsaSigningKey::from_pkcs8_pem(private_key.as_ref())?.sign(self, src)
+    }
+
+    /// Verify signature, parsing the key first, see [RsaVerifyingKey] to parse it once.
+    pub fn verify(
+        &self,
+        text: impl AsRef<[u8]>,
+        signature: impl AsRef<str>,
+        public_key_pem: impl AsRef<str>,
+    ) -> Result<(), Error> {
+        RsaVerifyingKey::from_public_key_pem(public_key_pem.as_ref())?.verify(self, text, signature)
+    }
+
+    pub fn verify_with_x509(
+        &self,
+        text: impl AsRef<[u8]>,
+        signature: impl AsRef<str>,
+        cert: impl AsRef<[u8]>,
+    ) -> Result<(), Error> {
+        RsaVerifyingKey::from_x509_pem(cert.as_ref())?.verify(self, text, signature)
+    }
+}
+
+/// A parsed `#pkcs8` private key, wiped from memory on drop.
+pub struct RsaSigningKey(RsaPrivateKey);
+
+impl RsaSigningKey {
+    pub fn from_pkcs8_pem(private_key: &str) -> Result<Self, Error> {
+        let key = RsaPrivateKey::from_pkcs8_pem(private_key).map_err(|e| {
             error!("Failed to parse rsa pkcs8 pem for: {:?}", e);
             Error::msg("private key error")
         })?;
-        let (hashed, hash) = match self {
-            RsaAlgorithm::Sha256withRsa => (HashAlg::Sha256.hash(src), Hash::SHA2_256),
-            RsaAlgorithm::Sha512withRsa => (HashAlg::Sha512.hash(src), Hash::SHA2_512),
-        };
-        let signed = pri_key
+        Ok(Self(key))
+    }
+
+    /// Calculate the base64 encoded signature of `src`.
+    pub fn sign(&self, algorithm: &RsaAlgorithm, src: impl AsRef<[u8]>) -> Result<String, Error> {
+        let (hashed, hash) = algorithm.digest(src.as_ref());
+        let signed = self
+            .0
             .sign(
                 PaddingScheme::PKCS1v15Sign { hash: Some(hash) },
                 hashed.as_slice(),
@@ -70,27 +108,58 @@ impl RsaAlgorithm {
             })?;
         Ok(base64::encode(signed))
     }
+}
+
+impl std::fmt::Debug for RsaSigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RsaSigningKey({})", crate::redact::REDACTED)
+    }
+}
+
+/// A parsed rsa public key.
+#[derive(Debug, Clone)]
+pub struct RsaVerifyingKey(RsaPublicKey);
+
+impl RsaVerifyingKey {
+    /// Parse a `#pkcs8` public key in pem.
+    pub fn from_public_key_pem(public_key_pem: &str) -> Result<Self, Error> {
+        let key = RsaPublicKey::from_public_key_pem(public_key_pem).map_err(|e| {
+            error!("Failed to parse RsaPublicKey for: {:?}", e);
+            Error::msg("public key parse error")
+        })?;
+        Ok(Self(key))
+    }
 
-    /// Verify signature
+    /// Parse the public key of a x.509 certificate in pem.
+    pub fn from_x509_pem(cert: &[u8]) -> Result<Self, Error> {
+        let (_, pem) = parse_x509_pem(cert).map_err(|e| {
+            error!("Failed to parse certificate in pem for: {:?}", e);
+            Error::msg("pem parse error")
+        })?;
+        let x509 = pem.parse_x509().map_err(|e| {
+            error!("Failed to parse x.509 for: {:?}", e);
+            Error::msg("x509 parse error")
+        })?;
+        let key = RsaPublicKey::from_public_key_der(x509.public_key().raw).map_err(|e| {
+            error!("Failed to parse public key from x.509 for: {:?}", e);
+            Error::msg("public key invalid")
+        })?;
+        Ok(Self(key))
+    }
+
+    /// Verify the base64 encoded `signature` of `text`.
     pub fn verify(
         &self,
+        algorithm: &RsaAlgorithm,
         text: impl AsRef<[u8]>,
         signature: impl AsRef<str>,
-        public_key_pem: impl AsRef<str>,
     ) -> Result<(), Error> {
-        let pb_key = RsaPublicKey::from_public_key_pem(public_key_pem.as_ref()).map_err(|e| {
-            error!("Failed to parse RsaPublicKey for: {:?}", e);
-            Error::msg("public key parse error")
-        })?;
-        let (hashed, hash) = match self {
-            RsaAlgorithm::Sha256withRsa => (HashAlg::Sha256.hash(text.as_ref()), Hash::SHA2_256),
-            RsaAlgorithm::Sha512withRsa => (HashAlg::Sha512.hash(text.as_ref()), Hash::SHA2_512),
-        };
+        let (hashed, hash) = algorithm.digest(text.as_ref());
         let signature = base64::decode(signature.as_ref()).map_err(|e| {
             error!("Invalid base64 string: {:?}", e);
             Error::msg("invalid base64 str")
         })?;
-        pb_key
+        self.0
             .verify(
                 PaddingScheme::PKCS1v15Sign { hash: Some(hash) },
                 hashed.as_slice(),
@@ -101,36 +170,19 @@ impl RsaAlgorithm {
                 Error::msg("verify error")
             })
     }
+}
 
-    pub fn verify_with_x509(
-        &self,
-        text: impl AsRef<[u8]>,
-        signature: impl AsRef<str>,
-        cert: impl AsRef<[u8]>,
-    ) -> Result<(), Error> {
-        // 1. parse certificate
-        let (_, pem) = parse_x509_pem(cert.as_ref()).map_err(|e| {
-            error!("Failed to parse certificate in pem for: {:?}", e);
-            Error::msg("pem parse error")
-        })?;
-        let x509 = pem.parse_x509().map_err(|e| {
-            error!("Failed to parse x.509 for: {:?}", e);
-            Error::msg("x509 parse error")
-        })?;
-        let public_key = x509.public_key().raw;
-        // 2. verify pkcs1 pem public key
-        // convert pkcs1 pem key to pkcs8
-        let pub_key = RsaPublicKey::from_public_key_der(public_key).map_err(|e| {
-            error!("Failed to parse public key from x.509 for: {:?}", e);
-            Error::msg("public key invalid")
-        })?;
-        let pub_key_pkcs8 = pub_key.to_public_key_pem(LineEnding::CRLF).map_err(|e| {
-            error!("Unable to encode public key to pkcs8: {:?}", e);
-            Error::msg("unable to transform pub key")
-        })?;
-        debug!("public key is: {}", pub_key_pkcs8);
-        self.verify(text, signature, pub_key_pkcs8.as_str())
-    }
+/// Big endian serial number of a x.509 certificate in pem.
+pub fn parse_x509_serial_number(cert: &[u8]) -> Result<Vec<u8>, Error> {
+    let (_, pem) = parse_x509_pem(cert).map_err(|e| {
+        error!("Failed to parse certificate in pem for: {:?}", e);
+        Error::msg("pem parse error")
+    })?;
+    let x509 = pem.parse_x509().map_err(|e| {
+        error!("Failed to parse x.509 for: {:?}", e);
+        Error::msg("x509 parse error")
+    })?;
+    Ok(x509.raw_serial().to_vec())
 }
 
 #[cfg(test)]
@@ -6,25 +6,30 @@
 mod certs_manager {
 
     use lazy_static::lazy_static;
-    use num_bigint_dig::BigUint;
     use std::{
         collections::HashMap,
-        str::FromStr,
         sync::{Arc, RwLock},
     };
-    use x509_parser::prelude::X509Certificate;
+
+    #[cfg(feature = "reqwest")]
+    use wechat_pay_core::auth::Credential;
+    #[cfg(feature = "reqwest")]
+    use wechat_pay_core::certs::{decrypt_certificates, CertificatesResponse};
+    use wechat_pay_core::keyring::ApiV3KeyRing;
+    use wechat_pay_core::serial::SerialNumber;
 
     use crate::{prelude::*, verify::Verifier};
 
     /// Certificate download url
+    #[cfg(feature = "reqwest")]
     const CERT_DOWNLOAD_PATH: &str = "https://api.mch.weixin.qq.com/v3/certificates";
 
     lazy_static! {
-        // cached certificates
-        static ref certificates: Arc<RwLock<HashMap<String, HashMap<BigUint, X509Certificate<'static>>>>> =
+        // cached certificates in pem, by merchant and serial number.
+        static ref certificates: Arc<RwLock<HashMap<String, HashMap<SerialNumber, Vec<u8>>>>> =
             Arc::new(RwLock::new(HashMap::new()));
-        // cached api-v3-keys
-        static ref api_v3_keys: Arc<RwLock<HashMap<String, Vec<u8>>>> = Arc::new(RwLock::new(HashMap::new()));
+        // api-v3-key rings, shared with the notification handlers of the merchant.
+        static ref api_v3_key_rings: Arc<RwLock<HashMap<String, Arc<ApiV3KeyRing>>>> = Arc::new(RwLock::new(HashMap::new()));
     }
 
     struct DefaultVerifier {
@@ -56,97 +61,105 @@ mod certs_manager {
 
     pub(crate) struct CertificateManager;
     impl CertificateManager {
-        /// Add a merchant to [CertificateManager] which should auto update certificates
+        /// Add the merchant of `credential` to [CertificateManager] which should auto update
+        /// certificates, downloaded with `credential` and decrypted with the current or a
+        /// retired key of `api_v3_keys`.
+        #[cfg(feature = "reqwest")]
         pub fn push_merchant(
             &mut self,
-            merchant_id: impl AsRef<str>,
-            api_v3_key: &[u8],
+            credential: &dyn Credential,
+            api_v3_keys: Arc<ApiV3KeyRing>,
         ) -> Result<(), String> {
-            if merchant_id.as_ref().is_empty() {
+            let merchant_id = credential.get_merchant_id();
+            if merchant_id.is_empty() {
                 return Err("merchant_id is empty".into());
             }
-            if certificates
-                .read()
+
+            api_v3_key_rings
+                .write()
                 .unwrap()
-                .get(merchant_id.as_ref())
-                .is_none()
-            {
-                certificates
-                    .write()
-                    .unwrap()
-                    .insert(merchant_id.as_ref().to_string(), HashMap::new());
-            }
+                .insert(merchant_id.to_string(), api_v3_keys);
 
             // init_certificate
-            init_certificates(merchant_id.as_ref(), api_v3_key);
-            todo!()
+            let response = download_certificate(credential)?;
+            init_certificates(merchant_id, &response)
         }
 
-        /// Get the latest X.509  certificate from [CertificateManager].
-        pub fn get_latest_certificate(
-            &self,
-            merchant_id: &str,
-        ) -> Result<X509Certificate<'static>, String> {
+        /// Get the latest X.509 certificate in pem from [CertificateManager].
+        pub fn get_latest_certificate(&self, merchant_id: &str) -> Result<Vec<u8>, String> {
             if merchant_id.is_empty() {
                 return Err("merchant_id is empty".into());
             }
 
             let certs = certificates.read().unwrap();
-            if let Some(cert_map) = certs.get(merchant_id) {
-                if cert_map.is_empty() {
-                    return Err(format!(
-                        "no certificate found, merchant_id: {}",
-                        merchant_id
-                    ));
-                }
-                let mut latest_certificate: Option<&X509Certificate> = None;
-                for cert in cert_map.values() {
-                    if latest_certificate.is_none()
-                        || latest_certificate
-                            .as_ref()
-                            .unwrap()
-                            .validity()
-                            .not_before
-                            .cmp(&cert.validity().not_before)
-                            .is_lt()
-                    {
-                        latest_certificate = Some(cert);
-                    }
+            let not_found = || format!("no certificate found, merchant_id: {}", merchant_id);
+            let cert_map = certs.get(merchant_id).ok_or_else(not_found)?;
+            let mut latest = None;
+            for pem in cert_map.values() {
+                let (_, parsed) = x509_parser::pem::parse_x509_pem(pem)
+                    .map_err(|e| format!("invalid certificate: {}", e))?;
+                let cert = parsed
+                    .parse_x509()
+                    .map_err(|e| format!("invalid certificate: {}", e))?;
+                let validity = cert.validity().clone();
+                match &latest {
+                    Some((not_before, _, _)) if *not_before >= validity.not_before => {}
+                    _ => latest = Some((validity.not_before, validity.is_valid(), pem)),
                 }
+            }
+            let (_, is_valid, pem) = latest.ok_or_else(not_found)?;
 
-                // if certificate date is not valid or expired, should return an error.
-                if latest_certificate.unwrap().validity().is_valid() {
-                    Ok(latest_certificate.unwrap().clone())
-                } else {
-                    Err(format!(
-                        "certificate is invalid or expired: {}",
-                        merchant_id
-                    ))
-                }
+            // if certificate date is not valid or expired, should return an error.
+            if is_valid {
+                Ok(pem.clone())
             } else {
-                return Err(format!(
-                    "no certificate found, merchant_id: {}",
+                Err(format!(
+                    "certificate is invalid or expired: {}",
                     merchant_id
-                ));
+                ))
             }
         }
     }
 
-    /// A function to check and init X.509 certificate
-    fn init_certificates(merchant_id: impl AsRef<str>, api_v3_key: &[u8]) {}
-
-    fn download_certificate() {
-        use reqwest::blocking::{Client, ClientBuilder};
-        use reqwest::{
-            header::{ACCEPT, USER_AGENT},
-            Method,
+    /// A function to check and cache X.509 certificates, decrypted with the key ring of the
+    /// merchant.
+    #[cfg(feature = "reqwest")]
+    fn init_certificates(
+        merchant_id: impl AsRef<str>,
+        response: &CertificatesResponse,
+    ) -> Result<(), String> {
+        let decrypted = {
+            let key_rings = api_v3_key_rings.read().unwrap();
+            let api_v3_keys = key_rings
+                .get(merchant_id.as_ref())
+                .ok_or_else(|| format!("no api v3 key, merchant_id: {}", merchant_id.as_ref()))?;
+            decrypt_certificates(response, api_v3_keys)?
         };
-        // ClientBuilder::default().proxy(proxy)
-        let client = Client::new();
-        let request = client
+        certificates
+            .write()
+            .unwrap()
+            .entry(merchant_id.as_ref().to_string())
+            .or_default()
+            .extend(decrypted);
+        Ok(())
+    }
+
+    /// Download the platform certificates, the request is signed with `credential`.
+    #[cfg(feature = "reqwest")]
+    fn download_certificate(credential: &dyn Credential) -> Result<CertificatesResponse, String> {
+        use reqwest::blocking::Client;
+        use reqwest::header::{ACCEPT, AUTHORIZATION, USER_AGENT};
+
+        let authorization = credential.get_authorization(CERT_DOWNLOAD_PATH, "GET", "")?;
+        Client::new()
             .get(CERT_DOWNLOAD_PATH)
             .header(ACCEPT, "application/json")
             .header(USER_AGENT, "rust/sdk")
-            .build();
+            .header(AUTHORIZATION, authorization)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("failed to download certificates: {}", e))?
+            .json::<CertificatesResponse>()
+            .map_err(|e| format!("invalid certificates response: {}", e))
     }
 }